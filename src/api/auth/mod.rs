@@ -0,0 +1,264 @@
+//! Bearer tokens of the API. Only their SHA-256 is stored, either in the config file or in the OS keyring.
+//! Tokens with the read scope may only GET, and admin tokens may do anything.
+
+use std::sync::RwLock;
+use std::time::Duration;
+
+use axum::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use axum::http::{HeaderValue, Method, Request};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::api::error::ApiError;
+use crate::config::{get_config, Scope, TokenConfig};
+use crate::TokenCommand;
+
+mod store;
+
+// Revoked tokens are rejected within this interval.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+static TOKENS: Lazy<RwLock<Vec<TokenConfig>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+fn hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn load_tokens() -> Result<Vec<TokenConfig>, String> {
+    let config = get_config();
+    store::load(config.api.token_store, config.path.as_deref())
+}
+
+/// Keeps the tokens up to date, so that minting and revoking take effect without a restart.
+pub(crate) async fn refresh_tokens() {
+    loop {
+        match load_tokens() {
+            Ok(tokens) => {
+                if tokens.is_empty() {
+                    warn!("No API token is found. Every request will be rejected.");
+                }
+                *TOKENS.write().unwrap() = tokens;
+            }
+            // The former tokens are kept.
+            Err(e) => error!("Failed to load API tokens. {}", e),
+        }
+        tokio::time::sleep(REFRESH_INTERVAL).await;
+    }
+}
+
+// EventSource of browsers can't set headers, so GET requests may give the token in `access_token`.
+fn token_of<B>(req: &Request<B>) -> Option<&str> {
+    let header = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let query = || {
+        req.uri()
+            .query()?
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("access_token="))
+    };
+    match *req.method() {
+        Method::GET => header.or_else(query),
+        _ => header,
+    }
+}
+
+/// Middleware applied to every route if `api.auth` is set.
+pub(crate) async fn require_token<B>(req: Request<B>, next: Next<B>) -> Response {
+    let required = match *req.method() {
+        Method::GET | Method::HEAD => Scope::Read,
+        _ => Scope::Admin,
+    };
+    let granted = token_of(&req).and_then(|token| {
+        let hashed = hash(token.trim());
+        TOKENS
+            .read()
+            .unwrap()
+            .iter()
+            .find(|t| t.sha256.eq_ignore_ascii_case(&hashed))
+            .map(|t| t.scope)
+    });
+
+    match granted {
+        Some(scope) if scope >= required => next.run(req).await,
+        Some(_) => ApiError::forbidden("The token is read-only.").into_response(),
+        None => {
+            let mut res =
+                ApiError::unauthorized("A valid bearer token is required.").into_response();
+            res.headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            res
+        }
+    }
+}
+
+/// Runs `token` subcommands. The new token is printed only once.
+pub(crate) fn run_command(cmd: &TokenCommand) -> Result<(), String> {
+    let config = get_config();
+    let store = config.api.token_store;
+    // Tokens are appended to ./config.toml if no config file is used yet.
+    let path = config
+        .path
+        .clone()
+        .unwrap_or_else(|| "./config.toml".into());
+
+    match cmd {
+        TokenCommand::Mint { name, scope } => {
+            if load_tokens()?.iter().any(|t| &t.name == name) {
+                return Err(format!("{:?} already exists.", name));
+            }
+            let mut bytes = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            let token = bytes
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>();
+            store::add(
+                store,
+                &path,
+                TokenConfig {
+                    name: name.clone(),
+                    sha256: hash(&token),
+                    scope: *scope,
+                },
+            )?;
+            info!("Token {:?} ({:?}) has been minted.", name, scope);
+            println!("{}", token);
+        }
+        TokenCommand::Revoke { name } => {
+            if !store::remove(store, &path, name)? {
+                return Err(format!("{:?} is not found.", name));
+            }
+            info!("Token {:?} has been revoked.", name);
+        }
+        TokenCommand::List => {
+            for t in load_tokens()? {
+                println!("{}\t{:?}", t.name, t.scope);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::StatusCode;
+    use axum::routing::get;
+    use axum::{middleware, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    // Every method is served, so that the response tells whether the token has been accepted.
+    fn app() -> Router {
+        *TOKENS.write().unwrap() = vec![
+            TokenConfig {
+                name: "reader".to_string(),
+                sha256: hash("read-token"),
+                scope: Scope::Read,
+            },
+            TokenConfig {
+                name: "admin".to_string(),
+                // Given in uppercase by hand
+                sha256: hash("admin-token").to_uppercase(),
+                scope: Scope::Admin,
+            },
+        ];
+        let ok = || async { "ok" };
+        Router::new()
+            .route("/", get(ok).post(ok).put(ok).delete(ok))
+            .layer(middleware::from_fn(require_token))
+    }
+
+    async fn send(method: Method, uri: &str, token: Option<&str>) -> Response {
+        let mut request = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        app()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    async fn status(method: Method, uri: &str, token: Option<&str>) -> StatusCode {
+        send(method, uri, token).await.status()
+    }
+
+    #[tokio::test]
+    async fn request_without_valid_token_is_unauthorized() {
+        let res = send(Method::GET, "/", None).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(res.headers()[WWW_AUTHENTICATE], "Bearer");
+
+        assert_eq!(
+            status(Method::GET, "/", Some("unknown-token")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        // The hash is not the token.
+        assert_eq!(
+            status(Method::GET, "/", Some(&hash("read-token"))).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn read_scope_may_only_get() {
+        let token = Some("read-token");
+        assert_eq!(status(Method::GET, "/", token).await, StatusCode::OK);
+        assert_eq!(status(Method::HEAD, "/", token).await, StatusCode::OK);
+        for method in [Method::POST, Method::PUT, Method::DELETE] {
+            assert_eq!(status(method, "/", token).await, StatusCode::FORBIDDEN);
+        }
+    }
+
+    #[tokio::test]
+    async fn admin_scope_may_do_anything() {
+        let token = Some("admin-token");
+        for method in [
+            Method::GET,
+            Method::HEAD,
+            Method::POST,
+            Method::PUT,
+            Method::DELETE,
+        ] {
+            assert_eq!(status(method, "/", token).await, StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn token_in_query_is_accepted_for_get_alone() {
+        assert_eq!(
+            status(Method::GET, "/?x=1&access_token=read-token", None).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(Method::HEAD, "/?access_token=read-token", None).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(Method::POST, "/?access_token=admin-token", None).await,
+            StatusCode::UNAUTHORIZED
+        );
+        // The header is preferred.
+        assert_eq!(
+            status(
+                Method::POST,
+                "/?access_token=read-token",
+                Some("admin-token")
+            )
+            .await,
+            StatusCode::OK
+        );
+    }
+}
@@ -0,0 +1,257 @@
+use std::io::Write;
+use std::path::Path;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::config::{TokenConfig, TokenStore};
+use crate::sched_trigger::persistence::write_atomically;
+
+const KEYRING_SERVICE: &str = "recorder-backend-rs";
+const KEYRING_USER: &str = "api-tokens";
+const HEADER: &str = "[[api.tokens]]";
+
+// Only `api.tokens` is read from the config file, so that the other keys don't matter here.
+#[derive(Default, Deserialize, Serialize)]
+struct File {
+    #[serde(default)]
+    api: ApiSection,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct ApiSection {
+    #[serde(default)]
+    tokens: Vec<TokenConfig>,
+}
+
+fn keyring_entry() -> keyring::Entry {
+    keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER)
+}
+
+pub(super) fn load(store: TokenStore, config: Option<&Path>) -> Result<Vec<TokenConfig>, String> {
+    match store {
+        TokenStore::Config => {
+            let path = match config {
+                Some(path) => path,
+                None => return Ok(Vec::new()),
+            };
+            let str = std::fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read {}. {}", path.display(), e))?;
+            toml::from_str::<File>(&str)
+                .map(|f| f.api.tokens)
+                .map_err(|e| format!("Failed to parse {}. {}", path.display(), e))
+        }
+        TokenStore::Keyring => match keyring_entry().get_password() {
+            Ok(json) => serde_json::from_str(&json).map_err(|e| e.to_string()),
+            Err(keyring::Error::NoEntry) => Ok(Vec::new()),
+            Err(e) => Err(format!("Failed to read the keyring. {}", e)),
+        },
+    }
+}
+
+fn save_to_keyring(tokens: &[TokenConfig]) -> Result<(), String> {
+    let json = serde_json::to_string(tokens).map_err(|e| e.to_string())?;
+    keyring_entry()
+        .set_password(&json)
+        .map_err(|e| format!("Failed to write the keyring. {}", e))
+}
+
+pub(super) fn add(store: TokenStore, config: &Path, token: TokenConfig) -> Result<(), String> {
+    match store {
+        // Appended as text, so that the comments in the file are kept.
+        TokenStore::Config => {
+            let block = toml::to_string(&File {
+                api: ApiSection {
+                    tokens: vec![token],
+                },
+            })
+            .map_err(|e| e.to_string())?;
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(config)
+                .map_err(|e| format!("Failed to open {}. {}", config.display(), e))?;
+            write!(file, "\n{}", block)
+                .map_err(|e| format!("Failed to write {}. {}", config.display(), e))
+        }
+        TokenStore::Keyring => {
+            let mut tokens = load(store, None)?;
+            tokens.push(token);
+            save_to_keyring(&tokens)
+        }
+    }
+}
+
+/// Returns false if no token has the name.
+pub(super) fn remove(store: TokenStore, config: &Path, name: &str) -> Result<bool, String> {
+    match store {
+        TokenStore::Config => {
+            let str = std::fs::read_to_string(config)
+                .map_err(|e| format!("Failed to read {}. {}", config.display(), e))?;
+            let names = |str: &str| {
+                toml::from_str::<File>(str)
+                    .map(|f| f.api.tokens.into_iter().map(|t| t.name).collect::<Vec<_>>())
+                    .map_err(|e| format!("Failed to parse {}. {}", config.display(), e))
+            };
+            let mut expected = names(&str)?;
+            if !expected.iter().any(|n| n == name) {
+                return Ok(false);
+            }
+            expected.retain(|n| n != name);
+
+            // Tokens written in other forms (e.g. an inline array) are left in the text, and caught here.
+            let kept = remove_blocks(&str, name);
+            if names(&kept)? != expected {
+                return Err(format!(
+                    "{:?} is not written as a `{}` table in {}. Remove it by hand.",
+                    name,
+                    HEADER,
+                    config.display()
+                ));
+            }
+            write_atomically(config, kept.as_bytes())
+                .map_err(|e| format!("Failed to write {}. {}", config.display(), e))?;
+            Ok(true)
+        }
+        TokenStore::Keyring => {
+            let mut tokens = load(store, None)?;
+            let before = tokens.len();
+            tokens.retain(|t| t.name != name);
+            if tokens.len() == before {
+                return Ok(false);
+            }
+            save_to_keyring(&tokens).map(|_| true)
+        }
+    }
+}
+
+// Drops each `[[api.tokens]]` table named `name`, up to the next table header.
+// The lines in the table (e.g. comments) go with it.
+fn remove_blocks(str: &str, name: &str) -> String {
+    let mut kept = String::new();
+    let mut block: Option<String> = None;
+
+    let flush = |block: Option<String>, kept: &mut String| {
+        if let Some(block) = block {
+            let body = block.trim_start().trim_start_matches(HEADER);
+            match toml::from_str::<TokenConfig>(body) {
+                Ok(t) if t.name == name => {}
+                _ => kept.push_str(&block),
+            }
+        }
+    };
+    for line in str.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if trimmed == HEADER {
+            flush(block.take(), &mut kept);
+            block = Some(line.to_string());
+        } else if trimmed.starts_with('[') {
+            flush(block.take(), &mut kept);
+            kept.push_str(line);
+        } else {
+            match block.as_mut() {
+                Some(block) => block.push_str(line),
+                None => kept.push_str(line),
+            }
+        }
+    }
+    flush(block.take(), &mut kept);
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Scope;
+    use crate::test_utils;
+
+    const CONFIG: &str = r#"# Comments are kept.
+[api]
+auth = true
+
+[[api.tokens]]
+name = "reader"
+sha256 = "00"
+scope = "read"
+
+[[api.tokens]]
+# The token of the scripts
+name = "admin"
+sha256 = "11"
+scope = "admin"
+
+[storage]
+min_free_mib = 0
+"#;
+
+    fn config_file(content: &str) -> std::path::PathBuf {
+        let path = test_utils::temp_dir("tokens").join("config.toml");
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn names(path: &Path) -> Vec<String> {
+        load(TokenStore::Config, Some(path))
+            .unwrap()
+            .into_iter()
+            .map(|t| t.name)
+            .collect()
+    }
+
+    #[test]
+    fn removes_table_of_token_alone() {
+        let path = config_file(CONFIG);
+        assert!(remove(TokenStore::Config, &path, "admin").unwrap());
+
+        let expected = r#"# Comments are kept.
+[api]
+auth = true
+
+[[api.tokens]]
+name = "reader"
+sha256 = "00"
+scope = "read"
+
+[storage]
+min_free_mib = 0
+"#;
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), expected);
+        assert_eq!(names(&path), vec!["reader"]);
+    }
+
+    #[test]
+    fn unknown_token_is_not_removed() {
+        let path = config_file(CONFIG);
+        assert!(!remove(TokenStore::Config, &path, "writer").unwrap());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), CONFIG);
+    }
+
+    #[test]
+    fn token_in_other_form_is_left_to_user() {
+        let content = r#"[api]
+tokens = [{ name = "admin", sha256 = "11", scope = "admin" }]
+"#;
+        let path = config_file(content);
+        assert!(remove(TokenStore::Config, &path, "admin").is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), content);
+    }
+
+    #[test]
+    fn added_token_is_loaded() {
+        let path = config_file(CONFIG);
+        add(
+            TokenStore::Config,
+            &path,
+            TokenConfig {
+                name: "viewer".to_string(),
+                sha256: "22".to_string(),
+                scope: Scope::Read,
+            },
+        )
+        .unwrap();
+
+        assert!(std::fs::read_to_string(&path).unwrap().starts_with(CONFIG));
+        assert_eq!(names(&path), vec!["reader", "admin", "viewer"]);
+        assert!(remove(TokenStore::Config, &path, "viewer").unwrap());
+        assert_eq!(names(&path), vec!["reader", "admin"]);
+    }
+}
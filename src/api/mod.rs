@@ -1,73 +1,56 @@
 use std::collections::HashMap;
-use std::net::SocketAddr;
 use std::sync::Arc;
 
-use axum::response::IntoResponse;
-use axum::{
-    response,
-    routing::{delete, get, put},
-    Router,
-};
-use log::info;
-use structopt::StructOpt;
+use axum::http::Method;
+use axum::middleware;
+use axum::response;
+use axum::response::sse::{self, KeepAlive, Sse};
+use log::{info, warn};
+use mirakurun_client::models::Program;
+use serde_derive::Serialize;
 use tokio::sync::Mutex;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+use ulid::Ulid;
+use utoipa::{OpenApi, ToSchema};
 
-use crate::db_utils::{get_all_programs, get_temporary_accessor, pull_program};
+use crate::api::openapi::{ApiDoc, Routes};
+use crate::config::get_config;
+use crate::db_utils::{
+    delete_rule, delete_series_plan, get_all_programs, get_all_rules, get_all_series_plans,
+    get_temporary_accessor, pull_program, pull_recording, push_rules, push_series_plans,
+    search_recordings,
+};
+use crate::events::{self, publish, Event};
+use crate::post_process::{Job, JOB_QUEUE};
+use crate::recording_planner::series::SeriesPlan;
+use crate::recording_planner::word::WordRule;
 use crate::recording_planner::PlanId;
+use crate::recording_pool::library::{remove_recording, Recording};
+use crate::recording_pool::recording_task::sink::check_profile;
 use crate::recording_pool::{RecordingTaskDescription, REC_POOL};
+use crate::sched_trigger::conflict::{detect_conflicts, resolve_conflicts, Conflict, TUNER_MODEL};
 use crate::sched_trigger::Schedule;
-use crate::{Opt, SchedQueue};
+use crate::storage::volume::{status_of, volumes, VolumeStatus};
+use crate::SchedQueue;
+
+pub(crate) mod auth;
+mod error;
+mod openapi;
+mod playback;
+mod v1;
 
 pub(crate) async fn api_startup(q_schedules: Arc<Mutex<SchedQueue>>) {
-    let q_schedules1 = q_schedules.clone();
-    let q_schedules2 = q_schedules.clone();
-    let q_schedules3 = q_schedules.clone();
-    let q_schedules4 = q_schedules.clone();
-    let app =
-        Router::new()
-            .route(
-                "/",
-                get(move || async move {
-                    serde_json::to_string(&q_schedules1.lock().await.items).unwrap()
-                }),
-            )
-            .route(
-                "/programs",
-                get(|| async {
-                    let args = Opt::from_args();
-                    let client = get_temporary_accessor();
-                    let res = get_all_programs(&client).await;
-                    match res {
-                        Ok(res) => Ok(response::Json(res)),
-                        Err(e) => Err(e.to_string().into_response()),
-                    }
-                }),
-            )
-            .route(
-                "/q/sched",
-                get(move || async move {
-                    serde_json::to_string(&q_schedules2.lock().await.items).unwrap()
-                }),
-            )
-            .route(
-                "/q/recording",
-                get(move || async move {
-                    let obj = REC_POOL
-                        .read()
-                        .unwrap()
-                        .iter()
-                        .cloned()
-                        .collect::<Vec<RecordingTaskDescription>>();
-                    serde_json::to_string(&obj).unwrap()
-                }),
-            )
-            .route(
-                "/new/sched",
-                put(move |p| async move { put_recording_schedule(q_schedules3, p).await }),
-            )
-            .route("/q/sched", delete(|p| delete_sched(q_schedules4, p)));
-
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+    let mut app = routes(q_schedules).into_router();
+    if get_config().api.auth {
+        tokio::spawn(auth::refresh_tokens());
+        app = app.layer(middleware::from_fn(auth::require_token));
+    } else if !get_config().api.listen.ip().is_loopback() {
+        warn!("The API is open to the network without authentication. Consider `api.auth`.");
+    }
+
+    let addr = get_config().api.listen;
     info!("listening on {}", addr);
     let e = axum::Server::bind(&addr)
         .serve(app.into_make_service())
@@ -75,10 +58,229 @@ pub(crate) async fn api_startup(q_schedules: Arc<Mutex<SchedQueue>>) {
         .unwrap();
 }
 
+// Every route must be documented, and every documented route must exist, which is checked by a test.
+fn routes(q_schedules: Arc<Mutex<SchedQueue>>) -> Routes {
+    let q_schedules1 = q_schedules.clone();
+    let q_schedules2 = q_schedules.clone();
+    let q_schedules3 = q_schedules.clone();
+    let q_schedules4 = q_schedules.clone();
+    let q_schedules5 = q_schedules.clone();
+    let q_schedules6 = q_schedules.clone();
+    let routes = Routes::new()
+        .on(Method::GET, "/", move || async move {
+            get_schedules(q_schedules1).await
+        })
+        .on(Method::GET, "/programs", get_programs)
+        .on(Method::GET, "/q/sched", move || async move {
+            get_q_sched(q_schedules2).await
+        })
+        .on(Method::GET, "/q/recording", get_recording_tasks)
+        .on(Method::GET, "/q/jobs", get_jobs)
+        .on(Method::GET, "/q/conflicts", move || async move {
+            get_conflicts(q_schedules6).await
+        })
+        .on(Method::PUT, "/new/sched", move |p| async move {
+            put_recording_schedule(q_schedules3, p).await
+        })
+        .on(Method::DELETE, "/q/sched", |p| {
+            delete_sched(q_schedules4, p)
+        })
+        .on(Method::GET, "/rules", get_rules)
+        .on(Method::PUT, "/new/rule", put_rule)
+        .on(Method::DELETE, "/rules", delete_rule_by_id)
+        .on(Method::GET, "/series", get_series_plans)
+        .on(Method::PUT, "/new/series", move |p| async move {
+            put_series_plan(q_schedules5, p).await
+        })
+        .on(Method::DELETE, "/series", delete_series_plan_by_id)
+        .on(Method::GET, "/storage/volumes", get_volumes)
+        .on(Method::GET, "/recordings", get_recordings)
+        .on(Method::GET, "/recording", get_recording_by_id)
+        .on(Method::DELETE, "/recording", delete_recording_by_id)
+        .on(Method::GET, "/api/openapi.json", get_openapi)
+        .on(Method::GET, "/api/events", get_events);
+    v1::routes(routes, q_schedules)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/openapi.json",
+    responses((status = 200, description = "This document", body = Object))
+)]
+async fn get_openapi() -> response::Json<utoipa::openapi::OpenApi> {
+    response::Json(ApiDoc::openapi())
+}
+
+// Pushes every event as JSON, so that clients don't have to poll the queues.
+#[utoipa::path(
+    get,
+    path = "/api/events",
+    responses((status = 200, description = "Server-Sent Events. `data` is an Event.", body = Event, content_type = "text/event-stream"))
+)]
+async fn get_events() -> Sse<impl Stream<Item = serde_json::Result<sse::Event>>> {
+    let stream = BroadcastStream::new(events::subscribe()).map(|received| {
+        let event = match received {
+            Ok(event) => event,
+            Err(BroadcastStreamRecvError::Lagged(missed)) => Event::Lagged { missed },
+        };
+        sse::Event::default().json_data(event)
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+#[utoipa::path(
+    get,
+    path = "/",
+    responses((status = 200, description = "All the schedules", body = [Schedule]))
+)]
+async fn get_schedules(schedules: Arc<Mutex<SchedQueue>>) -> response::Json<Vec<Schedule>> {
+    response::Json(schedules.lock().await.items.clone())
+}
+
+#[utoipa::path(
+    get,
+    path = "/q/sched",
+    responses((status = 200, description = "All the schedules", body = [Schedule]))
+)]
+async fn get_q_sched(schedules: Arc<Mutex<SchedQueue>>) -> response::Json<Vec<Schedule>> {
+    get_schedules(schedules).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/programs",
+    responses(
+        (status = 200, description = "All the programs in the EPG", body = [Object]),
+        (status = 500, description = "The index is unavailable", body = String),
+    )
+)]
+async fn get_programs() -> Result<response::Json<Vec<Program>>, String> {
+    let client = get_temporary_accessor();
+    get_all_programs(&client)
+        .await
+        .map(response::Json)
+        .map_err(|e| e.to_string())
+}
+
+#[utoipa::path(
+    get,
+    path = "/q/recording",
+    responses((status = 200, description = "Running and waiting recording tasks", body = [RecordingTaskDescription]))
+)]
+async fn get_recording_tasks() -> response::Json<Vec<RecordingTaskDescription>> {
+    let obj = REC_POOL
+        .read()
+        .unwrap()
+        .iter()
+        .cloned()
+        .collect::<Vec<RecordingTaskDescription>>();
+    response::Json(obj)
+}
+
+#[utoipa::path(
+    get,
+    path = "/q/jobs",
+    responses((status = 200, description = "Post-processing jobs", body = [Job]))
+)]
+async fn get_jobs() -> response::Json<Vec<Job>> {
+    let jobs = JOB_QUEUE
+        .read()
+        .unwrap()
+        .iter()
+        .cloned()
+        .collect::<Vec<_>>();
+    response::Json(jobs)
+}
+
+#[utoipa::path(
+    get,
+    path = "/q/conflicts",
+    responses((status = 200, description = "Moments when the tuners run short", body = [Conflict]))
+)]
+async fn get_conflicts(schedules: Arc<Mutex<SchedQueue>>) -> response::Json<Vec<Conflict>> {
+    let items = &schedules.lock().await.items;
+    response::Json(detect_conflicts(items, &TUNER_MODEL.read().unwrap()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/rules",
+    responses(
+        (status = 200, description = "All the rules", body = [WordRule]),
+        (status = 500, description = "The index is unavailable", body = String),
+    )
+)]
+async fn get_rules() -> Result<response::Json<Vec<WordRule>>, String> {
+    let client = get_temporary_accessor();
+    match get_all_rules(&client).await {
+        Ok(res) => Ok(response::Json(res)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/series",
+    responses(
+        (status = 200, description = "All the series plans", body = [SeriesPlan]),
+        (status = 500, description = "The index is unavailable", body = String),
+    )
+)]
+async fn get_series_plans() -> Result<response::Json<Vec<SeriesPlan>>, String> {
+    let client = get_temporary_accessor();
+    match get_all_series_plans(&client).await {
+        Ok(res) => Ok(response::Json(res)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/storage/volumes",
+    responses(
+        (status = 200, description = "Usage of the volumes", body = [VolumeStatus]),
+        (status = 500, description = "A volume is inaccessible", body = String),
+    )
+)]
+async fn get_volumes() -> Result<response::Json<Vec<VolumeStatus>>, String> {
+    let res = tokio::task::spawn_blocking(|| {
+        volumes()
+            .iter()
+            .map(status_of)
+            .collect::<Result<Vec<_>, _>>()
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+    match res {
+        Ok(res) => Ok(response::Json(res)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ScheduleAdded {
+    schedule: Schedule,
+    // Conflicts the new schedule is involved in
+    conflicts: Vec<Conflict>,
+}
+
+#[utoipa::path(
+    put,
+    path = "/new/sched",
+    params(
+        ("id" = i64, Query, description = "Id of the program"),
+        ("priority" = Option<i32>, Query, description = "Higher one wins a tuner conflict"),
+        ("profile" = Option<String>, Query, description = "Output profile in `recording.profiles`"),
+    ),
+    responses(
+        (status = 200, description = "The schedule and the conflicts it is involved in", body = ScheduleAdded),
+        (status = 500, description = "Invalid query, unknown program or unknown profile", body = String),
+    )
+)]
 async fn put_recording_schedule(
     schedules: Arc<Mutex<SchedQueue>>,
     axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
-) -> Result<response::Json<Schedule>, String> {
+) -> Result<response::Json<ScheduleAdded>, String> {
     let program = {
         let client = get_temporary_accessor();
         // Check input
@@ -92,26 +294,90 @@ async fn put_recording_schedule(
             .await
             .or_else(|e| Err(e.to_string()))?
     };
-    let s = Schedule {
+    let priority = match params.get("priority") {
+        Some(v) => v.parse::<i32>().map_err(|e| e.to_string())?,
+        None => 0,
+    };
+    let profile = params.get("profile").cloned();
+    check_profile(profile.as_deref())?;
+    let (_, added) = add_schedule(&schedules, program, priority, profile).await;
+    Ok(response::Json(added))
+}
+
+/// Adds the program unless it has already been scheduled, and resolves conflicts.
+/// The bool is false if the program was already there, in which case the existing schedule is returned.
+pub(crate) async fn add_schedule(
+    schedules: &Mutex<SchedQueue>,
+    program: Program,
+    priority: i32,
+    profile: Option<String>,
+) -> (bool, ScheduleAdded) {
+    let mut s = Schedule {
         program,
         plan_id: PlanId::None,
         is_active: true,
+        priority,
+        inactive_reason: None,
+        plan_name: None,
+        profile,
     };
 
-    let items = &mut schedules.lock().await.items;
-    if items.iter().all(|f| f.program.id != s.program.id) {
-        items.push(s.clone());
-    }
+    let (is_new, conflicts) = {
+        let mut q_schedules = schedules.lock().await;
+        let items = &mut q_schedules.items;
+        let is_new = items.iter().all(|f| f.program.id != s.program.id);
+        if is_new {
+            items.push(s.clone());
+        }
+        // Conflicts are reported before the resolution so that users can see what has lost.
+        let model = TUNER_MODEL.read().unwrap();
+        let conflicts = detect_conflicts(items, &model)
+            .into_iter()
+            .filter(|c| c.program_ids.contains(&s.program.id))
+            .collect::<Vec<_>>();
+        resolve_conflicts(items, &model);
+        if let Some(item) = items.iter().find(|f| f.program.id == s.program.id) {
+            s = item.clone();
+        }
+        q_schedules.save();
+        (is_new, conflicts)
+    };
 
-    info!("Program {:?} (service_id={}, network_id={}, event_id={}) has been successfully added to sched_trigger.",
-        &s.program.description,
-        &s.program.service_id,
-        &s.program.network_id,
-        &s.program.event_id,
-    );
-    Ok(response::Json(s))
+    if is_new {
+        publish(Event::ScheduleAdded {
+            schedule: s.clone(),
+        });
+        info!("Program {:?} (service_id={}, network_id={}, event_id={}) has been successfully added to sched_trigger.",
+            &s.program.description,
+            &s.program.service_id,
+            &s.program.network_id,
+            &s.program.event_id,
+        );
+    }
+    for c in conflicts.iter() {
+        warn!(
+            "Tuners will run short at {}. Programs on air: {:?}",
+            c.at, c.program_ids
+        );
+    }
+    (
+        is_new,
+        ScheduleAdded {
+            schedule: s,
+            conflicts,
+        },
+    )
 }
 
+#[utoipa::path(
+    delete,
+    path = "/q/sched",
+    params(("id" = i64, Query, description = "Id of the program")),
+    responses(
+        (status = 200, description = "Deleted, if it has been scheduled"),
+        (status = 500, description = "Invalid query", body = String),
+    )
+)]
 async fn delete_sched(
     schedules: Arc<Mutex<SchedQueue>>,
     axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
@@ -124,7 +390,272 @@ async fn delete_sched(
         .map_err(|e| e.to_string())?;
 
     // Delete
-    schedules.lock().await.items.retain(|f| f.program.id == id);
+    let mut q_schedules = schedules.lock().await;
+    let before = q_schedules.items.len();
+    q_schedules.items.retain(|f| f.program.id != id);
+    if q_schedules.items.len() != before {
+        publish(Event::ScheduleRemoved {
+            program_id: id,
+            reason: "deleted".to_string(),
+        });
+    }
+    q_schedules.save();
+
+    Ok(())
+}
+
+#[utoipa::path(
+    put,
+    path = "/new/rule",
+    request_body = WordRule,
+    responses(
+        (status = 200, description = "The saved rule", body = WordRule),
+        (status = 500, description = "Unknown profile, or the index is unavailable", body = String),
+    )
+)]
+async fn put_rule(
+    axum::extract::Json(rule): axum::extract::Json<WordRule>,
+) -> Result<response::Json<WordRule>, String> {
+    check_profile(rule.profile.as_deref())?;
+    let client = get_temporary_accessor();
+    push_rules(&client, &[rule.clone()])
+        .await
+        .map_err(|e| e.to_string())?;
+
+    info!(
+        "Rule {} (keyword={:?}) has been saved. It'll be applied in the next EPG update.",
+        &rule.id, &rule.keyword
+    );
+    Ok(response::Json(rule))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/rules",
+    params(("id" = String, Query, description = "ULID of the rule")),
+    responses(
+        (status = 200, description = "Deleted"),
+        (status = 500, description = "Invalid query or the index is unavailable", body = String),
+    )
+)]
+async fn delete_rule_by_id(
+    axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
+) -> Result<(), String> {
+    // Check input
+    let id = params
+        .get("id")
+        .ok_or("invalid query string\n")?
+        .parse::<Ulid>()
+        .map_err(|e| e.to_string())?;
+
+    // Delete
+    let client = get_temporary_accessor();
+    delete_rule(&client, &id).await.map_err(|e| e.to_string())?;
 
     Ok(())
 }
+
+#[utoipa::path(
+    put,
+    path = "/new/series",
+    params(
+        ("id" = i64, Query, description = "Id of a program in the series"),
+        ("priority" = Option<i32>, Query, description = "Given to the schedules created by the plan"),
+        ("profile" = Option<String>, Query, description = "Output profile in `recording.profiles`"),
+    ),
+    responses(
+        (status = 200, description = "The created plan", body = SeriesPlan),
+        (status = 500, description = "Invalid query, unknown program, no series info or unknown profile", body = String),
+    )
+)]
+async fn put_series_plan(
+    schedules: Arc<Mutex<SchedQueue>>,
+    axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
+) -> Result<response::Json<SeriesPlan>, String> {
+    let client = get_temporary_accessor();
+    let program = {
+        // Check input
+        let id = params
+            .get("id")
+            .ok_or("invalid query string\n")?
+            .parse::<i64>()
+            .map_err(|e| e.to_string())?;
+        // Pull
+        pull_program(&client, id)
+            .await
+            .or_else(|e| Err(e.to_string()))?
+    };
+    let mut plan = SeriesPlan::from_program(&program).ok_or("The program has no series info.\n")?;
+    if let Some(v) = params.get("priority") {
+        plan.priority = v.parse::<i32>().map_err(|e| e.to_string())?;
+    }
+    plan.profile = params.get("profile").cloned();
+    check_profile(plan.profile.as_deref())?;
+    push_series_plans(&client, &[plan.clone()])
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // The given program itself is scheduled right now. The other episodes follow in the next EPG update.
+    let mut q_schedules = schedules.lock().await;
+    if q_schedules.items.iter().all(|f| f.program.id != program.id) {
+        let s = Schedule {
+            program,
+            plan_id: plan.plan_id(),
+            is_active: true,
+            priority: plan.priority,
+            inactive_reason: None,
+            plan_name: plan.name.clone(),
+            profile: plan.profile.clone(),
+        };
+        publish(Event::ScheduleAdded {
+            schedule: s.clone(),
+        });
+        q_schedules.items.push(s);
+        q_schedules.save();
+    }
+
+    info!(
+        "Series plan {} (series_id={}, name={:?}) has been created.",
+        &plan.id, &plan.series_id, &plan.name
+    );
+    Ok(response::Json(plan))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/series",
+    params(("id" = String, Query, description = "ULID of the plan")),
+    responses(
+        (status = 200, description = "Deleted"),
+        (status = 500, description = "Invalid query or the index is unavailable", body = String),
+    )
+)]
+async fn delete_series_plan_by_id(
+    axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
+) -> Result<(), String> {
+    // Check input
+    let id = params
+        .get("id")
+        .ok_or("invalid query string\n")?
+        .parse::<Ulid>()
+        .map_err(|e| e.to_string())?;
+
+    // Delete
+    let client = get_temporary_accessor();
+    delete_series_plan(&client, &id)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+// Lists recordings. Matches `q` if given, otherwise the latest ones come first.
+#[utoipa::path(
+    get,
+    path = "/recordings",
+    params(
+        ("q" = Option<String>, Query, description = "Search query. The newest come first if not given."),
+        ("offset" = Option<usize>, Query),
+        ("limit" = Option<usize>, Query, description = "100 by default"),
+    ),
+    responses(
+        (status = 200, description = "Recordings in the library", body = [Recording]),
+        (status = 500, description = "Invalid query or the index is unavailable", body = String),
+    )
+)]
+async fn get_recordings(
+    axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
+) -> Result<response::Json<Vec<Recording>>, String> {
+    let query = params.get("q").map(String::as_str).unwrap_or("");
+    let offset = match params.get("offset") {
+        Some(v) => v.parse::<usize>().map_err(|e| e.to_string())?,
+        None => 0,
+    };
+    let limit = match params.get("limit") {
+        Some(v) => v.parse::<usize>().map_err(|e| e.to_string())?,
+        None => 100,
+    };
+
+    let client = get_temporary_accessor();
+    search_recordings(&client, query, offset, limit)
+        .await
+        .map(response::Json)
+        .map_err(|e| e.to_string())
+}
+
+#[utoipa::path(
+    get,
+    path = "/recording",
+    params(("id" = String, Query, description = "ULID of the recording")),
+    responses(
+        (status = 200, description = "The recording", body = Recording),
+        (status = 500, description = "Invalid query or unknown recording", body = String),
+    )
+)]
+async fn get_recording_by_id(
+    axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
+) -> Result<response::Json<Recording>, String> {
+    // Check input
+    let id = params
+        .get("id")
+        .ok_or("invalid query string\n")?
+        .parse::<Ulid>()
+        .map_err(|e| e.to_string())?;
+
+    let client = get_temporary_accessor();
+    pull_recording(&client, &id)
+        .await
+        .map(response::Json)
+        .map_err(|e| e.to_string())
+}
+
+// Files are kept unless `remove_files=true` is given.
+#[utoipa::path(
+    delete,
+    path = "/recording",
+    params(
+        ("id" = String, Query, description = "ULID of the recording"),
+        ("remove_files" = Option<bool>, Query, description = "Removes the files as well"),
+    ),
+    responses(
+        (status = 200, description = "The deleted entry", body = Recording),
+        (status = 500, description = "Invalid query or unknown recording", body = String),
+    )
+)]
+async fn delete_recording_by_id(
+    axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
+) -> Result<response::Json<Recording>, String> {
+    // Check input
+    let id = params
+        .get("id")
+        .ok_or("invalid query string\n")?
+        .parse::<Ulid>()
+        .map_err(|e| e.to_string())?;
+    let remove_files = match params.get("remove_files") {
+        Some(v) => v.parse::<bool>().map_err(|e| e.to_string())?,
+        None => false,
+    };
+
+    let entry = remove_recording(&id, remove_files).await?;
+    info!(
+        "Recording {} (id: {}) has been deleted. remove_files: {}",
+        &entry.id, &entry.program.id, remove_files
+    );
+    Ok(response::Json(entry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn every_route_is_documented() {
+        let path = test_utils::temp_dir("api").join("q_schedules.json");
+        let q_schedules = Arc::new(Mutex::new(SchedQueue::load(&path).unwrap()));
+        assert_eq!(
+            routes(q_schedules).drift(&ApiDoc::openapi()),
+            Vec::<String>::new()
+        );
+    }
+}
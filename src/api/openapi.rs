@@ -0,0 +1,183 @@
+use std::collections::BTreeSet;
+
+use axum::body::Body;
+use axum::handler::Handler;
+use axum::http::Method;
+use axum::routing::{on, MethodFilter};
+use axum::Router;
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+use utoipa::openapi::PathItemType;
+use utoipa::{Modify, OpenApi};
+
+use crate::api::error::ErrorBody;
+use crate::api::v1::{NewSchedule, SchedulePatch};
+use crate::api::ScheduleAdded;
+use crate::events::Event;
+use crate::post_process::{Job, JobMetadata, JobStatus, StepState, StepStatus};
+use crate::recording_planner::series::SeriesPlan;
+use crate::recording_planner::word::{TimeOfDayRange, WordRule};
+use crate::recording_planner::PlanId;
+use crate::recording_pool::library::{Part, Recording};
+use crate::recording_pool::{RecordingPart, RecordingTaskDescription};
+use crate::sched_trigger::conflict::{ChannelKey, Conflict, TunerType};
+use crate::sched_trigger::Schedule;
+use crate::storage::volume::VolumeStatus;
+
+/// Served at `/api/openapi.json`. Every handler must be listed here, which is checked by a test.
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        super::get_openapi,
+        super::get_events,
+        super::get_schedules,
+        super::get_q_sched,
+        super::get_programs,
+        super::get_recording_tasks,
+        super::get_jobs,
+        super::get_conflicts,
+        super::put_recording_schedule,
+        super::delete_sched,
+        super::get_rules,
+        super::put_rule,
+        super::delete_rule_by_id,
+        super::get_series_plans,
+        super::put_series_plan,
+        super::delete_series_plan_by_id,
+        super::get_volumes,
+        super::get_recordings,
+        super::get_recording_by_id,
+        super::delete_recording_by_id,
+        super::v1::list_schedules,
+        super::v1::create_schedule,
+        super::v1::get_schedule,
+        super::v1::patch_schedule,
+        super::v1::delete_schedule,
+        super::v1::get_live,
+        super::v1::get_file,
+        super::v1::get_hls,
+        super::v1::get_live_hls,
+    ),
+    components(schemas(
+        Schedule,
+        PlanId,
+        ScheduleAdded,
+        Conflict,
+        ChannelKey,
+        TunerType,
+        RecordingTaskDescription,
+        RecordingPart,
+        WordRule,
+        TimeOfDayRange,
+        SeriesPlan,
+        Recording,
+        Part,
+        Job,
+        JobMetadata,
+        JobStatus,
+        StepStatus,
+        StepState,
+        VolumeStatus,
+        NewSchedule,
+        SchedulePatch,
+        ErrorBody,
+        Event,
+    )),
+    modifiers(&BearerAuth),
+    security(("bearer" = []))
+)]
+pub(crate) struct ApiDoc;
+
+// Required only if `api.auth` is set
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "bearer",
+                SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+            );
+        }
+    }
+}
+
+/// Router which remembers what is routed, so that the routes can be compared with the spec.
+pub(crate) struct Routes {
+    router: Router,
+    // (method, path in the OpenAPI syntax)
+    routed: BTreeSet<(String, String)>,
+}
+
+impl Routes {
+    pub(crate) fn new() -> Self {
+        Self {
+            router: Router::new(),
+            routed: BTreeSet::new(),
+        }
+    }
+
+    // Routes on the same path are merged by axum.
+    pub(crate) fn on<H, T>(mut self, method: Method, path: &str, handler: H) -> Self
+    where
+        H: Handler<T, Body>,
+        T: 'static,
+    {
+        let filter = MethodFilter::try_from(method.clone()).expect("Unsupported method");
+        self.router = self.router.route(path, on(filter, handler));
+        self.routed
+            .insert((method.as_str().to_lowercase(), to_openapi_path(path)));
+        self
+    }
+
+    /// Describes every route missing in the spec, and every operation in the spec which isn't routed.
+    pub(crate) fn drift(&self, doc: &utoipa::openapi::OpenApi) -> Vec<String> {
+        let documented = doc
+            .paths
+            .paths
+            .iter()
+            .flat_map(|(path, item)| {
+                item.operations
+                    .keys()
+                    .map(move |t| (method_of(t).to_string(), path.clone()))
+            })
+            .collect::<BTreeSet<_>>();
+
+        let undocumented = self
+            .routed
+            .difference(&documented)
+            .map(|(m, p)| format!("{} {} is not documented.", m.to_uppercase(), p));
+        let unrouted = documented
+            .difference(&self.routed)
+            .map(|(m, p)| format!("{} {} is documented but not routed.", m.to_uppercase(), p));
+        undocumented.chain(unrouted).collect()
+    }
+
+    pub(crate) fn into_router(self) -> Router {
+        self.router
+    }
+}
+
+// `/schedules/:id` -> `/schedules/{id}`
+fn to_openapi_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| match segment.strip_prefix(':') {
+            Some(name) => format!("{{{}}}", name),
+            None => segment.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn method_of(t: &PathItemType) -> &'static str {
+    match t {
+        PathItemType::Get => "get",
+        PathItemType::Post => "post",
+        PathItemType::Put => "put",
+        PathItemType::Delete => "delete",
+        PathItemType::Options => "options",
+        PathItemType::Head => "head",
+        PathItemType::Patch => "patch",
+        PathItemType::Trace => "trace",
+        PathItemType::Connect => "connect",
+    }
+}
@@ -0,0 +1,159 @@
+//! Serving recorded files with `Range`, so that players can seek.
+//! Only a single range is supported. Requests for multiple ranges get the whole file, which RFC 9110 allows.
+
+use std::io::SeekFrom;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use axum::body::StreamBody;
+use axum::http::header::{
+    ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_NONE_MATCH, IF_RANGE,
+    RANGE,
+};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+use crate::api::error::ApiError;
+
+fn content_type_of(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("m2ts") | Some("ts") => "video/mp2t",
+        Some("mp4") => "video/mp4",
+        Some("mkv") => "video/x-matroska",
+        _ => "application/octet-stream",
+    }
+}
+
+// Inclusive range of bytes. None means the whole file, which is also served for an invalid `Range`.
+// Err if the range is valid but starts beyond the file.
+fn parse_range(value: &str, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = match value.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return Ok(None),
+    };
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.is_empty() => (start, len.saturating_sub(1)),
+        // The last `n` bytes. None of them for 0.
+        (Err(_), Ok(0)) if start.is_empty() => return Err(()),
+        (Err(_), Ok(n)) if start.is_empty() => (len.saturating_sub(n), len.saturating_sub(1)),
+        _ => return Ok(None),
+    };
+    if len == 0 || range.0 >= len {
+        return Err(());
+    }
+    Ok(Some(range))
+}
+
+pub(crate) async fn serve_file(path: &Path, headers: &HeaderMap) -> Result<Response, ApiError> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => ApiError::not_found("The file has been removed."),
+            _ => ApiError::internal(e.to_string()),
+        })?;
+    let metadata = file
+        .metadata()
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    let len = metadata.len();
+    // Changes whenever the file is rewritten, e.g. by post-processing.
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos());
+    let etag = format!("\"{:x}-{:x}\"", len, modified);
+
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    if header(IF_NONE_MATCH).map_or(false, |v| v.split(',').any(|t| t.trim() == etag)) {
+        return Ok((StatusCode::NOT_MODIFIED, [(ETAG, etag)]).into_response());
+    }
+    // A range of an older version of the file is meaningless.
+    let range = match header(RANGE) {
+        Some(_) if header(IF_RANGE).map_or(false, |v| v != etag) => None,
+        Some(value) => match parse_range(value, len) {
+            Ok(range) => range,
+            Err(()) => {
+                return Ok((
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [(CONTENT_RANGE, format!("bytes */{}", len))],
+                )
+                    .into_response())
+            }
+        },
+        None => None,
+    };
+
+    let (status, start, end) = match range {
+        Some((start, end)) => (StatusCode::PARTIAL_CONTENT, start, end),
+        None => (StatusCode::OK, 0, len.saturating_sub(1)),
+    };
+    let length = if len == 0 { 0 } else { end - start + 1 };
+    file.seek(SeekFrom::Start(start))
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    let body = StreamBody::new(ReaderStream::new(file.take(length)));
+
+    let mut res = (
+        status,
+        [
+            (CONTENT_TYPE, content_type_of(path).to_string()),
+            (CONTENT_LENGTH, length.to_string()),
+            (ACCEPT_RANGES, "bytes".to_string()),
+            (ETAG, etag),
+        ],
+        body,
+    )
+        .into_response();
+    if status == StatusCode::PARTIAL_CONTENT {
+        res.headers_mut().insert(
+            CONTENT_RANGE,
+            format!("bytes {}-{}/{}", start, end, len).parse().unwrap(),
+        );
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_single_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Ok(Some((0, 99))));
+        assert_eq!(parse_range("bytes=500-", 1000), Ok(Some((500, 999))));
+        assert_eq!(parse_range("bytes=-100", 1000), Ok(Some((900, 999))));
+        // Clamped to the end of the file
+        assert_eq!(parse_range("bytes=900-2000", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_range("bytes=-2000", 1000), Ok(Some((0, 999))));
+    }
+
+    #[test]
+    fn ignores_invalid_range() {
+        for value in [
+            "bytes=5-3",
+            "bytes=abc",
+            "bytes=a-b",
+            "bytes=-",
+            "items=0-99",
+            "bytes=0-1,5-9",
+        ] {
+            assert_eq!(parse_range(value, 1000), Ok(None), "{}", value);
+        }
+    }
+
+    #[test]
+    fn rejects_range_out_of_file() {
+        assert_eq!(parse_range("bytes=1000-", 1000), Err(()));
+        assert_eq!(parse_range("bytes=1000-1999", 1000), Err(()));
+        assert_eq!(parse_range("bytes=-0", 1000), Err(()));
+        assert_eq!(parse_range("bytes=0-", 0), Err(()));
+    }
+}
@@ -0,0 +1,689 @@
+use std::sync::Arc;
+
+use axum::body::StreamBody;
+use axum::extract::rejection::{JsonRejection, PathRejection};
+use axum::extract::{Path, RawQuery};
+use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE};
+use axum::http::{HeaderMap, Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::Local;
+use log::{info, warn};
+use serde_derive::Deserialize;
+use tokio::sync::Mutex;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
+use ulid::Ulid;
+use utoipa::ToSchema;
+
+use crate::api::error::{ApiError, ErrorBody};
+use crate::api::openapi::Routes;
+use crate::api::playback::serve_file;
+use crate::api::{add_schedule, ScheduleAdded};
+use crate::config::get_config;
+use crate::db_utils::{get_temporary_accessor, pull_program, pull_recording};
+use crate::events::{publish, Event};
+use crate::hls;
+use crate::recording_pool::live;
+use crate::recording_pool::recording_task::sink::check_profile;
+use crate::sched_trigger::conflict::{resolve_conflicts, TUNER_MODEL};
+use crate::sched_trigger::{expected_end_at, Schedule};
+use crate::SchedQueue;
+
+/// Versioned API under `/api/v1`. Errors are sent as JSON with proper status codes.
+pub(crate) fn routes(routes: Routes, q_schedules: Arc<Mutex<SchedQueue>>) -> Routes {
+    let q_schedules1 = q_schedules.clone();
+    let q_schedules2 = q_schedules.clone();
+    let q_schedules3 = q_schedules.clone();
+    let q_schedules4 = q_schedules.clone();
+    let q_schedules5 = q_schedules.clone();
+    routes
+        .on(Method::GET, "/api/v1/schedules", move || async move {
+            list_schedules(q_schedules1).await
+        })
+        .on(Method::POST, "/api/v1/schedules", move |body| async move {
+            create_schedule(q_schedules2, body).await
+        })
+        .on(Method::GET, "/api/v1/schedules/:id", move |id| async move {
+            get_schedule(q_schedules3, id).await
+        })
+        .on(
+            Method::PATCH,
+            "/api/v1/schedules/:id",
+            move |id, body| async move { patch_schedule(q_schedules4, id, body).await },
+        )
+        .on(
+            Method::DELETE,
+            "/api/v1/schedules/:id",
+            move |id| async move { delete_schedule(q_schedules5, id).await },
+        )
+        .on(Method::GET, "/api/v1/recordings/:id/live", get_live)
+        .on(Method::GET, "/api/v1/recordings/:id/files/:n", get_file)
+        .on(Method::GET, "/api/v1/recordings/:id/hls/:file", get_hls)
+        .on(
+            Method::GET,
+            "/api/v1/recordings/:id/live/hls/:file",
+            get_live_hls,
+        )
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct NewSchedule {
+    program_id: i64,
+    #[serde(default)]
+    priority: i32,
+    // Output profile in `recording.profiles`. None means the default one.
+    #[serde(default)]
+    profile: Option<String>,
+}
+
+// Fields which are not given are left as they are.
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SchedulePatch {
+    is_active: Option<bool>,
+    priority: Option<i32>,
+    profile: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/schedules",
+    responses((status = 200, description = "All the schedules", body = [Schedule]))
+)]
+async fn list_schedules(schedules: Arc<Mutex<SchedQueue>>) -> Json<Vec<Schedule>> {
+    Json(schedules.lock().await.items.clone())
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/schedules",
+    request_body = NewSchedule,
+    responses(
+        (status = 201, description = "Scheduled", body = ScheduleAdded),
+        (status = 404, description = "Unknown program", body = ErrorBody),
+        (status = 409, description = "Already scheduled", body = ErrorBody),
+        (status = 422, description = "Invalid body, unknown profile, or the program has ended", body = ErrorBody),
+    )
+)]
+async fn create_schedule(
+    schedules: Arc<Mutex<SchedQueue>>,
+    body: Result<Json<NewSchedule>, JsonRejection>,
+) -> Result<(StatusCode, Json<ScheduleAdded>), ApiError> {
+    let Json(body) = body?;
+    check_profile(body.profile.as_deref()).map_err(ApiError::unprocessable)?;
+    let program = pull_program(&get_temporary_accessor(), body.program_id).await?;
+    if expected_end_at(&program) < Local::now() {
+        return Err(ApiError::unprocessable(format!(
+            "Program {} has already ended.",
+            program.id
+        )));
+    }
+
+    match add_schedule(&schedules, program, body.priority, body.profile).await {
+        (true, added) => Ok((StatusCode::CREATED, Json(added))),
+        (false, _) => Err(ApiError::conflict(format!(
+            "Program {} has already been scheduled.",
+            body.program_id
+        ))),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/schedules/{id}",
+    params(("id" = i64, Path, description = "Id of the program")),
+    responses(
+        (status = 200, description = "The schedule", body = Schedule),
+        (status = 404, description = "Not scheduled", body = ErrorBody),
+    )
+)]
+async fn get_schedule(
+    schedules: Arc<Mutex<SchedQueue>>,
+    id: Result<Path<i64>, PathRejection>,
+) -> Result<Json<Schedule>, ApiError> {
+    let Path(id) = id?;
+    schedules
+        .lock()
+        .await
+        .items
+        .iter()
+        .find(|s| s.program.id == id)
+        .cloned()
+        .map(Json)
+        .ok_or_else(|| not_scheduled(id))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/v1/schedules/{id}",
+    params(("id" = i64, Path, description = "Id of the program")),
+    request_body = SchedulePatch,
+    responses(
+        (status = 200, description = "The schedule after conflicts are resolved", body = Schedule),
+        (status = 404, description = "Not scheduled", body = ErrorBody),
+        (status = 422, description = "Invalid body or unknown profile", body = ErrorBody),
+    )
+)]
+async fn patch_schedule(
+    schedules: Arc<Mutex<SchedQueue>>,
+    id: Result<Path<i64>, PathRejection>,
+    body: Result<Json<SchedulePatch>, JsonRejection>,
+) -> Result<Json<Schedule>, ApiError> {
+    let Path(id) = id?;
+    let Json(patch) = body?;
+    if patch.is_active.is_none() && patch.priority.is_none() && patch.profile.is_none() {
+        return Err(ApiError::unprocessable("Nothing to update."));
+    }
+    if patch.profile.is_some() {
+        check_profile(patch.profile.as_deref()).map_err(ApiError::unprocessable)?;
+    }
+
+    let mut q_schedules = schedules.lock().await;
+    let item = q_schedules
+        .items
+        .iter_mut()
+        .find(|s| s.program.id == id)
+        .ok_or_else(|| not_scheduled(id))?;
+    if let Some(priority) = patch.priority {
+        item.priority = priority;
+    }
+    // Recordings which have started keep the profile they have started with.
+    if let Some(profile) = patch.profile {
+        item.profile = Some(profile);
+    }
+    if let Some(is_active) = patch.is_active {
+        item.is_active = is_active;
+        // Set by users, so the resolver won't reactivate it.
+        item.inactive_reason = None;
+    }
+
+    // Activation may lose to schedules with higher priority, which is shown in `inactive_reason`.
+    resolve_conflicts(&mut q_schedules.items, &TUNER_MODEL.read().unwrap());
+    q_schedules.save();
+    let item = q_schedules
+        .items
+        .iter()
+        .find(|s| s.program.id == id)
+        .cloned()
+        .ok_or_else(|| not_scheduled(id))?;
+    info!(
+        "Schedule {} has been updated. is_active: {}, priority: {}",
+        id, item.is_active, item.priority
+    );
+    publish(Event::ScheduleUpdated {
+        schedule: item.clone(),
+    });
+    Ok(Json(item))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/schedules/{id}",
+    params(("id" = i64, Path, description = "Id of the program")),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 404, description = "Not scheduled", body = ErrorBody),
+    )
+)]
+async fn delete_schedule(
+    schedules: Arc<Mutex<SchedQueue>>,
+    id: Result<Path<i64>, PathRejection>,
+) -> Result<StatusCode, ApiError> {
+    let Path(id) = id?;
+    let mut q_schedules = schedules.lock().await;
+    let before = q_schedules.items.len();
+    q_schedules.items.retain(|s| s.program.id != id);
+    if q_schedules.items.len() == before {
+        return Err(not_scheduled(id));
+    }
+    // Schedules which have lost to this one may get a tuner now.
+    resolve_conflicts(&mut q_schedules.items, &TUNER_MODEL.read().unwrap());
+    q_schedules.save();
+    info!("Schedule {} has been deleted.", id);
+    publish(Event::ScheduleRemoved {
+        program_id: id,
+        reason: "deleted".to_string(),
+    });
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Streams the TS from the moment of the request. Clients which can't keep up are disconnected.
+// The recording is identified by the program id while it is in progress, since it has no entry in the library yet.
+#[utoipa::path(
+    get,
+    path = "/api/v1/recordings/{id}/live",
+    params(("id" = i64, Path, description = "Id of the program being recorded")),
+    responses(
+        (status = 200, description = "MPEG-TS", content_type = "video/mp2t"),
+        (status = 404, description = "Not being recorded", body = ErrorBody),
+    )
+)]
+async fn get_live(id: Result<Path<i64>, PathRejection>) -> Result<impl IntoResponse, ApiError> {
+    let Path(id) = id?;
+    let rx = live::subscribe(id)
+        .ok_or_else(|| ApiError::not_found(format!("Program {} is not being recorded.", id)))?;
+    info!("id: {} A live client has joined.", id);
+
+    let stream = BroadcastStream::new(rx).map(move |received| {
+        received.map_err(|BroadcastStreamRecvError::Lagged(missed)| {
+            warn!(
+                "id: {} A live client is dropped. It has missed {} chunks.",
+                id, missed
+            );
+            std::io::Error::new(std::io::ErrorKind::Other, "The client is too slow.")
+        })
+    });
+    Ok(([(CONTENT_TYPE, "video/mp2t")], StreamBody::new(stream)))
+}
+
+// Files are looked up through the library, so that no path is taken from the request.
+#[utoipa::path(
+    get,
+    path = "/api/v1/recordings/{id}/files/{n}",
+    params(
+        ("id" = String, Path, description = "ULID of the recording"),
+        ("n" = usize, Path, description = "Index in `files`, starting from 0"),
+    ),
+    responses(
+        (status = 200, description = "The whole file", content_type = "video/mp2t"),
+        (status = 206, description = "The range given in `Range`", content_type = "video/mp2t"),
+        (status = 304, description = "Not modified since the ETag in `If-None-Match`"),
+        (status = 404, description = "Unknown recording or file", body = ErrorBody),
+        (status = 416, description = "The range is out of the file"),
+    )
+)]
+async fn get_file(
+    path: Result<Path<(Ulid, usize)>, PathRejection>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let Path((id, n)) = path?;
+    let entry = pull_recording(&get_temporary_accessor(), &id).await?;
+    let file = entry
+        .files
+        .get(n)
+        .ok_or_else(|| ApiError::not_found(format!("Recording {} has no file #{}.", id, n)))?;
+    serve_file(file, &headers).await
+}
+
+// Segmented on the first request. The playlist grows until `#EXT-X-ENDLIST` is appended.
+#[utoipa::path(
+    get,
+    path = "/api/v1/recordings/{id}/hls/{file}",
+    params(
+        ("id" = String, Path, description = "ULID of the recording"),
+        ("file" = String, Path, description = "`index.m3u8`, or a segment listed in it"),
+    ),
+    responses(
+        (status = 200, description = "The playlist or a segment", content_type = "application/vnd.apple.mpegurl"),
+        (status = 206, description = "The range of a segment given in `Range`", content_type = "video/mp2t"),
+        (status = 404, description = "HLS is disabled, or unknown recording or segment", body = ErrorBody),
+        (status = 422, description = "The recording is not MPEG-TS", body = ErrorBody),
+    )
+)]
+async fn get_hls(
+    path: Result<Path<(Ulid, String)>, PathRejection>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let Path((id, file)) = path?;
+    check_hls_enabled()?;
+    let name = hls::Name::parse(&file).ok_or_else(|| unknown_segment(&file))?;
+    let entry = pull_recording(&get_temporary_accessor(), &id).await?;
+    // Files re-encoded by post-processing can't be segmented.
+    let is_ts = |f: &std::path::PathBuf| {
+        matches!(
+            f.extension().and_then(|e| e.to_str()),
+            Some("ts") | Some("m2ts")
+        )
+    };
+    if !entry.files.iter().all(is_ts) {
+        return Err(ApiError::unprocessable(format!(
+            "Recording {} is not MPEG-TS.",
+            id
+        )));
+    }
+
+    let dir = hls::vod::prepare(id, entry.files)
+        .await
+        .map_err(ApiError::internal)?;
+    match name {
+        hls::Name::Playlist => {
+            let m3u8 = tokio::fs::read_to_string(dir.join(hls::PLAYLIST))
+                .await
+                .map_err(|_| ApiError::not_found(format!("Failed to segment recording {}.", id)))?;
+            Ok(playlist(hls::with_query(&m3u8, query.as_deref())))
+        }
+        hls::Name::Segment(seq) => serve_file(&dir.join(hls::segment_name(seq)), &headers).await,
+    }
+}
+
+// The playlist has the latest segments only. Segmenting stops a while after the last request.
+#[utoipa::path(
+    get,
+    path = "/api/v1/recordings/{id}/live/hls/{file}",
+    params(
+        ("id" = i64, Path, description = "Id of the program being recorded"),
+        ("file" = String, Path, description = "`index.m3u8`, or a segment listed in it"),
+    ),
+    responses(
+        (status = 200, description = "The playlist or a segment", content_type = "application/vnd.apple.mpegurl"),
+        (status = 404, description = "HLS is disabled, not being recorded, or the segment has expired", body = ErrorBody),
+    )
+)]
+async fn get_live_hls(
+    path: Result<Path<(i64, String)>, PathRejection>,
+    RawQuery(query): RawQuery,
+) -> Result<Response, ApiError> {
+    let Path((id, file)) = path?;
+    check_hls_enabled()?;
+    match hls::Name::parse(&file).ok_or_else(|| unknown_segment(&file))? {
+        hls::Name::Playlist => {
+            if !hls::live::open(id) {
+                return Err(ApiError::not_found(format!(
+                    "Program {} is not being recorded.",
+                    id
+                )));
+            }
+            let m3u8 = hls::live::playlist(id).ok_or_else(|| unknown_segment(&file))?;
+            Ok(playlist(hls::with_query(&m3u8, query.as_deref())))
+        }
+        hls::Name::Segment(seq) => hls::live::segment(id, seq)
+            .map(|data| ([(CONTENT_TYPE, "video/mp2t")], data).into_response())
+            .ok_or_else(|| unknown_segment(&file)),
+    }
+}
+
+fn check_hls_enabled() -> Result<(), ApiError> {
+    if !get_config().hls.enabled {
+        return Err(ApiError::not_found("HLS is disabled."));
+    }
+    Ok(())
+}
+
+// Playlists change as segments are added.
+fn playlist(m3u8: String) -> Response {
+    (
+        [
+            (CONTENT_TYPE, "application/vnd.apple.mpegurl"),
+            (CACHE_CONTROL, "no-cache"),
+        ],
+        m3u8,
+    )
+        .into_response()
+}
+
+fn unknown_segment(file: &str) -> ApiError {
+    ApiError::not_found(format!("{} is not found.", file))
+}
+
+fn not_scheduled(id: i64) -> ApiError {
+    ApiError::not_found(format!("Program {} is not scheduled.", id))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::{Body, HttpBody};
+    use axum::http::Request;
+    use axum::Router;
+    use chrono::Duration;
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_utils;
+
+    // The versioned API over an empty queue of its own
+    fn app() -> Router {
+        app_over(queue())
+    }
+
+    fn queue() -> Arc<Mutex<SchedQueue>> {
+        test_utils::config();
+        let path = test_utils::temp_dir("v1").join("q_schedules.json");
+        Arc::new(Mutex::new(SchedQueue::load(&path).unwrap()))
+    }
+
+    fn app_over(q_schedules: Arc<Mutex<SchedQueue>>) -> Router {
+        routes(Routes::new(), q_schedules).into_router()
+    }
+
+    async fn send(
+        app: &Router,
+        method: Method,
+        uri: &str,
+        body: Option<&str>,
+    ) -> (StatusCode, Value) {
+        let mut request = Request::builder().method(method).uri(uri);
+        if body.is_some() {
+            request = request.header(CONTENT_TYPE, "application/json");
+        }
+        let body = body.map_or_else(Body::empty, |b| Body::from(b.to_string()));
+        let response = app
+            .clone()
+            .oneshot(request.body(body).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let mut body = response.into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        if bytes.is_empty() {
+            return (status, Value::Null);
+        }
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    // Puts a program of 30 minutes in the EPG, starting in an hour or having ended an hour ago.
+    fn program(event_id: u16, ended: bool) -> i64 {
+        let start_at = if ended {
+            Local::now() - Duration::hours(2)
+        } else {
+            Local::now() + Duration::hours(1)
+        };
+        let program =
+            test_utils::program((0x7fe0, 0x0400, event_id), start_at, Some(30 * 60 * 1000));
+        test_utils::put_documents("_programs", vec![serde_json::to_value(&program).unwrap()]);
+        program.id
+    }
+
+    async fn create(app: &Router, id: i64) -> (StatusCode, Value) {
+        let body = json!({ "program_id": id }).to_string();
+        send(app, Method::POST, "/api/v1/schedules", Some(&body)).await
+    }
+
+    #[tokio::test]
+    async fn lists_and_gets_schedules() {
+        let app = app();
+        let id = program(0x2001, false);
+        assert_eq!(create(&app, id).await.0, StatusCode::CREATED);
+
+        let (status, list) = send(&app, Method::GET, "/api/v1/schedules", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(list.as_array().unwrap().len(), 1);
+        assert_eq!(list[0]["program"]["id"], id);
+
+        let uri = format!("/api/v1/schedules/{}", id);
+        let (status, schedule) = send(&app, Method::GET, &uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(schedule["program"]["id"], id);
+
+        let (status, error) = send(&app, Method::GET, "/api/v1/schedules/1", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(error["error"], "not_found");
+        let (status, _) = send(&app, Method::GET, "/api/v1/schedules/abc", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn creates_schedule_once() {
+        let app = app();
+        let id = program(0x2002, false);
+        let (status, added) = create(&app, id).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(added["schedule"]["program"]["id"], id);
+        assert_eq!(added["schedule"]["is_active"], true);
+
+        let (status, error) = create(&app, id).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(error["error"], "conflict");
+
+        let (status, error) = create(&app, program(0x2003, true)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error["error"], "unprocessable");
+
+        let body = json!({ "program_id": program(0x2004, false), "profile": "none" }).to_string();
+        let (status, _) = send(&app, Method::POST, "/api/v1/schedules", Some(&body)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+        let (status, _) = create(&app, 1).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn patches_schedule() {
+        let app = app();
+        let id = program(0x2005, false);
+        create(&app, id).await;
+        let uri = format!("/api/v1/schedules/{}", id);
+
+        let body = r#"{"priority": 5, "is_active": false}"#;
+        let (status, schedule) = send(&app, Method::PATCH, &uri, Some(body)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(schedule["priority"], 5);
+        assert_eq!(schedule["is_active"], false);
+
+        let (status, _) = send(&app, Method::PATCH, "/api/v1/schedules/1", Some(body)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = send(&app, Method::PATCH, &uri, Some("{}")).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let body = r#"{"profile": "none"}"#;
+        let (status, _) = send(&app, Method::PATCH, &uri, Some(body)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn deactivation_by_user_is_told_from_resolver() {
+        let q_schedules = queue();
+        let app = app_over(q_schedules.clone());
+        let id = program(0x2009, false);
+        create(&app, id).await;
+        let uri = format!("/api/v1/schedules/{}", id);
+
+        // Lost a conflict
+        {
+            let mut q_schedules = q_schedules.lock().await;
+            let item = &mut q_schedules.items[0];
+            item.is_active = false;
+            item.inactive_reason = Some("No tuner is left.".to_string());
+        }
+        // Other changes leave it to the resolver.
+        let (_, schedule) = send(&app, Method::PATCH, &uri, Some(r#"{"priority": 3}"#)).await;
+        assert_eq!(schedule["is_active"], false);
+        assert_eq!(schedule["inactive_reason"], "No tuner is left.");
+
+        // Deactivated by the user, it won't be reactivated by the resolver.
+        let body = r#"{"is_active": false}"#;
+        let (_, schedule) = send(&app, Method::PATCH, &uri, Some(body)).await;
+        assert_eq!(schedule["is_active"], false);
+        assert_eq!(schedule["inactive_reason"], Value::Null);
+        assert!(q_schedules.lock().await.items[0].inactive_reason.is_none());
+
+        let body = r#"{"is_active": true}"#;
+        let (_, schedule) = send(&app, Method::PATCH, &uri, Some(body)).await;
+        assert_eq!(schedule["is_active"], true);
+        assert_eq!(schedule["inactive_reason"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn deletes_schedule() {
+        let app = app();
+        let id = program(0x2006, false);
+        create(&app, id).await;
+        let uri = format!("/api/v1/schedules/{}", id);
+
+        let (status, body) = send(&app, Method::DELETE, &uri, None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(body, Value::Null);
+        let (status, _) = send(&app, Method::DELETE, &uri, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, list) = send(&app, Method::GET, "/api/v1/schedules", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(list, json!([]));
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_and_unknown_fields() {
+        let app = app();
+        let id = program(0x2007, false);
+        let uri = format!("/api/v1/schedules/{}", id);
+
+        let (status, error) = send(&app, Method::POST, "/api/v1/schedules", Some("{")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error["error"], "bad_request");
+        let body = json!({ "program_id": id, "color": "red" }).to_string();
+        let (status, error) = send(&app, Method::POST, "/api/v1/schedules", Some(&body)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error["error"], "unprocessable");
+
+        create(&app, id).await;
+        let (status, _) = send(&app, Method::PATCH, &uri, Some("[")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let body = r#"{"priority": 1, "color": "red"}"#;
+        let (status, _) = send(&app, Method::PATCH, &uri, Some(body)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        // Without Content-Type
+        let (status, _) = send(&app, Method::POST, "/api/v1/schedules", None).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn live_is_served_by_program_id() {
+        let app = app();
+        let id = program(0x2008, false);
+        create(&app, id).await;
+
+        // Scheduled, but not being recorded yet
+        let uri = format!("/api/v1/recordings/{}/live", id);
+        let (status, error) = send(&app, Method::GET, &uri, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            error["message"],
+            format!("Program {} is not being recorded.", id)
+        );
+        let (status, _) = send(&app, Method::GET, "/api/v1/recordings/abc/live", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let feed = live::LiveFeed::open(id);
+        let request = || Request::get(&uri).body(Body::empty()).unwrap();
+        let mut keeping_up = app.clone().oneshot(request()).await.unwrap().into_body();
+        let mut lagging = app.clone().oneshot(request()).await.unwrap().into_body();
+
+        // Sending never waits for the lagging client, which reads nothing.
+        for i in 0..live::CAPACITY + 1 {
+            feed.send(&bytes::Bytes::from(i.to_string()));
+            if i < 2 {
+                let chunk = keeping_up.data().await.unwrap().unwrap();
+                assert_eq!(chunk, i.to_string());
+            }
+        }
+        // It has missed the oldest chunk, and is disconnected.
+        assert!(lagging.data().await.unwrap().is_err());
+        // The other one is within the buffer, and goes on.
+        let chunk = keeping_up.data().await.unwrap().unwrap();
+        assert_eq!(chunk, &b"2"[..]);
+
+        // Dropping the feed ends the stream of the clients which have kept up.
+        let mut ended = app.clone().oneshot(request()).await.unwrap().into_body();
+        feed.send(&bytes::Bytes::from_static(b"last"));
+        drop(feed);
+        assert_eq!(ended.data().await.unwrap().unwrap(), &b"last"[..]);
+        assert!(ended.data().await.is_none());
+        let (status, _) = send(&app, Method::GET, &uri, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,960 @@
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use once_cell::sync::OnceCell;
+use serde_derive::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::recording_pool::naming;
+use crate::Opt;
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+/// Settings loaded from the TOML file and merged with the command line arguments.
+/// Every key is optional, and the defaults are the values that used to be hard-coded.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) mirakurun: MirakurunConfig,
+    pub(crate) meilisearch: MeilisearchConfig,
+    pub(crate) api: ApiConfig,
+    pub(crate) recording: RecordingConfig,
+    pub(crate) naming: NamingConfig,
+    pub(crate) post_process: PostProcessConfig,
+    pub(crate) storage: StorageConfig,
+    pub(crate) timers: TimersConfig,
+    pub(crate) hls: HlsConfig,
+    // The file the config has been loaded from. Tokens in `api.tokens` are re-read from it.
+    #[serde(skip)]
+    pub(crate) path: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct MirakurunConfig {
+    pub(crate) base_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct MeilisearchConfig {
+    pub(crate) base_uri: String,
+    pub(crate) api_key: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ApiConfig {
+    pub(crate) listen: SocketAddr,
+    // Requests without a valid token are rejected. Tokens are minted with `token mint`.
+    pub(crate) auth: bool,
+    pub(crate) token_store: TokenStore,
+    // Hashed tokens, used if `token_store` is "config"
+    pub(crate) tokens: Vec<TokenConfig>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TokenStore {
+    // `api.tokens` in the config file
+    Config,
+    // The OS keyring (e.g. Secret Service, Keychain)
+    Keyring,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TokenConfig {
+    pub(crate) name: String,
+    // SHA-256 of the token in lowercase hex. The token itself is never stored.
+    pub(crate) sha256: String,
+    pub(crate) scope: Scope,
+}
+
+// Admin includes Read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Scope {
+    // GET and HEAD
+    Read,
+    // Every method
+    Admin,
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(format!("{:?} is neither read nor admin.", s)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RecordingConfig {
+    // A bare name is looked up in PATH.
+    pub(crate) tsreadex_path: PathBuf,
+    pub(crate) pre_roll_minutes: i64,
+    // Hard limit (in minutes from the start) of recordings whose duration is unknown
+    pub(crate) unknown_duration_limit_minutes: i64,
+    // Output pipelines selected by schedules and plans. "default" replaces the built-in one.
+    pub(crate) profiles: Vec<ProfileConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ProfileConfig {
+    pub(crate) name: String,
+    // The stream passes through them in order. The last one is `file` or `null`.
+    pub(crate) stages: Vec<StageConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum StageConfig {
+    // Pipes the stream through stdin and stdout of the program.
+    Command {
+        program: PathBuf,
+        #[serde(default)]
+        args: Vec<String>,
+        // The stage is skipped if the program fails to start.
+        #[serde(default)]
+        optional: bool,
+    },
+    // Drops the packets of the PIDs in process.
+    DropPids {
+        pids: Vec<u16>,
+    },
+    // Keeps the PIDs of a single service and rewrites the PAT to list it alone, in process.
+    Service {
+        // The service of the program if not given. The first one in the PAT if it isn't there either.
+        #[serde(default)]
+        service_id: Option<u16>,
+        // PSI/SI dropped as well
+        #[serde(default = "default_dropped_pids")]
+        drop_pids: Vec<u16>,
+    },
+    // Writes SHA-256 of the output to `<output>.sha256`. Must be right before `file`.
+    Checksum,
+    // Copies the stream at this point to the output with the extension replaced. See sink::copy_of.
+    Tee {
+        extension: String,
+    },
+    // Writes the output file.
+    File,
+    // Discards the stream, e.g. for testing tuners.
+    Null,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct NamingConfig {
+    // Path of recordings relative to the save directory, without extension. See recording_pool::naming.
+    pub(crate) template: String,
+    // Limit of each path component in bytes, including the extension
+    pub(crate) max_bytes: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct PostProcessConfig {
+    // Number of jobs running at the same time. The others wait in the queue.
+    pub(crate) max_concurrent_jobs: usize,
+    // Run in order for every recording which has ended gracefully
+    pub(crate) steps: Vec<StepConfig>,
+}
+
+// `deny_unknown_fields` cannot be used together with `flatten`.
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct StepConfig {
+    #[serde(default)]
+    pub(crate) name: Option<String>,
+    #[serde(flatten)]
+    pub(crate) action: StepAction,
+    #[serde(default = "default_step_timeout_secs")]
+    pub(crate) timeout_secs: u64,
+    // Additional attempts after a failure
+    #[serde(default)]
+    pub(crate) retries: u32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum StepAction {
+    // Metadata is given as REC_* environment variables and JSON in stdin.
+    Command {
+        program: PathBuf,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    // Moves the recording and its sidecars into the directory. The following steps see the new location.
+    Move {
+        to: PathBuf,
+    },
+    // POSTs the metadata as JSON.
+    Notify {
+        url: String,
+    },
+}
+
+// EIT, including the ones for one-seg and L-EIT
+fn default_dropped_pids() -> Vec<u16> {
+    vec![0x12, 0x26, 0x27]
+}
+
+fn default_step_timeout_secs() -> u64 {
+    600
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct StorageConfig {
+    // Recordings are not started if the free space of the save directory is below this.
+    pub(crate) min_free_mib: u64,
+    pub(crate) placement: PlacementStrategy,
+    // The current directory is the only volume if none is given.
+    pub(crate) volumes: Vec<VolumeConfig>,
+    pub(crate) retention: RetentionConfig,
+    // The queues of schedules and recordings are written here.
+    pub(crate) state_dir: PathBuf,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PlacementStrategy {
+    MostFree,
+    RoundRobin,
+    // Volumes which name the plan in `allowed_plans` first, then the first one which has room
+    Pinned,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct VolumeConfig {
+    pub(crate) name: String,
+    pub(crate) path: PathBuf,
+    // Limit of the total size of the files under `path`
+    #[serde(default)]
+    pub(crate) max_gib: Option<u64>,
+    // "common", "word", "series" or ULIDs of plans. Every plan is allowed if empty.
+    #[serde(default)]
+    pub(crate) allowed_plans: Vec<String>,
+}
+
+// Every policy is disabled if not given.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RetentionConfig {
+    // Number of the latest recordings kept for each series plan
+    pub(crate) keep_per_series: Option<usize>,
+    pub(crate) max_age_days: Option<i64>,
+    // The oldest recordings are deleted while the library is larger than this.
+    pub(crate) quota_gib: Option<u64>,
+    pub(crate) interval_secs: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct TimersConfig {
+    pub(crate) epg_refresh_secs: u64,
+    pub(crate) scheduler_scan_secs: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct HlsConfig {
+    // Playlists and segments are served only if enabled.
+    pub(crate) enabled: bool,
+    // Segments are cut at the first keyframe after this.
+    pub(crate) segment_secs: u64,
+    // Number of segments in the playlist of a recording in progress
+    pub(crate) live_segments: usize,
+    // Library items are segmented into here on the first request.
+    pub(crate) cache_dir: PathBuf,
+    // Limit of the total size of `cache_dir`. The least recently requested items are removed beyond it.
+    pub(crate) cache_max_gib: Option<u64>,
+}
+
+impl Default for MirakurunConfig {
+    fn default() -> Self {
+        Self {
+            base_uri: "http://localhost:40772/api".to_string(),
+        }
+    }
+}
+
+impl Default for MeilisearchConfig {
+    fn default() -> Self {
+        Self {
+            base_uri: "http://localhost:7700/".to_string(),
+            api_key: "masterKey".to_string(),
+        }
+    }
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            auth: false,
+            token_store: TokenStore::Config,
+            tokens: Vec::new(),
+        }
+    }
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        Self {
+            tsreadex_path: PathBuf::from("tsreadex"),
+            pre_roll_minutes: 10,
+            unknown_duration_limit_minutes: 240,
+            profiles: Vec::new(),
+        }
+    }
+}
+
+impl Default for NamingConfig {
+    fn default() -> Self {
+        Self {
+            template: "{plan}/{id}_{title}".to_string(),
+            max_bytes: 255,
+        }
+    }
+}
+
+impl Default for PostProcessConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent_jobs: 1,
+            steps: Vec::new(),
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            min_free_mib: 2048,
+            placement: PlacementStrategy::MostFree,
+            volumes: Vec::new(),
+            retention: RetentionConfig::default(),
+            state_dir: PathBuf::from("./"),
+        }
+    }
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            keep_per_series: None,
+            max_age_days: None,
+            quota_gib: None,
+            interval_secs: 3600,
+        }
+    }
+}
+
+impl Default for TimersConfig {
+    fn default() -> Self {
+        Self {
+            epg_refresh_secs: 600,
+            scheduler_scan_secs: 5,
+        }
+    }
+}
+
+impl Default for HlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            segment_secs: 6,
+            live_segments: 6,
+            cache_dir: PathBuf::from("./hls"),
+            cache_max_gib: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(&'static str, String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "Failed to read {}. {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "Failed to parse {}. {}", path.display(), e),
+            ConfigError::Invalid(key, reason) => {
+                write!(f, "Invalid value in `{}`. {}", key, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Reads the file given by `--config`, then overwrites it with the other arguments.
+    /// A missing file is an error only if it was explicitly given.
+    pub(crate) fn load(args: &Opt) -> Result<Self, ConfigError> {
+        let path = match &args.config {
+            Some(path) => Some(path.clone()),
+            None if Path::new("./config.toml").exists() => Some(PathBuf::from("./config.toml")),
+            None => None,
+        };
+        let mut config = match &path {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        config.path = path;
+
+        if let Some(ref uri) = args.mirakurun_base_uri {
+            config.mirakurun.base_uri = uri.clone();
+        }
+        if let Some(ref uri) = args.meilisearch_base_uri {
+            config.meilisearch.base_uri = uri.clone();
+        }
+        if let Some(ref key) = args.meilisearch_api_key {
+            config.meilisearch.api_key = key.clone();
+        }
+        if let Some(limit) = args.unknown_duration_limit {
+            config.recording.unknown_duration_limit_minutes = limit;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let str =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        toml::from_str(&str).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        for &(key, is_valid, reason) in RULES {
+            if !is_valid(self) {
+                return Err(ConfigError::Invalid(key, reason.to_string()));
+            }
+        }
+        check_uri("mirakurun.base_uri", &self.mirakurun.base_uri)?;
+        check_uri("meilisearch.base_uri", &self.meilisearch.base_uri)?;
+        // Only paths are checked here. Bare names are resolved when tsreadex is spawned.
+        check_program("recording.tsreadex_path", &self.recording.tsreadex_path)?;
+        for (i, profile) in self.recording.profiles.iter().enumerate() {
+            check_unique(
+                "recording.profiles.name",
+                &profile.name,
+                self.recording.profiles[..i].iter().map(|p| &p.name),
+            )?;
+            check_stages(&profile.stages).map_err(|e| {
+                ConfigError::Invalid(
+                    "recording.profiles.stages",
+                    format!("{:?}: {}", profile.name, e),
+                )
+            })?;
+        }
+        naming::validate_template(&self.naming.template)
+            .map_err(|e| ConfigError::Invalid("naming.template", e))?;
+        for step in &self.post_process.steps {
+            match &step.action {
+                StepAction::Command { program, .. } => {
+                    check_program("post_process.steps.program", program)?
+                }
+                StepAction::Move { .. } => {}
+                StepAction::Notify { url } => check_uri("post_process.steps.url", url)?,
+            }
+        }
+        for (i, volume) in self.storage.volumes.iter().enumerate() {
+            check_unique(
+                "storage.volumes.name",
+                &volume.name,
+                self.storage.volumes[..i].iter().map(|v| &v.name),
+            )?;
+            if let Some(plan) = volume.allowed_plans.iter().find(|plan| {
+                !matches!(plan.as_str(), "common" | "word" | "series")
+                    && plan.parse::<Ulid>().is_err()
+            }) {
+                return Err(ConfigError::Invalid(
+                    "storage.volumes.allowed_plans",
+                    format!("{:?} is neither a kind of plans nor a ULID.", plan),
+                ));
+            }
+        }
+        for (i, token) in self.api.tokens.iter().enumerate() {
+            if token.sha256.len() != 64 || !token.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(ConfigError::Invalid(
+                    "api.tokens.sha256",
+                    format!("The hash of {:?} is not a SHA-256 in hex.", token.name),
+                ));
+            }
+            check_unique(
+                "api.tokens.name",
+                &token.name,
+                self.api.tokens[..i].iter().map(|t| &t.name),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+const NOT_EMPTY: &str = "It must not be empty.";
+const POSITIVE: &str = "It must be 1 or more.";
+
+// The key, whether the value is valid, and the reason if it isn't
+type Rule = (&'static str, fn(&Config) -> bool, &'static str);
+
+// Values which are checked by themselves. The others are checked in `validate` with the values they depend on.
+const RULES: &[Rule] = &[
+    (
+        "meilisearch.api_key",
+        |c| !c.meilisearch.api_key.is_empty(),
+        NOT_EMPTY,
+    ),
+    (
+        "recording.pre_roll_minutes",
+        |c| c.recording.pre_roll_minutes >= 0,
+        "It must be 0 or more.",
+    ),
+    (
+        "recording.unknown_duration_limit_minutes",
+        |c| c.recording.unknown_duration_limit_minutes > 0,
+        POSITIVE,
+    ),
+    (
+        "recording.profiles.name",
+        |c| c.recording.profiles.iter().all(|p| !p.name.is_empty()),
+        NOT_EMPTY,
+    ),
+    // The extension and a collision suffix must fit in with some characters of the title.
+    (
+        "naming.max_bytes",
+        |c| c.naming.max_bytes >= 32,
+        "It must be 32 or more.",
+    ),
+    (
+        "post_process.max_concurrent_jobs",
+        |c| c.post_process.max_concurrent_jobs > 0,
+        POSITIVE,
+    ),
+    (
+        "post_process.steps.timeout_secs",
+        |c| c.post_process.steps.iter().all(|s| s.timeout_secs > 0),
+        POSITIVE,
+    ),
+    (
+        "post_process.steps.to",
+        |c| {
+            c.post_process.steps.iter().all(|s| match &s.action {
+                StepAction::Move { to } => !to.as_os_str().is_empty(),
+                _ => true,
+            })
+        },
+        NOT_EMPTY,
+    ),
+    (
+        "storage.volumes",
+        |c| {
+            c.storage
+                .volumes
+                .iter()
+                .all(|v| !v.name.is_empty() && !v.path.as_os_str().is_empty())
+        },
+        "`name` and `path` must not be empty.",
+    ),
+    (
+        "storage.volumes.max_gib",
+        |c| c.storage.volumes.iter().all(|v| v.max_gib != Some(0)),
+        POSITIVE,
+    ),
+    (
+        "storage.retention.keep_per_series",
+        |c| c.storage.retention.keep_per_series != Some(0),
+        POSITIVE,
+    ),
+    (
+        "storage.retention.max_age_days",
+        |c| {
+            c.storage
+                .retention
+                .max_age_days
+                .map_or(true, |days| days > 0)
+        },
+        POSITIVE,
+    ),
+    (
+        "storage.retention.quota_gib",
+        |c| c.storage.retention.quota_gib != Some(0),
+        POSITIVE,
+    ),
+    (
+        "storage.retention.interval_secs",
+        |c| c.storage.retention.interval_secs > 0,
+        POSITIVE,
+    ),
+    (
+        "storage.state_dir",
+        |c| !c.storage.state_dir.as_os_str().is_empty(),
+        NOT_EMPTY,
+    ),
+    ("hls.segment_secs", |c| c.hls.segment_secs > 0, POSITIVE),
+    // Players start a few segments behind the end of the playlist.
+    (
+        "hls.live_segments",
+        |c| c.hls.live_segments >= 3,
+        "It must be 3 or more.",
+    ),
+    (
+        "hls.cache_dir",
+        |c| !c.hls.cache_dir.as_os_str().is_empty(),
+        NOT_EMPTY,
+    ),
+    (
+        "hls.cache_max_gib",
+        |c| c.hls.cache_max_gib != Some(0),
+        POSITIVE,
+    ),
+    (
+        "timers.epg_refresh_secs",
+        |c| c.timers.epg_refresh_secs > 0,
+        POSITIVE,
+    ),
+    (
+        "timers.scheduler_scan_secs",
+        |c| c.timers.scheduler_scan_secs > 0,
+        POSITIVE,
+    ),
+];
+
+fn check_unique<'a>(
+    key: &'static str,
+    name: &str,
+    mut others: impl Iterator<Item = &'a String>,
+) -> Result<(), ConfigError> {
+    if others.any(|other| other == name) {
+        Err(ConfigError::Invalid(
+            key,
+            format!("{:?} is used twice.", name),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+// A bare name is looked up in PATH when the program is spawned.
+fn check_program(key: &'static str, program: &Path) -> Result<(), ConfigError> {
+    if program.components().count() > 1 && !program.is_file() {
+        Err(ConfigError::Invalid(
+            key,
+            format!("{} is not found.", program.display()),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn check_uri(key: &'static str, uri: &str) -> Result<(), ConfigError> {
+    if uri.starts_with("http://") || uri.starts_with("https://") {
+        Ok(())
+    } else {
+        Err(ConfigError::Invalid(
+            key,
+            format!("{:?} is not an http(s) URI.", uri),
+        ))
+    }
+}
+
+// The output must be opened exactly once, at the end.
+fn check_stages(stages: &[StageConfig]) -> Result<(), String> {
+    let (last, rest) = stages.split_last().ok_or("No stage is given.")?;
+    if !matches!(last, StageConfig::File | StageConfig::Null) {
+        return Err("The last stage must be `file` or `null`.".to_string());
+    }
+    for (i, stage) in rest.iter().enumerate() {
+        match stage {
+            StageConfig::File | StageConfig::Null => {
+                return Err("`file` and `null` must be the last stage.".to_string())
+            }
+            StageConfig::Command { program, .. } => {
+                if program.components().count() > 1 && !program.is_file() {
+                    return Err(format!("{} is not found.", program.display()));
+                }
+            }
+            StageConfig::DropPids { pids }
+            | StageConfig::Service {
+                drop_pids: pids, ..
+            } => {
+                if let Some(pid) = pids.iter().find(|pid| **pid > 0x1fff) {
+                    return Err(format!("{} is not a PID.", pid));
+                }
+            }
+            // The output itself and the temporary one
+            StageConfig::Tee { extension } => {
+                if matches!(extension.as_str(), "" | "m2ts" | "m2ts-tmp") {
+                    return Err(format!("{:?} can't be the extension of `tee`.", extension));
+                }
+            }
+            // The digest would not match the file otherwise.
+            StageConfig::Checksum => {
+                if i + 1 != rest.len() || !matches!(last, StageConfig::File) {
+                    return Err("`checksum` must be right before `file`.".to_string());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Must be called once at startup, before any other module reads the config.
+pub(crate) fn init(config: Config) {
+    CONFIG.set(config).expect("Config is initialized twice.");
+}
+
+// Tests share one config, since it cannot be replaced.
+#[cfg(test)]
+pub(crate) fn init_for_tests(config: impl FnOnce() -> Config) -> &'static Config {
+    CONFIG.get_or_init(config)
+}
+
+pub(crate) fn get_config() -> &'static Config {
+    CONFIG.get().expect("Config is not initialized yet.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    fn args(config: Option<PathBuf>) -> Opt {
+        Opt {
+            mirakurun_base_uri: None,
+            meilisearch_base_uri: None,
+            meilisearch_api_key: None,
+            unknown_duration_limit: None,
+            config,
+            cmd: None,
+        }
+    }
+
+    fn write(toml: &str) -> PathBuf {
+        let path = test_utils::temp_dir("config").join("config.toml");
+        std::fs::write(&path, toml).unwrap();
+        path
+    }
+
+    // The key of the error, if the config is invalid
+    fn invalid_key(toml: &str) -> Option<&'static str> {
+        match toml::from_str::<Config>(toml).unwrap().validate() {
+            Ok(()) => None,
+            Err(ConfigError::Invalid(key, _)) => Some(key),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn arguments_take_precedence_over_file() {
+        let path = write(
+            r#"
+            [mirakurun]
+            base_uri = "http://tuner:40772/api"
+            [meilisearch]
+            api_key = "fromFile"
+            [recording]
+            pre_roll_minutes = 5
+            unknown_duration_limit_minutes = 120
+            "#,
+        );
+        let config = Config::load(&Opt {
+            mirakurun_base_uri: Some("http://other:40772/api".to_string()),
+            unknown_duration_limit: Some(60),
+            ..args(Some(path.clone()))
+        })
+        .unwrap();
+        assert_eq!(config.path, Some(path));
+        assert_eq!(config.mirakurun.base_uri, "http://other:40772/api");
+        assert_eq!(config.recording.unknown_duration_limit_minutes, 60);
+        // Given only in the file
+        assert_eq!(config.meilisearch.api_key, "fromFile");
+        assert_eq!(config.recording.pre_roll_minutes, 5);
+        // Given in neither
+        assert_eq!(config.meilisearch.base_uri, "http://localhost:7700/");
+        assert_eq!(config.timers.scheduler_scan_secs, 5);
+    }
+
+    #[test]
+    fn arguments_are_validated_as_well() {
+        let path = write("");
+        let result = Config::load(&Opt {
+            meilisearch_base_uri: Some("localhost:7700".to_string()),
+            ..args(Some(path))
+        });
+        assert!(matches!(
+            result,
+            Err(ConfigError::Invalid("meilisearch.base_uri", _))
+        ));
+    }
+
+    #[test]
+    fn given_file_must_be_readable() {
+        let missing = test_utils::temp_dir("config").join("missing.toml");
+        assert!(matches!(
+            Config::load(&args(Some(missing))),
+            Err(ConfigError::Io(..))
+        ));
+        let unknown_key = write("[recording]\npre_roll = 5\n");
+        assert!(matches!(
+            Config::load(&args(Some(unknown_key))),
+            Err(ConfigError::Parse(..))
+        ));
+        let wrong_type = write("[timers]\nepg_refresh_secs = \"600\"\n");
+        assert!(matches!(
+            Config::load(&args(Some(wrong_type))),
+            Err(ConfigError::Parse(..))
+        ));
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert_eq!(invalid_key(""), None);
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn invalid_values_are_rejected_by_key() {
+        let sha256 = "0".repeat(64);
+        let cases = [
+            (
+                "[mirakurun]\nbase_uri = \"localhost\"",
+                "mirakurun.base_uri",
+            ),
+            ("[meilisearch]\napi_key = \"\"", "meilisearch.api_key"),
+            (
+                "[recording]\ntsreadex_path = \"/nonexistent/tsreadex\"",
+                "recording.tsreadex_path",
+            ),
+            (
+                "[recording]\npre_roll_minutes = -1",
+                "recording.pre_roll_minutes",
+            ),
+            (
+                "[recording]\nunknown_duration_limit_minutes = 0",
+                "recording.unknown_duration_limit_minutes",
+            ),
+            (
+                "[[recording.profiles]]\nname = \"\"\nstages = [{ kind = \"file\" }]",
+                "recording.profiles.name",
+            ),
+            (
+                "[[recording.profiles]]\nname = \"a\"\nstages = [{ kind = \"file\" }]\n\
+                 [[recording.profiles]]\nname = \"a\"\nstages = [{ kind = \"null\" }]",
+                "recording.profiles.name",
+            ),
+            (
+                "[[recording.profiles]]\nname = \"a\"\nstages = []",
+                "recording.profiles.stages",
+            ),
+            ("[naming]\ntemplate = \"{nothing}\"", "naming.template"),
+            ("[naming]\nmax_bytes = 31", "naming.max_bytes"),
+            (
+                "[post_process]\nmax_concurrent_jobs = 0",
+                "post_process.max_concurrent_jobs",
+            ),
+            (
+                "[[post_process.steps]]\nkind = \"move\"\nto = \"/mnt\"\ntimeout_secs = 0",
+                "post_process.steps.timeout_secs",
+            ),
+            (
+                "[[post_process.steps]]\nkind = \"move\"\nto = \"\"",
+                "post_process.steps.to",
+            ),
+            (
+                "[[post_process.steps]]\nkind = \"command\"\nprogram = \"/nonexistent/encode.sh\"",
+                "post_process.steps.program",
+            ),
+            (
+                "[[post_process.steps]]\nkind = \"notify\"\nurl = \"example.com\"",
+                "post_process.steps.url",
+            ),
+            (
+                "[[storage.volumes]]\nname = \"\"\npath = \"/mnt\"",
+                "storage.volumes",
+            ),
+            (
+                "[[storage.volumes]]\nname = \"a\"\npath = \"/mnt/a\"\n\
+                 [[storage.volumes]]\nname = \"a\"\npath = \"/mnt/b\"",
+                "storage.volumes.name",
+            ),
+            (
+                "[[storage.volumes]]\nname = \"a\"\npath = \"/mnt\"\nmax_gib = 0",
+                "storage.volumes.max_gib",
+            ),
+            (
+                "[[storage.volumes]]\nname = \"a\"\npath = \"/mnt\"\nallowed_plans = [\"anime\"]",
+                "storage.volumes.allowed_plans",
+            ),
+            ("[storage]\nstate_dir = \"\"", "storage.state_dir"),
+            (
+                "[storage.retention]\nkeep_per_series = 0",
+                "storage.retention.keep_per_series",
+            ),
+            (
+                "[storage.retention]\nmax_age_days = 0",
+                "storage.retention.max_age_days",
+            ),
+            (
+                "[storage.retention]\nquota_gib = 0",
+                "storage.retention.quota_gib",
+            ),
+            (
+                "[storage.retention]\ninterval_secs = 0",
+                "storage.retention.interval_secs",
+            ),
+            ("[hls]\nsegment_secs = 0", "hls.segment_secs"),
+            ("[hls]\nlive_segments = 2", "hls.live_segments"),
+            ("[hls]\ncache_dir = \"\"", "hls.cache_dir"),
+            ("[hls]\ncache_max_gib = 0", "hls.cache_max_gib"),
+            ("[timers]\nepg_refresh_secs = 0", "timers.epg_refresh_secs"),
+            (
+                "[timers]\nscheduler_scan_secs = 0",
+                "timers.scheduler_scan_secs",
+            ),
+        ];
+        for (toml, key) in cases {
+            assert_eq!(invalid_key(toml), Some(key), "{}", toml);
+        }
+
+        let token = |name: &str, sha256: &str| {
+            format!(
+                "[[api.tokens]]\nname = \"{}\"\nsha256 = \"{}\"\nscope = \"read\"\n",
+                name, sha256
+            )
+        };
+        assert_eq!(invalid_key(&token("a", &sha256)), None);
+        assert_eq!(invalid_key(&token("a", "0123")), Some("api.tokens.sha256"));
+        assert_eq!(
+            invalid_key(&(token("a", &sha256) + &token("a", &sha256))),
+            Some("api.tokens.name")
+        );
+    }
+
+    #[test]
+    fn checksum_must_be_right_before_file() {
+        let service = || StageConfig::Service {
+            service_id: None,
+            drop_pids: default_dropped_pids(),
+        };
+        assert!(check_stages(&[service(), StageConfig::Checksum, StageConfig::File]).is_ok());
+        assert!(check_stages(&[StageConfig::Checksum, service(), StageConfig::File]).is_err());
+        assert!(check_stages(&[StageConfig::Checksum, StageConfig::Null]).is_err());
+    }
+}
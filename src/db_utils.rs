@@ -1,17 +1,21 @@
 use std::time::Duration;
 
+use meilisearch_sdk::documents::DocumentsQuery;
 use meilisearch_sdk::errors::Error;
 use meilisearch_sdk::indexes::Index;
 use meilisearch_sdk::tasks::Task;
 use meilisearch_sdk::Client;
 use mirakurun_client::models::{Program, Service};
-use structopt::StructOpt;
+use ulid::Ulid;
 
-use crate::Opt;
+use crate::config::get_config;
+use crate::recording_planner::series::SeriesPlan;
+use crate::recording_planner::word::WordRule;
+use crate::recording_pool::library::Recording;
 
 pub fn get_temporary_accessor() -> Client {
-    let args = Opt::from_args();
-    Client::new(args.meilisearch_base_uri, "masterKey")
+    let config = get_config();
+    Client::new(&config.meilisearch.base_uri, &config.meilisearch.api_key)
 }
 
 pub async fn push_programs_ranges(index: &Index, data: &[Program]) -> Result<Task, Error> {
@@ -63,3 +67,125 @@ pub async fn get_all_services(client: &Client) -> Result<Vec<Service>, Error> {
         .await
         .and_then(|f| Ok(f.results))
 }
+
+pub async fn push_rules(client: &Client, data: &[WordRule]) -> Result<Task, Error> {
+    client
+        .index("_rules")
+        .add_or_update(data, Some("id"))
+        .await?
+        .wait_for_completion(client, None, Some(Duration::from_secs(10)))
+        .await
+}
+
+pub async fn get_all_rules(client: &Client) -> Result<Vec<WordRule>, Error> {
+    client
+        .get_index("_rules")
+        .await?
+        .get_documents()
+        .await
+        .and_then(|f| Ok(f.results))
+}
+
+pub async fn delete_rule(client: &Client, id: &Ulid) -> Result<Task, Error> {
+    client
+        .index("_rules")
+        .delete_document(id.to_string())
+        .await?
+        .wait_for_completion(client, None, Some(Duration::from_secs(10)))
+        .await
+}
+
+pub async fn push_series_plans(client: &Client, data: &[SeriesPlan]) -> Result<Task, Error> {
+    client
+        .index("_series")
+        .add_or_update(data, Some("id"))
+        .await?
+        .wait_for_completion(client, None, Some(Duration::from_secs(10)))
+        .await
+}
+
+pub async fn get_all_series_plans(client: &Client) -> Result<Vec<SeriesPlan>, Error> {
+    client
+        .get_index("_series")
+        .await?
+        .get_documents()
+        .await
+        .and_then(|f| Ok(f.results))
+}
+
+pub async fn delete_series_plan(client: &Client, id: &Ulid) -> Result<Task, Error> {
+    client
+        .index("_series")
+        .delete_document(id.to_string())
+        .await?
+        .wait_for_completion(client, None, Some(Duration::from_secs(10)))
+        .await
+}
+
+pub async fn push_recordings(client: &Client, data: &[Recording]) -> Result<Task, Error> {
+    client
+        .index("_recordings")
+        .add_or_update(data, Some("id"))
+        .await?
+        .wait_for_completion(client, None, Some(Duration::from_secs(10)))
+        .await
+}
+
+pub async fn get_all_recordings(client: &Client) -> Result<Vec<Recording>, Error> {
+    // Fetched page by page, since the library grows beyond the default limit.
+    const PAGE: usize = 1000;
+    let index = client.get_index("_recordings").await?;
+    let mut all = Vec::new();
+    loop {
+        let page = DocumentsQuery::new(&index)
+            .with_offset(all.len())
+            .with_limit(PAGE)
+            .execute::<Recording>()
+            .await?
+            .results;
+        let n = page.len();
+        all.extend(page);
+        if n < PAGE {
+            return Ok(all);
+        }
+    }
+}
+
+pub async fn pull_recording(client: &Client, id: &Ulid) -> Result<Recording, Error> {
+    client
+        .get_index("_recordings")
+        .await?
+        .get_document(&*id.to_string())
+        .await
+}
+
+pub async fn search_recordings(
+    client: &Client,
+    query: &str,
+    offset: usize,
+    limit: usize,
+) -> Result<Vec<Recording>, Error> {
+    let index = client.get_index("_recordings").await?;
+    let mut search = index.search();
+    search
+        .with_query(query)
+        .with_offset(offset)
+        .with_limit(limit);
+    // The newest first if no query is given. Otherwise by relevance.
+    if query.is_empty() {
+        search.with_sort(&["started_at:desc"]);
+    }
+    search
+        .execute::<Recording>()
+        .await
+        .map(|f| f.hits.into_iter().map(|hit| hit.result).collect())
+}
+
+pub async fn delete_recording(client: &Client, id: &Ulid) -> Result<Task, Error> {
+    client
+        .index("_recordings")
+        .delete_document(id.to_string())
+        .await?
+        .wait_for_completion(client, None, Some(Duration::from_secs(10)))
+        .await
+}
@@ -8,27 +8,79 @@ use meilisearch_sdk::errors::Error;
 use meilisearch_sdk::indexes::Index;
 use mirakurun_client::apis::configuration::Configuration;
 use mirakurun_client::models::event::EventContent::{Program, Service, Tuner};
-use structopt::StructOpt;
 use tokio::sync::Mutex;
 use tokio_stream::StreamExt;
 
+use crate::config::get_config;
 use crate::db_utils::{push_programs_ranges, push_services_ranges};
-use crate::{Opt, SchedQueue};
+use crate::events::{publish, Event};
+use crate::recording_pool::{EVENT_RELAY, REC_POOL};
+use crate::sched_trigger::conflict::TUNER_MODEL;
+use crate::SchedQueue;
 
 mod events_stream;
 mod periodic_tasks;
 
 pub(crate) async fn epg_sync_startup(sched_ptr: Arc<Mutex<SchedQueue>>) {
-    let args = Opt::from_args();
+    let config = get_config();
     EpgSyncManager::new(
-        args.mirakurun_base_uri,
-        args.meilisearch_base_uri,
+        &config.mirakurun.base_uri,
+        &config.meilisearch.base_uri,
+        &config.meilisearch.api_key,
         Some(sched_ptr),
     )
     .await
     .unwrap();
 }
 
+// The settings are replaced as a whole, so the attributes are added to the ones which are already set.
+// Nothing is written if they are all there, which saves reindexing on every start.
+async fn add_filterable_attributes(
+    client: &Client,
+    index: &Index,
+    attributes: &[&str],
+) -> Result<(), Error> {
+    let current = index.get_filterable_attributes().await?;
+    if let Some(merged) = merge_attributes(current, attributes) {
+        index
+            .set_filterable_attributes(&merged)
+            .await?
+            .wait_for_completion(client, None, None)
+            .await?;
+    }
+    Ok(())
+}
+
+async fn add_sortable_attributes(
+    client: &Client,
+    index: &Index,
+    attributes: &[&str],
+) -> Result<(), Error> {
+    let current = index.get_sortable_attributes().await?;
+    if let Some(merged) = merge_attributes(current, attributes) {
+        index
+            .set_sortable_attributes(&merged)
+            .await?
+            .wait_for_completion(client, None, None)
+            .await?;
+    }
+    Ok(())
+}
+
+// None if `current` has every one of `attributes`
+fn merge_attributes(mut current: Vec<String>, attributes: &[&str]) -> Option<Vec<String>> {
+    let missing = attributes
+        .iter()
+        .filter(|a| !current.iter().any(|c| c == *a))
+        .map(|a| a.to_string())
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        return None;
+    }
+    current.extend(missing);
+    Some(current)
+}
+
 ///
 pub(crate) struct EpgSyncManager {
     m_conf: Configuration,
@@ -42,6 +94,7 @@ impl EpgSyncManager {
     pub(crate) async fn new<S: Into<String> + Sized, T: Into<String> + Sized>(
         m_url: S,
         db_url: T,
+        db_key: &str,
         sched_ptr: Option<Arc<Mutex<SchedQueue>>>,
     ) -> Result<(), Error> {
         // Initialize Mirakurun
@@ -49,7 +102,7 @@ impl EpgSyncManager {
         m_conf.base_path = m_url.into();
 
         // Initialize Meilisearch
-        let search_client = Client::new(db_url, "masterKey");
+        let search_client = Client::new(db_url, db_key);
 
         // Try to get the inner index if the task succeeded
         let index_programs = match search_client.get_index("_programs").await {
@@ -68,6 +121,25 @@ impl EpgSyncManager {
                 task.try_make_index(&search_client).unwrap()
             }
         };
+        // Plans and recordings are only read through the client, but the indexes have to exist.
+        for uid in ["_rules", "_series", "_recordings"] {
+            if search_client.get_index(uid).await.is_err() {
+                let task = search_client.create_index(uid, Some("id")).await?;
+                task.wait_for_completion(&search_client, None, None).await?;
+            }
+        }
+        // Series plans look up their episodes, and the episodes recorded in the library, by filter.
+        add_filterable_attributes(&search_client, &index_programs, &["series.id", "networkId"])
+            .await?;
+        let index_recordings = search_client.index("_recordings");
+        add_filterable_attributes(
+            &search_client,
+            &index_recordings,
+            &["program.id", "program.series.id", "program.networkId"],
+        )
+        .await?;
+        // The library is listed from the newest.
+        add_sortable_attributes(&search_client, &index_recordings, &["started_at"]).await?;
 
         let tracker = Self {
             m_conf,
@@ -78,7 +150,7 @@ impl EpgSyncManager {
         };
 
         let periodic = async {
-            let sec = 600;
+            let sec = get_config().timers.epg_refresh_secs;
             info!("Periodic EPG update is running every {} seconds.", sec);
             loop {
                 tracker.refresh_db().await.expect("TODO: panic message");
@@ -118,6 +190,10 @@ impl EpgSyncManager {
                     match serde_json::from_str(&next_str) {
                         Ok(Service(value)) => {
                             info!("Updating the service: {:#?}", value);
+                            TUNER_MODEL
+                                .write()
+                                .unwrap()
+                                .update_services(std::slice::from_ref(&value));
                             match push_services_ranges(&tracker.index_services, &vec![value]).await
                             {
                                 Ok(_) => info!("Updates have been successfully applied."),
@@ -137,23 +213,45 @@ impl EpgSyncManager {
                                     info!("Updates have been successfully applied.");
                                     // Update schedules
                                     if let Some(sched_ptr) = &tracker.sched_ptr {
-                                        sched_ptr.lock().await.items.iter_mut().for_each(
-                                            |mut f| {
-                                                if value.id == f.program.id {
-                                                    //TODO: Print details
-                                                    f.program.start_at = value.start_at;
-                                                    f.program.duration = value.duration;
+                                        let mut q_schedules = sched_ptr.lock().await;
+                                        q_schedules.items.iter_mut().for_each(|mut f| {
+                                            if value.id == f.program.id {
+                                                //TODO: Print details
+                                                let changed = f.program.start_at != value.start_at
+                                                    || f.program.duration != value.duration;
+                                                f.program.start_at = value.start_at;
+                                                f.program.duration = value.duration;
+                                                if changed {
+                                                    publish(Event::ScheduleUpdated {
+                                                        schedule: f.clone(),
+                                                    });
                                                 }
-                                            },
-                                        );
+                                            }
+                                        });
+                                        q_schedules.save();
                                     }
+                                    // Relays are often announced on air.
+                                    let relay = EpgSyncManager::get_event_relay(
+                                        std::slice::from_ref(&value),
+                                    );
+                                    EVENT_RELAY.write().unwrap().extend(relay);
+                                    // Running tasks follow it as well. (e.g. The duration determined on air)
+                                    let mut pool = REC_POOL.write().unwrap();
+                                    pool.iter_mut()
+                                        .filter(|f| f.program.id == value.id)
+                                        .for_each(|f| {
+                                            f.program.start_at = value.start_at;
+                                            f.program.duration = value.duration;
+                                        });
+                                    pool.save();
                                 }
                                 Err(e) => error!("{}", e),
                             }
                             continue;
                         }
                         Ok(Tuner(value)) => {
-                            info!("Tuner configuration has been changed. {:?}", value)
+                            info!("Tuner configuration has been changed. {:?}", value);
+                            tracker.refresh_tuner_model().await;
                         }
                         Err(e) => {
                             error!("In /events, {}", e);
@@ -173,3 +271,22 @@ impl EpgSyncManager {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attributes_are_added_to_current_ones() {
+        let current = vec!["genre".to_string(), "networkId".to_string()];
+        assert_eq!(
+            merge_attributes(current.clone(), &["series.id", "networkId"]),
+            Some(vec![
+                "genre".to_string(),
+                "networkId".to_string(),
+                "series.id".to_string()
+            ])
+        );
+        assert_eq!(merge_attributes(current, &["networkId"]), None);
+    }
+}
@@ -1,15 +1,24 @@
 use std::collections::HashMap;
 
-use log::info;
+use log::{error, info};
 use meilisearch_sdk::errors::Error;
 use mirakurun_client::models::related_item::Type;
 use mirakurun_client::models::Program;
 
-use crate::db_utils::{push_programs_ranges, push_services_ranges};
+use crate::db_utils::{
+    get_all_rules, get_all_series_plans, push_programs_ranges, push_series_plans,
+    push_services_ranges,
+};
 use crate::epg_syncer::EpgSyncManager;
+use crate::events::{publish, Event};
 use crate::mirakurun_client::{
-    fetch_programmes, fetch_services, ProgramsReturnType, ServicesReturnType,
+    fetch_programmes, fetch_services, fetch_tuners, get_program_id, ProgramsReturnType,
+    ServicesReturnType,
 };
+use crate::recording_planner::series::collect_series_schedules;
+use crate::recording_planner::word::collect_word_schedules;
+use crate::recording_pool::EVENT_RELAY;
+use crate::sched_trigger::conflict::TUNER_MODEL;
 
 impl EpgSyncManager {
     async fn fetch_epg(&self) -> (ServicesReturnType, ProgramsReturnType) {
@@ -17,13 +26,20 @@ impl EpgSyncManager {
         let s = fetch_services(&self.m_conf).await;
         (s, p)
     }
-    async fn get_reverse_event_relay(p: &Vec<Program>) -> HashMap<i32, i32> {
+    /// Program id -> the program id the event is relayed to.
+    pub(crate) fn get_event_relay(p: &[Program]) -> HashMap<i64, i64> {
         let mut table = HashMap::new();
         for elem in p {
             if let Some(ref rels) = elem.related_items {
                 for r in rels {
-                    if let Some(Type::Relay) = r.r#type {
-                        table.insert(r.event_id.unwrap(), elem.event_id);
+                    if let (Some(Type::Relay), Some(event_id)) = (r.r#type, r.event_id) {
+                        // Network and service are omitted if they are the same as the origin.
+                        let network_id = r.network_id.unwrap_or(elem.network_id);
+                        let service_id = r.service_id.unwrap_or(elem.service_id);
+                        table.insert(
+                            elem.id,
+                            get_program_id(network_id as i64, service_id as i64, event_id as i64),
+                        );
                     }
                 }
             }
@@ -34,14 +50,113 @@ impl EpgSyncManager {
         // Periodically updates the list of currently available channels, future programs.
         // This is triggered every 10 minutes.
         let initial_epg = self.fetch_epg().await;
+        if let Ok(ref services) = initial_epg.0 {
+            TUNER_MODEL.write().unwrap().update_services(services);
+        }
+        self.refresh_tuner_model().await;
+        let programs = initial_epg.1.unwrap();
+        *EVENT_RELAY.write().unwrap() = Self::get_event_relay(&programs);
         info!(
             "{:?}",
-            push_programs_ranges(&self.index_programs, &initial_epg.1.unwrap()).await?
+            push_programs_ranges(&self.index_programs, &programs).await?
         );
         info!(
             "{:?}",
             push_services_ranges(&self.index_programs, &initial_epg.0.unwrap()).await?
         );
+
+        // Failures in rules must not stop the periodic EPG update.
+        if let Err(e) = self.apply_word_rules().await {
+            error!("Failed to evaluate auto-recording rules. {}", e)
+        }
+        if let Err(e) = self.apply_series_plans().await {
+            error!("Failed to evaluate series plans. {}", e)
+        }
+        Ok(())
+    }
+    pub(crate) async fn refresh_tuner_model(&self) {
+        match fetch_tuners(&self.m_conf).await {
+            Ok(tuners) => TUNER_MODEL.write().unwrap().update_tuners(&tuners),
+            Err(e) => error!("Failed to fetch tuners. {}", e),
+        }
+    }
+    async fn apply_word_rules(&self) -> Result<(), Error> {
+        let sched_ptr = match &self.sched_ptr {
+            Some(sched_ptr) => sched_ptr,
+            None => return Ok(()),
+        };
+        let rules = get_all_rules(&self.search_client).await?;
+        let found = collect_word_schedules(&self.index_programs, &rules).await?;
+
+        let mut q_schedules = sched_ptr.lock().await;
+        for s in found {
+            if q_schedules
+                .items
+                .iter()
+                .all(|f| f.program.id != s.program.id)
+            {
+                info!(
+                    "Program {:?} (id={}) is added to sched_trigger by a rule.",
+                    &s.program.name, &s.program.id
+                );
+                publish(Event::ScheduleAdded {
+                    schedule: s.clone(),
+                });
+                q_schedules.items.push(s);
+            }
+        }
+        q_schedules.save();
+        Ok(())
+    }
+    async fn apply_series_plans(&self) -> Result<(), Error> {
+        let sched_ptr = match &self.sched_ptr {
+            Some(sched_ptr) => sched_ptr,
+            None => return Ok(()),
+        };
+        let mut plans = get_all_series_plans(&self.search_client).await?;
+        if plans.iter().all(|p| p.is_retired) {
+            return Ok(());
+        }
+        let was_active = plans
+            .iter()
+            .filter(|p| !p.is_retired)
+            .map(|p| p.id)
+            .collect::<Vec<_>>();
+        let current = sched_ptr.lock().await.items.clone();
+        let index_recordings = self.search_client.index("_recordings");
+        let found = collect_series_schedules(
+            &self.index_programs,
+            &index_recordings,
+            &mut plans,
+            &current,
+        )
+        .await?;
+        push_series_plans(&self.search_client, &plans).await?;
+        for plan in plans
+            .iter()
+            .filter(|p| p.is_retired && was_active.contains(&p.id))
+        {
+            info!("Series plan {} ({:?}) is retired.", &plan.id, &plan.name);
+        }
+
+        let mut q_schedules = sched_ptr.lock().await;
+        for s in found {
+            if q_schedules
+                .items
+                .iter()
+                .all(|f| f.program.id != s.program.id)
+            {
+                info!(
+                    "Program {:?} (id={}) is added to sched_trigger by a series plan.",
+                    &s.program.name, &s.program.id
+                );
+                publish(Event::ScheduleAdded {
+                    schedule: s.clone(),
+                });
+                q_schedules.items.push(s);
+            }
+        }
+        q_schedules.save();
         Ok(())
     }
 }
@@ -0,0 +1,71 @@
+//! Internal bus of the events pushed to clients through `/api/events`.
+//! Publishing never blocks. Subscribers which fall behind miss the oldest events.
+
+use std::path::PathBuf;
+
+use once_cell::sync::Lazy;
+use serde_derive::Serialize;
+use tokio::sync::broadcast;
+use utoipa::ToSchema;
+
+use crate::recording_pool::recording_task::RecordingState;
+use crate::sched_trigger::Schedule;
+
+// Events kept for each subscriber until it receives them
+const CAPACITY: usize = 256;
+
+static BUS: Lazy<broadcast::Sender<Event>> = Lazy::new(|| broadcast::channel(CAPACITY).0);
+
+#[derive(Clone, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Event {
+    ScheduleAdded {
+        schedule: Schedule,
+    },
+    // e.g. Rescheduled by EIT, or deactivated by the conflict resolver
+    ScheduleUpdated {
+        schedule: Schedule,
+    },
+    ScheduleRemoved {
+        program_id: i64,
+        // "expired" or "deleted"
+        reason: String,
+    },
+    RecordingState {
+        program_id: i64,
+        #[schema(value_type = Object)]
+        from: RecordingState,
+        #[schema(value_type = Object)]
+        to: RecordingState,
+    },
+    // The recording continues in another file, e.g. `.m2ts-tmp` -> `.m2ts`
+    FileRotated {
+        program_id: i64,
+        #[schema(value_type = String)]
+        file: PathBuf,
+    },
+    Error {
+        program_id: Option<i64>,
+        message: String,
+    },
+    // The volume has no room for new recordings.
+    DiskWarning {
+        volume: String,
+        #[schema(value_type = String)]
+        path: PathBuf,
+        room_bytes: u64,
+    },
+    // Sent only to the subscriber which has fallen behind. It should fetch the current state again.
+    Lagged {
+        missed: u64,
+    },
+}
+
+pub(crate) fn publish(event: Event) {
+    // Fails only if nobody is listening.
+    let _ = BUS.send(event);
+}
+
+pub(crate) fn subscribe() -> broadcast::Receiver<Event> {
+    BUS.subscribe()
+}
@@ -0,0 +1,170 @@
+//! Segments recordings in progress from the live fan-out into memory.
+//! Started by the first request of the playlist, and stopped when the recording ends or nobody is watching.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::config::get_config;
+use crate::hls::segmenter::{Segment, Segmenter};
+use crate::hls::{render, Entry};
+use crate::recording_pool::live;
+
+// Segments which have left the playlist are kept for players still downloading them.
+const KEPT_AFTER_PLAYLIST: usize = 2;
+// Stopped if neither the playlist nor the segments have been requested for this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// The ended playlist is kept for this long, so that players see EXT-X-ENDLIST.
+const LINGER: Duration = Duration::from_secs(30);
+
+struct Window {
+    segments: VecDeque<(Entry, Bytes)>,
+    next_seq: u64,
+    // Number of EXT-X-DISCONTINUITY which have been dropped from `segments`
+    dropped_discontinuities: u64,
+    ended: bool,
+    last_access: Instant,
+}
+
+// For each program id
+static WINDOWS: Lazy<RwLock<HashMap<i64, Window>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Starts segmenting unless it is in progress. Returns false if the program is not being recorded.
+pub(crate) fn open(id: i64) -> bool {
+    let mut windows = WINDOWS.write().unwrap();
+    if windows.contains_key(&id) {
+        return true;
+    }
+    let rx = match live::subscribe(id) {
+        Some(rx) => rx,
+        None => return false,
+    };
+    windows.insert(
+        id,
+        Window {
+            segments: VecDeque::new(),
+            next_seq: 0,
+            dropped_discontinuities: 0,
+            ended: false,
+            last_access: Instant::now(),
+        },
+    );
+    tokio::spawn(run(id, rx));
+    info!("id: {} HLS has started.", id);
+    true
+}
+
+/// The latest `hls.live_segments` segments. None if it is not open.
+pub(crate) fn playlist(id: i64) -> Option<String> {
+    let mut windows = WINDOWS.write().unwrap();
+    let window = windows.get_mut(&id)?;
+    window.last_access = Instant::now();
+
+    let config = &get_config().hls;
+    let skipped = window.segments.len().saturating_sub(config.live_segments);
+    let discontinuity_seq = window.dropped_discontinuities
+        + window
+            .segments
+            .iter()
+            .take(skipped)
+            .filter(|(e, _)| e.discontinuity)
+            .count() as u64;
+    let entries = window
+        .segments
+        .iter()
+        .skip(skipped)
+        .map(|(e, _)| *e)
+        .collect::<Vec<_>>();
+    Some(render(
+        &entries,
+        config.segment_secs,
+        discontinuity_seq,
+        false,
+        window.ended,
+    ))
+}
+
+pub(crate) fn segment(id: i64, seq: u64) -> Option<Bytes> {
+    let mut windows = WINDOWS.write().unwrap();
+    let window = windows.get_mut(&id)?;
+    window.last_access = Instant::now();
+    window
+        .segments
+        .iter()
+        .find(|(e, _)| e.seq == seq)
+        .map(|(_, data)| data.clone())
+}
+
+async fn run(id: i64, mut rx: broadcast::Receiver<Bytes>) {
+    let mut segmenter = Segmenter::new(get_config().hls.segment_secs as f64);
+    let mut discontinuity = false;
+    loop {
+        let segments = match rx.recv().await {
+            Ok(chunk) => segmenter.push(&chunk),
+            Err(RecvError::Lagged(missed)) => {
+                warn!("id: {} HLS has missed {} chunks.", id, missed);
+                segmenter.discard();
+                discontinuity = true;
+                continue;
+            }
+            // The recording has ended.
+            Err(RecvError::Closed) => break,
+        };
+        for segment in segments {
+            append(id, segment, discontinuity);
+            discontinuity = false;
+        }
+
+        let idle = WINDOWS
+            .read()
+            .unwrap()
+            .get(&id)
+            .map_or(true, |w| w.last_access.elapsed() > IDLE_TIMEOUT);
+        if idle {
+            WINDOWS.write().unwrap().remove(&id);
+            info!("id: {} HLS has stopped as nobody is watching.", id);
+            return;
+        }
+    }
+
+    if let Some(segment) = segmenter.finish() {
+        append(id, segment, discontinuity);
+    }
+    if let Some(window) = WINDOWS.write().unwrap().get_mut(&id) {
+        window.ended = true;
+    }
+    info!("id: {} HLS has ended.", id);
+    tokio::time::sleep(LINGER).await;
+    WINDOWS.write().unwrap().remove(&id);
+}
+
+fn append(id: i64, segment: Segment, discontinuity: bool) {
+    let kept = get_config().hls.live_segments + KEPT_AFTER_PLAYLIST;
+    let mut windows = WINDOWS.write().unwrap();
+    let window = match windows.get_mut(&id) {
+        Some(window) => window,
+        None => return,
+    };
+    let entry = Entry {
+        seq: window.next_seq,
+        duration: segment.duration,
+        discontinuity,
+    };
+    window.next_seq += 1;
+    window
+        .segments
+        .push_back((entry, Bytes::from(segment.data)));
+    while window.segments.len() > kept {
+        if let Some((e, _)) = window.segments.pop_front() {
+            if e.discontinuity {
+                window.dropped_discontinuities += 1;
+            }
+        }
+    }
+}
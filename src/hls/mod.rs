@@ -0,0 +1,98 @@
+//! HLS of the library items and of the recordings in progress.
+//! Library items are segmented into `hls.cache_dir` on the first request. Recordings in progress are segmented
+//! from the live fan-out into memory, and only the latest segments are kept.
+
+use std::fmt::Write;
+
+pub(crate) mod live;
+mod segmenter;
+pub(crate) mod vod;
+
+pub(crate) const PLAYLIST: &str = "index.m3u8";
+
+// A segment listed in the playlist
+#[derive(Clone, Copy)]
+struct Entry {
+    seq: u64,
+    duration: f64,
+    // Timestamps don't continue from the previous segment.
+    discontinuity: bool,
+}
+
+/// A file name in the URL of HLS
+pub(crate) enum Name {
+    Playlist,
+    Segment(u64),
+}
+
+impl Name {
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        if name == PLAYLIST {
+            return Some(Name::Playlist);
+        }
+        name.strip_suffix(".ts")?.parse().ok().map(Name::Segment)
+    }
+}
+
+pub(crate) fn segment_name(seq: u64) -> String {
+    format!("{}.ts", seq)
+}
+
+// `discontinuity_seq` counts EXT-X-DISCONTINUITY which have left the playlist.
+// `event` is for playlists which only grow, and `ended` appends EXT-X-ENDLIST.
+fn render(
+    entries: &[Entry],
+    target_secs: u64,
+    discontinuity_seq: u64,
+    event: bool,
+    ended: bool,
+) -> String {
+    // Every segment must fit in EXT-X-TARGETDURATION after rounding.
+    let target = entries
+        .iter()
+        .map(|e| e.duration.round() as u64)
+        .fold(target_secs, u64::max);
+    let mut m3u8 = String::new();
+    let _ = writeln!(m3u8, "#EXTM3U");
+    let _ = writeln!(m3u8, "#EXT-X-VERSION:3");
+    let _ = writeln!(m3u8, "#EXT-X-TARGETDURATION:{}", target);
+    let _ = writeln!(
+        m3u8,
+        "#EXT-X-MEDIA-SEQUENCE:{}",
+        entries.first().map_or(0, |e| e.seq)
+    );
+    if discontinuity_seq > 0 {
+        let _ = writeln!(m3u8, "#EXT-X-DISCONTINUITY-SEQUENCE:{}", discontinuity_seq);
+    }
+    if event {
+        let _ = writeln!(m3u8, "#EXT-X-PLAYLIST-TYPE:EVENT");
+    }
+    for entry in entries {
+        if entry.discontinuity {
+            let _ = writeln!(m3u8, "#EXT-X-DISCONTINUITY");
+        }
+        let _ = writeln!(m3u8, "#EXTINF:{:.3},", entry.duration);
+        let _ = writeln!(m3u8, "{}", segment_name(entry.seq));
+    }
+    if ended {
+        let _ = writeln!(m3u8, "#EXT-X-ENDLIST");
+    }
+    m3u8
+}
+
+/// Appends the query of the playlist request to the URIs of the segments, so that `access_token` is passed on.
+pub(crate) fn with_query(m3u8: &str, query: Option<&str>) -> String {
+    let query = match query {
+        Some(query) if !query.is_empty() => query,
+        _ => return m3u8.to_string(),
+    };
+    m3u8.lines()
+        .map(|line| {
+            if line.starts_with('#') || line.is_empty() {
+                format!("{}\n", line)
+            } else {
+                format!("{}?{}\n", line, query)
+            }
+        })
+        .collect()
+}
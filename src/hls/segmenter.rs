@@ -0,0 +1,408 @@
+//! Splits MPEG-TS into segments of HLS. Each segment starts at a keyframe with the latest PAT and PMT in front,
+//! so that it can be decoded on its own.
+
+use crate::ts::{
+    payload_of, payload_unit_start, pid_of, Packets, PAT_PID, TABLE_ID_PAT, TABLE_ID_PMT,
+    TS_PACKET_SIZE,
+};
+
+// PAT and PMT of a single service fit in a few packets.
+const MAX_PSI_PACKETS: usize = 4;
+const PTS_HZ: f64 = 90_000.0;
+// PTS is 33 bits long and wraps around.
+const PTS_MASK: u64 = (1 << 33) - 1;
+
+pub(crate) struct Segment {
+    pub(crate) data: Vec<u8>,
+    // In seconds, measured with PTS
+    pub(crate) duration: f64,
+}
+
+// The elementary stream which segments are cut at
+#[derive(Clone, Copy)]
+struct KeyStream {
+    pid: u16,
+    stream_type: u8,
+}
+
+pub(crate) struct Segmenter {
+    packets: Packets,
+    cutter: Cutter,
+}
+
+struct Cutter {
+    target_secs: f64,
+    // Latest PAT and PMT as they have been received
+    pat: Vec<u8>,
+    pmt: Vec<u8>,
+    pmt_pid: Option<u16>,
+    key: Option<KeyStream>,
+    // The segment being filled. Packets before the first keyframe are dropped.
+    buf: Vec<u8>,
+    start_pts: Option<u64>,
+    last_pts: Option<u64>,
+    done: Vec<Segment>,
+}
+
+impl Segmenter {
+    pub(crate) fn new(target_secs: f64) -> Self {
+        Segmenter {
+            packets: Packets::new(),
+            cutter: Cutter {
+                target_secs,
+                pat: Vec::new(),
+                pmt: Vec::new(),
+                pmt_pid: None,
+                key: None,
+                buf: Vec::new(),
+                start_pts: None,
+                last_pts: None,
+                done: Vec::new(),
+            },
+        }
+    }
+
+    /// Returns the segments completed by `buf`.
+    pub(crate) fn push(&mut self, buf: &[u8]) -> Vec<Segment> {
+        let cutter = &mut self.cutter;
+        self.packets
+            .push(buf, |packet, _| cutter.feed_packet(packet));
+        std::mem::take(&mut cutter.done)
+    }
+
+    /// Returns the last segment, which is shorter than the target.
+    pub(crate) fn finish(&mut self) -> Option<Segment> {
+        let cutter = &mut self.cutter;
+        let start = cutter.start_pts.take()?;
+        let duration = cutter.duration_since(start, cutter.last_pts.unwrap_or(start));
+        self.packets.clear();
+        Some(Segment {
+            data: std::mem::take(&mut cutter.buf),
+            duration,
+        })
+    }
+
+    /// Throws away the segment being filled, e.g. when some of the input has been lost.
+    /// The next segment starts at the next keyframe.
+    pub(crate) fn discard(&mut self) {
+        self.cutter.buf.clear();
+        self.cutter.start_pts = None;
+        self.packets.clear();
+    }
+}
+
+impl Cutter {
+    fn feed_packet(&mut self, packet: &[u8]) {
+        let pid = pid_of(packet);
+        let payload_unit_start = payload_unit_start(packet);
+        // transport_error_indicator. Such packets are passed through without being parsed.
+        let payload = match packet[1] & 0x80 {
+            0 => payload_of(packet),
+            _ => None,
+        };
+
+        if pid == PAT_PID {
+            collect_psi(&mut self.pat, packet, payload_unit_start);
+            if let Some(pmt_pid) = payload.filter(|_| payload_unit_start).and_then(parse_pat) {
+                if self.pmt_pid != Some(pmt_pid) {
+                    self.pmt_pid = Some(pmt_pid);
+                    self.pmt.clear();
+                    self.key = None;
+                }
+            }
+        } else if Some(pid) == self.pmt_pid {
+            collect_psi(&mut self.pmt, packet, payload_unit_start);
+            if let Some(key) = payload.filter(|_| payload_unit_start).and_then(parse_pmt) {
+                self.key = Some(key);
+            }
+        } else if let Some(key) = self.key.filter(|k| k.pid == pid && payload_unit_start) {
+            if let Some(payload) = payload {
+                self.on_pes_start(packet, payload, key);
+            }
+        }
+
+        if self.start_pts.is_some() {
+            self.buf.extend_from_slice(packet);
+        }
+    }
+
+    fn on_pes_start(&mut self, packet: &[u8], payload: &[u8], key: KeyStream) {
+        let (pts, es) = match parse_pes_header(payload) {
+            Some(parsed) => parsed,
+            None => return,
+        };
+        let pts = match pts {
+            Some(pts) => pts,
+            None => return,
+        };
+        self.last_pts = Some(pts);
+
+        // Without video, any access unit of the audio can start a segment.
+        let is_boundary = !is_video(key.stream_type)
+            || random_access_indicator(packet)
+            || starts_keyframe(key.stream_type, es);
+        if !is_boundary {
+            return;
+        }
+        match self.start_pts {
+            None => self.start_segment(pts),
+            Some(start) => {
+                let duration = self.duration_since(start, pts);
+                if duration >= self.target_secs {
+                    let data = std::mem::take(&mut self.buf);
+                    self.done.push(Segment { data, duration });
+                    self.start_segment(pts);
+                }
+            }
+        }
+    }
+
+    fn start_segment(&mut self, pts: u64) {
+        self.buf.clear();
+        self.buf.extend_from_slice(&self.pat);
+        self.buf.extend_from_slice(&self.pmt);
+        self.start_pts = Some(pts);
+    }
+
+    fn duration_since(&self, start: u64, pts: u64) -> f64 {
+        let secs = (pts.wrapping_sub(start) & PTS_MASK) as f64 / PTS_HZ;
+        // PTS has jumped, e.g. backwards at a splice of the broadcast.
+        if secs > self.target_secs * 4.0 {
+            self.target_secs
+        } else {
+            secs
+        }
+    }
+}
+
+fn random_access_indicator(packet: &[u8]) -> bool {
+    let adaptation_field_control = (packet[3] >> 4) & 0x03;
+    adaptation_field_control & 0x02 != 0 && packet[4] > 0 && packet[5] & 0x40 != 0
+}
+
+// Keeps the packets of the latest section, starting from the one with payload_unit_start_indicator.
+fn collect_psi(psi: &mut Vec<u8>, packet: &[u8], payload_unit_start: bool) {
+    if payload_unit_start {
+        psi.clear();
+    } else if psi.is_empty() || psi.len() >= TS_PACKET_SIZE * MAX_PSI_PACKETS {
+        return;
+    }
+    psi.extend_from_slice(packet);
+}
+
+// Returns the section in the payload of a packet with payload_unit_start_indicator, without CRC_32.
+// Sections spanning several packets are cut at the end of the packet.
+fn section_of(payload: &[u8], table_id: u8) -> Option<&[u8]> {
+    let pointer = *payload.first()? as usize;
+    let section = payload.get(1 + pointer..)?;
+    if section.len() < 3 || section[0] != table_id {
+        return None;
+    }
+    let section_length = ((section[1] as usize & 0x0f) << 8) | section[2] as usize;
+    let end = (3 + section_length).saturating_sub(4).min(section.len());
+    section.get(..end)
+}
+
+// PID of the PMT of the first service
+fn parse_pat(payload: &[u8]) -> Option<u16> {
+    let section = section_of(payload, TABLE_ID_PAT)?;
+    section.get(8..)?.chunks_exact(4).find_map(|p| {
+        let program_number = ((p[0] as u16) << 8) | p[1] as u16;
+        // 0 is for the NIT.
+        (program_number != 0).then_some(((p[2] as u16 & 0x1f) << 8) | p[3] as u16)
+    })
+}
+
+// The first video stream, or the first audio stream if there is no video
+fn parse_pmt(payload: &[u8]) -> Option<KeyStream> {
+    let section = section_of(payload, TABLE_ID_PMT)?;
+    let program_info_length =
+        ((*section.get(10)? as usize & 0x0f) << 8) | *section.get(11)? as usize;
+    let mut rest = section.get(12 + program_info_length..)?;
+    let mut audio = None;
+    while rest.len() >= 5 {
+        let stream_type = rest[0];
+        let pid = ((rest[1] as u16 & 0x1f) << 8) | rest[2] as u16;
+        let es_info_length = ((rest[3] as usize & 0x0f) << 8) | rest[4] as usize;
+        if is_video(stream_type) {
+            return Some(KeyStream { pid, stream_type });
+        }
+        if audio.is_none() && is_audio(stream_type) {
+            audio = Some(KeyStream { pid, stream_type });
+        }
+        rest = rest.get(5 + es_info_length..).unwrap_or_default();
+    }
+    audio
+}
+
+fn is_video(stream_type: u8) -> bool {
+    // MPEG-1, MPEG-2, H.264, H.265
+    matches!(stream_type, 0x01 | 0x02 | 0x1b | 0x24)
+}
+
+fn is_audio(stream_type: u8) -> bool {
+    // MPEG-1, MPEG-2, AAC (ADTS, LATM)
+    matches!(stream_type, 0x03 | 0x04 | 0x0f | 0x11)
+}
+
+// (PTS, the beginning of the elementary stream)
+fn parse_pes_header(payload: &[u8]) -> Option<(Option<u64>, &[u8])> {
+    if payload.len() < 9 || payload[..3] != [0x00, 0x00, 0x01] {
+        return None;
+    }
+    let pts_dts_flags = payload[7] >> 6;
+    let es = payload.get(9 + payload[8] as usize..).unwrap_or_default();
+    if pts_dts_flags & 0x02 == 0 || payload.len() < 14 {
+        return Some((None, es));
+    }
+    let p = &payload[9..14];
+    let pts = (((p[0] as u64 >> 1) & 0x07) << 30)
+        | ((p[1] as u64) << 22)
+        | ((p[2] as u64 >> 1) << 15)
+        | ((p[3] as u64) << 7)
+        | (p[4] as u64 >> 1);
+    Some((Some(pts), es))
+}
+
+// Looks for the start of a random access point in the first packet of the PES.
+// Broadcasts don't always set random_access_indicator.
+fn starts_keyframe(stream_type: u8, es: &[u8]) -> bool {
+    es.windows(4).any(|w| {
+        w[..3] == [0x00, 0x00, 0x01]
+            && match stream_type {
+                // sequence_header
+                0x01 | 0x02 => w[3] == 0xb3,
+                // IDR, SPS
+                0x1b => matches!(w[3] & 0x1f, 5 | 7),
+                // IRAP, VPS, SPS
+                0x24 => matches!((w[3] >> 1) & 0x3f, 16..=21 | 32 | 33),
+                _ => false,
+            }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ts::testing::{packetize, pat, pids, pmt};
+    use crate::ts::TS_SYNC_BYTE;
+
+    const PMT_PID: u16 = 0x01f0;
+    const VIDEO_PID: u16 = 0x0111;
+
+    // The first packet of a PES starting with `es`
+    fn pes(pid: u16, cc: &mut u8, pts: u64, es: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0xff; TS_PACKET_SIZE];
+        packet[..4].copy_from_slice(&[
+            TS_SYNC_BYTE,
+            0x40 | (pid >> 8) as u8,
+            pid as u8,
+            0x10 | *cc,
+        ]);
+        *cc = (*cc + 1) & 0x0f;
+        let header = [
+            0x00,
+            0x00,
+            0x01,
+            0xe0,
+            0x00,
+            0x00,
+            // PTS only
+            0x80,
+            0x80,
+            0x05,
+            0x21 | ((pts >> 29) & 0x0e) as u8,
+            (pts >> 22) as u8,
+            ((pts >> 14) & 0xfe) as u8 | 0x01,
+            (pts >> 7) as u8,
+            ((pts << 1) & 0xfe) as u8 | 0x01,
+        ];
+        packet[4..4 + header.len()].copy_from_slice(&header);
+        packet[4 + header.len()..4 + header.len() + es.len()].copy_from_slice(es);
+        packet
+    }
+
+    // PAT and PMT of a service with the video, then a frame of the video for each (PTS, keyframe)
+    fn stream(stream_type: u8, frames: &[(u64, bool)]) -> Vec<u8> {
+        let mut stream = packetize(PAT_PID, &mut 0, &pat(&[(0x0400, PMT_PID)]));
+        stream.extend(packetize(
+            PMT_PID,
+            &mut 0,
+            &pmt(0x0400, VIDEO_PID, &[], &[(stream_type, VIDEO_PID, &[])]),
+        ));
+        let mut cc = 0;
+        for (pts, key) in frames {
+            let es: &[u8] = match (stream_type, key) {
+                // IDR and non-IDR slices
+                (0x1b, true) => &[0x00, 0x00, 0x00, 0x01, 0x65],
+                (0x1b, false) => &[0x00, 0x00, 0x00, 0x01, 0x41],
+                // sequence_header and picture_start_code
+                (_, true) => &[0x00, 0x00, 0x01, 0xb3],
+                (_, false) => &[0x00, 0x00, 0x01, 0x00],
+            };
+            stream.extend(pes(VIDEO_PID, &mut cc, *pts, es));
+        }
+        stream
+    }
+
+    fn durations(segments: &[Segment]) -> Vec<f64> {
+        segments.iter().map(|s| s.duration).collect()
+    }
+
+    #[test]
+    fn cuts_h264_at_keyframes() {
+        let frames = [
+            (0, true),
+            (45_000, false),
+            (90_000, false),
+            (135_000, true),
+            (180_000, false),
+        ];
+        let mut segmenter = Segmenter::new(1.0);
+        let segments = segmenter.push(&stream(0x1b, &frames));
+        // Not cut at 90_000, which isn't a keyframe
+        assert_eq!(durations(&segments), [1.5]);
+        assert_eq!(
+            pids(&segments[0].data),
+            [PAT_PID, PMT_PID, VIDEO_PID, VIDEO_PID, VIDEO_PID]
+        );
+        let last = segmenter.finish().unwrap();
+        assert_eq!(last.duration, 0.5);
+        assert_eq!(pids(&last.data), [PAT_PID, PMT_PID, VIDEO_PID, VIDEO_PID]);
+    }
+
+    #[test]
+    fn cuts_mpeg2_at_sequence_headers() {
+        let frames = [(0, false), (45_000, true), (90_000, false), (135_000, true)];
+        let mut segmenter = Segmenter::new(1.0);
+        // Frames before the first keyframe are dropped.
+        assert_eq!(durations(&segmenter.push(&stream(0x02, &frames))), [1.0]);
+    }
+
+    #[test]
+    fn measures_across_pts_wraparound() {
+        let frames = [(PTS_MASK - 44_999, true), (45_000, true)];
+        let mut segmenter = Segmenter::new(1.0);
+        assert_eq!(durations(&segmenter.push(&stream(0x1b, &frames))), [1.0]);
+    }
+
+    #[test]
+    fn joins_packets_split_across_pushes() {
+        let frames = [(0, true), (90_000, true), (180_000, true)];
+        let stream = stream(0x1b, &frames);
+        let mut whole = Segmenter::new(1.0);
+        let expected = whole.push(&stream);
+
+        let mut split = Segmenter::new(1.0);
+        let mut segments = Vec::new();
+        for chunk in stream.chunks(100) {
+            segments.extend(split.push(chunk));
+        }
+        assert_eq!(durations(&segments), [1.0, 1.0]);
+        assert_eq!(durations(&segments), durations(&expected));
+        assert!(segments
+            .iter()
+            .zip(&expected)
+            .all(|(a, b)| a.data == b.data));
+    }
+}
@@ -0,0 +1,221 @@
+//! Segments library items into `hls.cache_dir/{ulid}`. The playlist is rewritten after each segment,
+//! so that players can start before the whole recording is segmented.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
+use tokio::io::AsyncReadExt;
+use ulid::Ulid;
+
+use crate::config::get_config;
+use crate::hls::segmenter::Segmenter;
+use crate::hls::{render, segment_name, Entry, PLAYLIST};
+use crate::storage::volume::dir_size;
+
+const READ_SIZE: usize = 188 * 1024;
+const GIB: u64 = 1024 * 1024 * 1024;
+
+// Recordings being segmented
+static PACKAGING: Lazy<Mutex<HashSet<Ulid>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+// When each recording has been requested last. Ones not requested since the start are evicted first.
+static LAST_USED: Lazy<Mutex<HashMap<Ulid, Instant>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub(crate) fn dir_of(id: &Ulid) -> PathBuf {
+    get_config().hls.cache_dir.join(id.to_string())
+}
+
+/// Starts segmenting unless it is done or in progress, and returns the directory.
+/// The playlist exists in the directory when this returns.
+pub(crate) async fn prepare(id: Ulid, files: Vec<PathBuf>) -> Result<PathBuf, String> {
+    let dir = dir_of(&id);
+    LAST_USED.lock().unwrap().insert(id, Instant::now());
+    if PACKAGING.lock().unwrap().contains(&id) {
+        return Ok(dir);
+    }
+    if let Ok(m3u8) = tokio::fs::read_to_string(dir.join(PLAYLIST)).await {
+        if m3u8.contains("#EXT-X-ENDLIST") {
+            return Ok(dir);
+        }
+    }
+    if !PACKAGING.lock().unwrap().insert(id) {
+        return Ok(dir);
+    }
+
+    // Left over by segmenting which has been interrupted
+    let _ = tokio::fs::remove_dir_all(&dir).await;
+    let created = async {
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .map_err(|e| format!("Failed to create {}. {}", dir.display(), e))?;
+        write_playlist(&dir, &[], false).await
+    };
+    if let Err(e) = created.await {
+        PACKAGING.lock().unwrap().remove(&id);
+        return Err(e);
+    }
+
+    info!("{} Segmenting into {}", id, dir.display());
+    let dir1 = dir.clone();
+    tokio::spawn(async move {
+        match package(&dir1, &files).await {
+            Ok(()) => {
+                info!("{} Segmenting has finished.", id);
+                if let Some(gib) = get_config().hls.cache_max_gib {
+                    let cache_dir = get_config().hls.cache_dir.clone();
+                    let _ = tokio::task::spawn_blocking(move || evict(&cache_dir, gib * GIB, &id))
+                        .await;
+                }
+            }
+            Err(e) => {
+                error!("{} Failed to segment. {}", id, e);
+                // Tried again on the next request
+                let _ = tokio::fs::remove_dir_all(&dir1).await;
+            }
+        }
+        PACKAGING.lock().unwrap().remove(&id);
+    });
+    Ok(dir)
+}
+
+async fn package(dir: &Path, files: &[PathBuf]) -> Result<(), String> {
+    let target_secs = get_config().hls.segment_secs;
+    let mut entries = Vec::new();
+    let mut buf = vec![0u8; READ_SIZE];
+
+    for (i, path) in files.iter().enumerate() {
+        let mut file = tokio::fs::File::open(path)
+            .await
+            .map_err(|e| format!("Failed to open {}. {}", path.display(), e))?;
+        let mut segmenter = Segmenter::new(target_secs as f64);
+        // Parts are recorded separately, so that their timestamps don't continue.
+        let mut discontinuity = i > 0;
+        loop {
+            let n = file
+                .read(&mut buf)
+                .await
+                .map_err(|e| format!("Failed to read {}. {}", path.display(), e))?;
+            let segments = match n {
+                0 => segmenter.finish().into_iter().collect(),
+                _ => segmenter.push(&buf[..n]),
+            };
+            for segment in segments {
+                let seq = entries.len() as u64;
+                let segment_path = dir.join(segment_name(seq));
+                tokio::fs::write(&segment_path, &segment.data)
+                    .await
+                    .map_err(|e| format!("Failed to write {}. {}", segment_path.display(), e))?;
+                entries.push(Entry {
+                    seq,
+                    duration: segment.duration,
+                    discontinuity,
+                });
+                discontinuity = false;
+                write_playlist(dir, &entries, false).await?;
+            }
+            if n == 0 {
+                break;
+            }
+        }
+    }
+    if entries.is_empty() {
+        return Err("No keyframe is found.".to_string());
+    }
+    write_playlist(dir, &entries, true).await
+}
+
+// Replaced by renaming, so that readers never see a half-written playlist.
+async fn write_playlist(dir: &Path, entries: &[Entry], ended: bool) -> Result<(), String> {
+    let m3u8 = render(entries, get_config().hls.segment_secs, 0, true, ended);
+    let tmp = dir.join(format!("{}.tmp", PLAYLIST));
+    tokio::fs::write(&tmp, m3u8)
+        .await
+        .map_err(|e| format!("Failed to write {}. {}", tmp.display(), e))?;
+    tokio::fs::rename(&tmp, dir.join(PLAYLIST))
+        .await
+        .map_err(|e| format!("Failed to write {}. {}", tmp.display(), e))
+}
+
+// Removes the least recently requested recordings until `cache_dir` fits in `max_bytes`.
+// Recordings being segmented and `keep` are left. Blocks while walking the directory.
+fn evict(cache_dir: &Path, max_bytes: u64, keep: &Ulid) {
+    let entries = match std::fs::read_dir(cache_dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Failed to read {}. {}", cache_dir.display(), e);
+            return;
+        }
+    };
+    let last_used = LAST_USED.lock().unwrap().clone();
+    let mut dirs = entries
+        .filter_map(Result::ok)
+        .filter_map(|e| {
+            let id = e.file_name().to_str()?.parse::<Ulid>().ok()?;
+            Some((last_used.get(&id).copied(), id, e.path()))
+        })
+        .map(|(used_at, id, path)| (used_at, id, dir_size(&path), path))
+        .collect::<Vec<_>>();
+    let mut total = dirs.iter().map(|(_, _, size, _)| size).sum::<u64>();
+    // None comes first.
+    dirs.sort_by_key(|(used_at, ..)| *used_at);
+
+    for (_, id, size, path) in dirs {
+        if total <= max_bytes {
+            break;
+        }
+        if id == *keep || PACKAGING.lock().unwrap().contains(&id) {
+            continue;
+        }
+        match std::fs::remove_dir_all(&path) {
+            Ok(()) => {
+                info!("{} is evicted from the cache.", path.display());
+                LAST_USED.lock().unwrap().remove(&id);
+                total -= size;
+            }
+            Err(e) => warn!("Failed to remove {}. {}", path.display(), e),
+        }
+    }
+}
+
+/// Removes the segments of a recording which has been removed from the library.
+pub(crate) async fn forget(id: &Ulid) {
+    LAST_USED.lock().unwrap().remove(id);
+    let dir = dir_of(id);
+    match tokio::fs::remove_dir_all(&dir).await {
+        Ok(()) => info!("{} is removed.", dir.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => warn!("Failed to remove {}. {}", dir.display(), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_dir;
+
+    #[test]
+    fn evicts_least_recently_requested() {
+        let cache_dir = temp_dir("hls-cache");
+        let ids = [Ulid::new(), Ulid::new(), Ulid::new(), Ulid::new()];
+        for id in &ids {
+            let dir = cache_dir.join(id.to_string());
+            std::fs::create_dir(&dir).unwrap();
+            std::fs::write(dir.join(segment_name(0)), [0u8; 10]).unwrap();
+        }
+        // ids[0] hasn't been requested since the start.
+        for id in &ids[1..] {
+            LAST_USED.lock().unwrap().insert(*id, Instant::now());
+        }
+
+        evict(&cache_dir, 15, &ids[3]);
+        let left = ids.map(|id| cache_dir.join(id.to_string()).exists());
+        assert_eq!(left, [false, false, false, true]);
+
+        // Fits already
+        evict(&cache_dir, 10, &ids[3]);
+        assert!(cache_dir.join(ids[3].to_string()).exists());
+    }
+}
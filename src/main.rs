@@ -1,34 +1,76 @@
 #[macro_use]
 extern crate machine;
 
+use std::future::Future;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use structopt::StructOpt;
 use tokio::sync::Mutex;
 
+use crate::config::{Config, Scope};
+use crate::recording_pool::{pool, REC_POOL};
 use crate::sched_trigger::SchedQueue;
 use crate::{
     api::api_startup, epg_syncer::epg_sync_startup, recording_pool::recording_pool_startup,
-    sched_trigger::scheduler_startup,
+    sched_trigger::scheduler_startup, storage::storage_startup,
 };
 
 mod api;
+mod config;
 mod db_utils;
 mod epg_syncer;
+mod events;
+mod hls;
 mod mirakurun_client;
+mod post_process;
 mod recording_planner;
 mod recording_pool;
 mod sched_trigger;
+mod shutdown;
+mod storage;
+#[cfg(test)]
+mod test_utils;
+mod ts;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "meister", about = "An example of StructOpt usage.")]
 struct Opt {
-    #[structopt(default_value = "http://localhost:40772/api")]
-    mirakurun_base_uri: String,
-    #[structopt(default_value = "http://localhost:7700/")]
-    meilisearch_base_uri: String,
+    /// Overrides `mirakurun.base_uri` in the config (default: http://localhost:40772/api)
+    mirakurun_base_uri: Option<String>,
+    /// Overrides `meilisearch.base_uri` in the config (default: http://localhost:7700/)
+    meilisearch_base_uri: Option<String>,
     #[structopt(short)]
     meilisearch_api_key: Option<String>,
+    /// Hard limit (in minutes from the start) of recordings whose duration is unknown
+    #[structopt(long)]
+    unknown_duration_limit: Option<i64>,
+    /// TOML config file. ./config.toml is used if it exists.
+    #[structopt(short, long, parse(from_os_str))]
+    config: Option<PathBuf>,
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Manages the tokens of the API. See `api.auth` in the config.
+    Token(TokenCommand),
+}
+
+#[derive(Debug, StructOpt)]
+enum TokenCommand {
+    /// Creates a token and prints it. It cannot be shown again.
+    Mint {
+        name: String,
+        /// read or admin
+        #[structopt(long, default_value = "read")]
+        scope: Scope,
+    },
+    /// Invalidates the token. The server stops accepting it within a minute.
+    Revoke { name: String },
+    /// Lists the names and scopes of the tokens
+    List,
 }
 
 #[tokio::main]
@@ -37,11 +79,46 @@ async fn main() {
 
     env_logger::init();
 
+    let opt = Opt::from_args();
+    match Config::load(&opt) {
+        Ok(config) => config::init(config),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1)
+        }
+    }
+
+    if let Some(Command::Token(cmd)) = &opt.cmd {
+        if let Err(e) = api::auth::run_command(cmd) {
+            eprintln!("{}", e);
+            std::process::exit(1)
+        }
+        return;
+    }
+
     //Create Recording Queue Notifier
     let (rqn_tx, rqn_rx) = tokio::sync::mpsc::channel(100);
 
     //Deserialize
-    let q_schedules = Arc::new(Mutex::new(SchedQueue { items: vec![] }));
+    // Loaded before anything else starts, since every change is written through to the file.
+    // Files which cannot be read are left as they are, instead of being overwritten with empty queues.
+    let path = config::get_config()
+        .storage
+        .state_dir
+        .join("q_schedules.json");
+    let q_schedules = match SchedQueue::load(&path) {
+        Ok(q_schedules) => q_schedules,
+        Err(e) => {
+            eprintln!("Failed to read {}. {}", path.display(), e);
+            std::process::exit(1)
+        }
+    };
+    // Resume the recordings which were running when the process stopped
+    if let Err(e) = REC_POOL.write().unwrap().restore() {
+        eprintln!("Failed to restore the recording pool. {}", e);
+        std::process::exit(1)
+    }
+    let q_schedules = Arc::new(Mutex::new(q_schedules));
     //let rules;
 
     // Spawn epg_syncer
@@ -49,9 +126,97 @@ async fn main() {
         _ = epg_sync_startup(q_schedules.clone()) => {  },
         _ = scheduler_startup(q_schedules.clone(), rqn_tx.clone()) => {  },
         _ = recording_pool_startup(rqn_rx) => {  },
+        _ = storage_startup() => {  },
 
         _ = api_startup(q_schedules.clone()) => {  },
 
-        _ = tokio::signal::ctrl_c() => { println!("First signal: gracefully exitting...") }
+        _ = shutdown::wait_for_signal() => { println!("First signal: gracefully exitting...") }
+    }
+    // Leaving the select above stops the API, the scheduler and the EPG syncer, so no schedule is accepted anymore.
+
+    // A second signal exits without waiting for the recordings. They are resumed on the next start.
+    if !finish(finalize(q_schedules), shutdown::wait_for_signal()).await {
+        eprintln!("Second signal: exiting immediately.");
+        std::process::exit(1)
+    }
+    println!("Shutdown completed.");
+}
+
+// Returns false if the signal has come before the shutdown is completed.
+async fn finish(finalizing: impl Future<Output = ()>, signal: impl Future<Output = ()>) -> bool {
+    tokio::select! {
+        _ = finalizing => true,
+        _ = signal => false,
+    }
+}
+
+// Closes every recording, then saves the queues. The interrupted recordings are resumed on the next start.
+async fn finalize(q_schedules: Arc<Mutex<SchedQueue>>) {
+    shutdown::begin();
+
+    let handles = REC_POOL.write().unwrap().take_handles();
+    println!("Waiting for {} recording(s) to be closed...", handles.len());
+    for handle in handles {
+        if let Err(e) = handle.await {
+            eprintln!("{}", e);
+        }
+    }
+
+    persist(&q_schedules).await;
+}
+
+// Returns once the queues have been written to the files.
+async fn persist(q_schedules: &Mutex<SchedQueue>) {
+    let flush_schedules = {
+        let mut q_schedules = q_schedules.lock().await;
+        q_schedules.save();
+        q_schedules.flush()
+    };
+    REC_POOL.write().unwrap().save();
+    let flush = move || {
+        flush_schedules();
+        pool::flush();
+    };
+    if let Err(e) = tokio::task::spawn_blocking(flush).await {
+        eprintln!("{}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Local;
+
+    use super::*;
+    use crate::recording_planner::PlanId;
+    use crate::sched_trigger::Schedule;
+
+    #[tokio::test]
+    async fn second_signal_cuts_shutdown_short() {
+        let finished = finish(async {}, std::future::pending()).await;
+        assert!(finished);
+        let finished = finish(std::future::pending(), async {}).await;
+        assert!(!finished);
+    }
+
+    #[tokio::test]
+    async fn queues_are_written_before_exit() {
+        let config = test_utils::config();
+        let path = test_utils::temp_dir("main").join("q_schedules.json");
+        let q_schedules = Mutex::new(SchedQueue::load(&path).unwrap());
+        // Changed without saving, e.g. by a task which has been cut off by the shutdown
+        q_schedules.lock().await.items.push(Schedule {
+            program: test_utils::program((0x7fe0, 0x0400, 0x6101), Local::now(), None),
+            plan_id: PlanId::None,
+            is_active: true,
+            priority: 0,
+            inactive_reason: None,
+            plan_name: None,
+            profile: None,
+        });
+
+        persist(&q_schedules).await;
+
+        assert_eq!(SchedQueue::load(&path).unwrap().items.len(), 1);
+        assert!(config.storage.state_dir.join("q_recording.json").exists());
     }
 }
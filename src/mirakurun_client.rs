@@ -3,12 +3,14 @@ use mirakurun_client::apis::channels_api::GetChannelsError;
 use mirakurun_client::apis::configuration::Configuration;
 use mirakurun_client::apis::programs_api::{get_programs, GetProgramsError};
 use mirakurun_client::apis::services_api::{get_services, GetServicesError};
+use mirakurun_client::apis::tuners_api::{get_tuners, GetTunersError};
 use mirakurun_client::apis::Error;
-use mirakurun_client::models::{Channel, Program, Service};
+use mirakurun_client::models::{Channel, ChannelType, Program, Service, TunerDevice};
 
 pub type ChannelsReturnType = Result<Vec<Channel>, Error<GetChannelsError>>;
 pub type ServicesReturnType = Result<Vec<Service>, Error<GetServicesError>>;
 pub type ProgramsReturnType = Result<Vec<Program>, Error<GetProgramsError>>;
+pub type TunersReturnType = Result<Vec<TunerDevice>, Error<GetTunersError>>;
 
 pub(crate) async fn fetch_services(c: &Configuration) -> ServicesReturnType {
     get_services(c, None, None, None, None, None, None).await
@@ -18,6 +20,21 @@ pub(crate) async fn fetch_programmes(c: &Configuration) -> ProgramsReturnType {
     get_programs(c, None, None, None).await
 }
 
+pub(crate) async fn fetch_tuners(c: &Configuration) -> TunersReturnType {
+    get_tuners(c).await
+}
+
+/// Same as Program.id of Mirakurun.
+pub(crate) fn get_program_id(network_id: i64, service_id: i64, event_id: i64) -> i64 {
+    network_id * 100000 * 100000 + service_id * 100000 + event_id
+}
+
+/// Returns the type and the physical channel the service is broadcast on.
+pub(crate) fn get_channel_of_service(s: &Service) -> Option<(ChannelType, String)> {
+    let channel = s.channel.as_ref()?;
+    Some((channel.r#type?, channel.channel.clone()?))
+}
+
 pub(crate) async fn get_service_from_program(c: &Configuration, p: &Program) -> Option<Service> {
     let result = get_services(
         c,
@@ -0,0 +1,436 @@
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use chrono::{DateTime, Local};
+use log::{error, info, warn};
+use mirakurun_client::models::Program;
+use once_cell::sync::Lazy;
+use serde_derive::Serialize;
+use tokio::sync::Semaphore;
+use ulid::Ulid;
+use utoipa::ToSchema;
+
+use crate::config::{get_config, StepConfig};
+use crate::events::{publish, Event};
+use crate::post_process::step::run_step;
+use crate::recording_planner::PlanId;
+use crate::recording_pool::library;
+use crate::recording_pool::RecordingTaskDescription;
+
+mod step;
+
+pub(crate) static JOB_QUEUE: Lazy<RwLock<JobQueue>> =
+    Lazy::new(|| RwLock::new(JobQueue::default()));
+
+// Limits the number of running jobs. Jobs waiting for a permit stay Queued.
+static PERMITS: Lazy<Semaphore> =
+    Lazy::new(|| Semaphore::new(get_config().post_process.max_concurrent_jobs));
+
+// Finished jobs kept for the API. Older ones are forgotten.
+const HISTORY_LIMIT: usize = 100;
+#[cfg(not(test))]
+const RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+#[cfg(test)]
+const RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// Given to every step. Commands receive it in stdin, and notifications in the body.
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct JobMetadata {
+    // Entry in the library. None if it couldn't be stored.
+    #[schema(value_type = Option<String>)]
+    pub(crate) recording_id: Option<Ulid>,
+    // Current location of the recording. Updated by move steps.
+    #[schema(value_type = String)]
+    pub(crate) file: PathBuf,
+    // Other files of the recording, e.g. the temporary file of the pre-roll. Moved along with it.
+    #[schema(value_type = Vec<String>)]
+    pub(crate) sidecars: Vec<PathBuf>,
+    #[schema(value_type = Object)]
+    pub(crate) program: Program,
+    pub(crate) plan_id: PlanId,
+    pub(crate) plan_name: Option<String>,
+    pub(crate) relayed_from: Option<i64>,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct Job {
+    #[schema(value_type = String)]
+    pub(crate) id: Ulid,
+    pub(crate) queued_at: DateTime<Local>,
+    pub(crate) status: JobStatus,
+    pub(crate) steps: Vec<StepStatus>,
+    pub(crate) metadata: JobMetadata,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct StepStatus {
+    pub(crate) name: String,
+    pub(crate) state: StepState,
+    pub(crate) attempts: u32,
+    pub(crate) last_error: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum StepState {
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+    // Not run because an earlier step has failed
+    Skipped,
+}
+
+#[derive(Default)]
+pub(crate) struct JobQueue {
+    jobs: Vec<Job>,
+}
+
+impl JobQueue {
+    fn insert(&mut self, job: Job) {
+        self.jobs.push(job);
+        let finished = self
+            .jobs
+            .iter()
+            .filter(|j| matches!(j.status, JobStatus::Succeeded | JobStatus::Failed))
+            .count();
+        let mut excess = finished.saturating_sub(HISTORY_LIMIT);
+        // Jobs are ordered by queued time, so the oldest finished ones are dropped.
+        self.jobs.retain(|j| {
+            let drop = excess > 0 && matches!(j.status, JobStatus::Succeeded | JobStatus::Failed);
+            if drop {
+                excess -= 1;
+            }
+            !drop
+        });
+    }
+    fn update(&mut self, id: &Ulid, f: impl FnOnce(&mut Job)) {
+        if let Some(job) = self.jobs.iter_mut().find(|j| &j.id == id) {
+            f(job)
+        }
+    }
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Job> {
+        self.jobs.iter()
+    }
+    // Whether the files of the recording may be touched by a job
+    pub(crate) fn is_busy(&self, recording_id: &Ulid) -> bool {
+        self.jobs.iter().any(|j| {
+            matches!(j.status, JobStatus::Queued | JobStatus::Running)
+                && j.metadata.recording_id.as_ref() == Some(recording_id)
+        })
+    }
+}
+
+fn step_name(i: usize, step: &StepConfig) -> String {
+    step.name.clone().unwrap_or_else(|| format!("#{}", i + 1))
+}
+
+/// Queues the post-processing of a recording which has ended gracefully.
+/// Nothing is queued if no step is configured.
+pub(crate) fn enqueue(
+    info: &RecordingTaskDescription,
+    file: &Path,
+    sidecars: Vec<PathBuf>,
+    recording_id: Option<Ulid>,
+) {
+    let steps = &get_config().post_process.steps;
+    if steps.is_empty() {
+        return;
+    }
+
+    let metadata = JobMetadata {
+        recording_id,
+        file: file.to_path_buf(),
+        sidecars,
+        program: info.program.clone(),
+        plan_id: info.plan_id.clone(),
+        plan_name: info.plan_name.clone(),
+        relayed_from: info.relayed_from,
+    };
+    let id = insert_job(steps, metadata.clone());
+    info!(
+        "Post-processing job {} for id: {} is queued.",
+        id, info.program.id
+    );
+    tokio::spawn(run_job(id, steps, metadata));
+}
+
+fn insert_job(steps: &[StepConfig], metadata: JobMetadata) -> Ulid {
+    let job = Job {
+        id: Ulid::new(),
+        queued_at: Local::now(),
+        status: JobStatus::Queued,
+        steps: steps
+            .iter()
+            .enumerate()
+            .map(|(i, step)| StepStatus {
+                name: step_name(i, step),
+                state: StepState::Pending,
+                attempts: 0,
+                last_error: None,
+            })
+            .collect(),
+        metadata,
+    };
+    let id = job.id;
+    JOB_QUEUE.write().unwrap().insert(job);
+    id
+}
+
+async fn run_job(id: Ulid, steps: &[StepConfig], mut metadata: JobMetadata) {
+    let _permit = PERMITS
+        .acquire()
+        .await
+        .expect("The semaphore is never closed.");
+    let update = |f: &dyn Fn(&mut Job)| JOB_QUEUE.write().unwrap().update(&id, f);
+
+    update(&|job| job.status = JobStatus::Running);
+    info!("Post-processing job {} has started.", id);
+
+    for (i, step) in steps.iter().enumerate() {
+        let before = metadata.clone();
+        let mut attempts = 0;
+        let result = loop {
+            attempts += 1;
+            update(&|job| {
+                job.steps[i].state = StepState::Running;
+                job.steps[i].attempts = attempts;
+            });
+
+            let result = match tokio::time::timeout(
+                std::time::Duration::from_secs(step.timeout_secs),
+                run_step(&step.action, &mut metadata),
+            )
+            .await
+            {
+                Ok(result) => result,
+                // Dropping the step kills the command, if any.
+                Err(_) => Err(format!("Timed out after {} secs.", step.timeout_secs)),
+            };
+
+            match result {
+                Ok(()) => break Ok(()),
+                Err(e) if attempts <= step.retries => {
+                    warn!(
+                        "Post-processing job {} step {} failed. Retrying... {}",
+                        id,
+                        step_name(i, step),
+                        e
+                    );
+                    update(&|job| job.steps[i].last_error = Some(e.clone()));
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                }
+                Err(e) => break Err(e),
+            }
+        };
+
+        match result {
+            Ok(()) => {
+                // The files may have been moved.
+                let moves: Vec<_> = std::iter::once((&before.file, &metadata.file))
+                    .chain(before.sidecars.iter().zip(&metadata.sidecars))
+                    .filter(|(from, to)| from != to)
+                    .map(|(from, to)| (from.clone(), to.clone()))
+                    .collect();
+                if let (Some(recording_id), false) = (&metadata.recording_id, moves.is_empty()) {
+                    if let Err(e) = library::relocate(recording_id, &moves).await {
+                        warn!("Failed to update the library entry {}. {}", recording_id, e);
+                    }
+                }
+                update(&|job| {
+                    job.steps[i].state = StepState::Succeeded;
+                    job.metadata = metadata.clone();
+                });
+            }
+            Err(e) => {
+                let message = format!(
+                    "Post-processing job {} step {} failed. {}",
+                    id,
+                    step_name(i, step),
+                    e
+                );
+                error!("{}", message);
+                publish(Event::Error {
+                    program_id: Some(metadata.program.id),
+                    message,
+                });
+                update(&|job| {
+                    job.steps[i].state = StepState::Failed;
+                    job.steps[i].last_error = Some(e.clone());
+                    job.steps[i + 1..]
+                        .iter_mut()
+                        .for_each(|s| s.state = StepState::Skipped);
+                    job.status = JobStatus::Failed;
+                });
+                return;
+            }
+        }
+    }
+
+    update(&|job| job.status = JobStatus::Succeeded);
+    info!("Post-processing job {} has succeeded.", id);
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::config::StepAction;
+    use crate::test_utils;
+
+    fn metadata(event_id: u16, file: PathBuf) -> JobMetadata {
+        let start_at = Local.with_ymd_and_hms(2030, 1, 2, 21, 0, 0).unwrap();
+        JobMetadata {
+            recording_id: None,
+            file,
+            sidecars: Vec::new(),
+            program: test_utils::program(
+                (0x7fe0, 0x0400, event_id),
+                start_at,
+                Some(30 * 60 * 1000),
+            ),
+            plan_id: PlanId::None,
+            plan_name: Some("plan".to_string()),
+            relayed_from: None,
+        }
+    }
+
+    // Runs the script by sh, with the directory as $0.
+    fn script(script: &str, dir: &Path, timeout_secs: u64, retries: u32) -> StepConfig {
+        StepConfig {
+            name: None,
+            action: StepAction::Command {
+                program: PathBuf::from("sh"),
+                args: vec![
+                    "-c".to_string(),
+                    script.to_string(),
+                    dir.to_string_lossy().into_owned(),
+                ],
+            },
+            timeout_secs,
+            retries,
+        }
+    }
+
+    async fn run(steps: &[StepConfig], metadata: JobMetadata) -> Job {
+        test_utils::config();
+        let id = insert_job(steps, metadata.clone());
+        run_job(id, steps, metadata).await;
+        let queue = JOB_QUEUE.read().unwrap();
+        queue.iter().find(|j| j.id == id).cloned().unwrap()
+    }
+
+    #[tokio::test]
+    async fn failed_step_is_retried_until_exhausted() {
+        let dir = test_utils::temp_dir("post-process");
+        let steps = [
+            script(r#"echo x >> "$0/attempts"; exit 1"#, &dir, 10, 2),
+            script(r#"touch "$0/next""#, &dir, 10, 0),
+        ];
+        let job = run(&steps, metadata(0x5001, dir.join("a.m2ts"))).await;
+
+        assert_eq!(job.status, JobStatus::Failed);
+        assert_eq!(job.steps[0].state, StepState::Failed);
+        assert_eq!(job.steps[0].attempts, 3);
+        assert!(job.steps[0]
+            .last_error
+            .as_ref()
+            .unwrap()
+            .contains("exited with"));
+        assert_eq!(
+            std::fs::read_to_string(dir.join("attempts"))
+                .unwrap()
+                .lines()
+                .count(),
+            3
+        );
+        assert_eq!(job.steps[1].state, StepState::Skipped);
+        assert_eq!(job.steps[1].attempts, 0);
+        assert!(!dir.join("next").exists());
+    }
+
+    #[tokio::test]
+    async fn succeeds_on_retry() {
+        let dir = test_utils::temp_dir("post-process");
+        // Fails only at the first attempt
+        let steps = [script(
+            r#"[ -e "$0/failed" ] || { touch "$0/failed"; exit 1; }"#,
+            &dir,
+            10,
+            1,
+        )];
+        let job = run(&steps, metadata(0x5002, dir.join("a.m2ts"))).await;
+
+        assert_eq!(job.status, JobStatus::Succeeded);
+        assert_eq!(job.steps[0].state, StepState::Succeeded);
+        assert_eq!(job.steps[0].attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn command_is_killed_on_timeout() {
+        let dir = test_utils::temp_dir("post-process");
+        let steps = [script(r#"sleep 2; touch "$0/survived""#, &dir, 1, 0)];
+        let job = run(&steps, metadata(0x5003, dir.join("a.m2ts"))).await;
+
+        assert_eq!(job.status, JobStatus::Failed);
+        assert_eq!(
+            job.steps[0].last_error.as_deref(),
+            Some("Timed out after 1 secs.")
+        );
+        // The shell would have touched the file if it was left running.
+        tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+        assert!(!dir.join("survived").exists());
+    }
+
+    #[tokio::test]
+    async fn command_is_given_metadata_in_env_and_stdin() {
+        let dir = test_utils::temp_dir("post-process");
+        let steps = [script(
+            r#"env | grep ^REC_ > "$0/env"; cat > "$0/stdin.json""#,
+            &dir,
+            10,
+            0,
+        )];
+        let metadata = metadata(0x5004, dir.join("a.m2ts"));
+        let job = run(&steps, metadata.clone()).await;
+        assert_eq!(job.status, JobStatus::Succeeded);
+
+        let env = std::fs::read_to_string(dir.join("env")).unwrap();
+        let env: Vec<&str> = env.lines().collect();
+        let p = &metadata.program;
+        let start_at: DateTime<Local> = p.start_at.into();
+        for expected in [
+            format!("REC_FILE={}", dir.join("a.m2ts").display()),
+            format!("REC_PROGRAM_ID={}", p.id),
+            "REC_SERVICE_ID=1024".to_string(),
+            "REC_NETWORK_ID=32736".to_string(),
+            "REC_EVENT_ID=20484".to_string(),
+            "REC_TITLE=テスト番組".to_string(),
+            format!("REC_START_AT={}", start_at.to_rfc3339()),
+            "REC_DURATION_MS=1800000".to_string(),
+            "REC_PLAN_NAME=plan".to_string(),
+        ] {
+            assert!(
+                env.contains(&expected.as_str()),
+                "{} in {:?}",
+                expected,
+                env
+            );
+        }
+
+        let stdin: Value =
+            serde_json::from_slice(&std::fs::read(dir.join("stdin.json")).unwrap()).unwrap();
+        assert_eq!(stdin, serde_json::to_value(&metadata).unwrap());
+    }
+}
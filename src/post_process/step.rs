@@ -0,0 +1,210 @@
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use chrono::{DateTime, Local};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::config::StepAction;
+use crate::post_process::JobMetadata;
+use crate::recording_pool::naming;
+
+pub(super) async fn run_step(
+    action: &StepAction,
+    metadata: &mut JobMetadata,
+) -> Result<(), String> {
+    match action {
+        StepAction::Command { program, args } => run_command(program, args, metadata).await,
+        StepAction::Move { to } => {
+            let (file, sidecars) = move_files(&metadata.file, &metadata.sidecars, to)
+                .await
+                .map_err(|e| e.to_string())?;
+            metadata.file = file;
+            metadata.sidecars = sidecars;
+            Ok(())
+        }
+        StepAction::Notify { url } => notify(url, metadata).await.map_err(|e| e.to_string()),
+    }
+}
+
+fn env_of(metadata: &JobMetadata) -> Vec<(&'static str, String)> {
+    let p = &metadata.program;
+    let start_at: DateTime<Local> = p.start_at.into();
+    vec![
+        ("REC_FILE", metadata.file.to_string_lossy().into_owned()),
+        ("REC_PROGRAM_ID", p.id.to_string()),
+        ("REC_SERVICE_ID", p.service_id.to_string()),
+        ("REC_NETWORK_ID", p.network_id.to_string()),
+        ("REC_EVENT_ID", p.event_id.to_string()),
+        ("REC_TITLE", p.name.clone().unwrap_or_default()),
+        ("REC_START_AT", start_at.to_rfc3339()),
+        (
+            "REC_DURATION_MS",
+            p.duration.map(|d| d.to_string()).unwrap_or_default(),
+        ),
+        (
+            "REC_PLAN_NAME",
+            metadata.plan_name.clone().unwrap_or_default(),
+        ),
+    ]
+}
+
+async fn run_command(
+    program: &Path,
+    args: &[String],
+    metadata: &JobMetadata,
+) -> Result<(), String> {
+    let json = serde_json::to_vec(metadata).map_err(|e| e.to_string())?;
+    let mut child = Command::new(program)
+        .args(args)
+        .envs(env_of(metadata))
+        .stdin(Stdio::piped())
+        // The step is dropped on timeout.
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("Failed to spawn {}. {}", program.display(), e))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // Commands which don't read stdin may exit before it is written, which is not an error.
+        let _ = stdin.write_all(&json).await;
+    }
+    let status = child.wait().await.map_err(|e| e.to_string())?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("{} exited with {}.", program.display(), status))
+    }
+}
+
+// Returns the new locations of the file and the sidecars.
+// The file is renamed like the recordings if the name is already taken, and the sidecars named after it
+// (e.g. "X.m2ts-tmp" of "X.m2ts") follow the new name. Nothing is moved if a sidecar would overwrite a file.
+async fn move_files(
+    file: &Path,
+    sidecars: &[PathBuf],
+    to_dir: &Path,
+) -> std::io::Result<(PathBuf, Vec<PathBuf>)> {
+    tokio::fs::create_dir_all(to_dir).await?;
+    let stem = file.file_stem().map(PathBuf::from).unwrap_or_default();
+    let dest = match file.extension() {
+        Some(ext) => naming::with_extension(
+            &naming::resolve_collision(to_dir, &stem)?,
+            &ext.to_string_lossy(),
+        ),
+        None => naming::resolve_collision(to_dir, &stem)?,
+    };
+
+    let prefix = format!("{}.", stem.to_string_lossy());
+    let new_stem = dest.file_stem().unwrap_or_default().to_string_lossy();
+    let mut moves = vec![(file.to_path_buf(), dest.clone())];
+    let mut new_sidecars = Vec::new();
+    for sidecar in sidecars {
+        // Removed by an earlier step
+        if tokio::fs::metadata(sidecar).await.is_err() {
+            new_sidecars.push(sidecar.clone());
+            continue;
+        }
+        let name = sidecar.file_name().unwrap_or_default().to_string_lossy();
+        let to = match name.strip_prefix(&prefix) {
+            Some(rest) => to_dir.join(format!("{}.{}", new_stem, rest)),
+            None => to_dir.join(name.as_ref()),
+        };
+        if tokio::fs::metadata(&to).await.is_ok() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} already exists.", to.display()),
+            ));
+        }
+        moves.push((sidecar.clone(), to.clone()));
+        new_sidecars.push(to);
+    }
+
+    for (from, to) in &moves {
+        // rename() fails across file systems.
+        if tokio::fs::rename(from, to).await.is_err() {
+            tokio::fs::copy(from, to).await?;
+            tokio::fs::remove_file(from).await?;
+        }
+    }
+    Ok((dest, new_sidecars))
+}
+
+async fn notify(url: &str, metadata: &JobMetadata) -> Result<(), reqwest::Error> {
+    reqwest::Client::new()
+        .post(url)
+        .json(metadata)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    fn touch(path: &Path) {
+        std::fs::write(path, path.to_string_lossy().as_bytes()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sidecars_follow_renamed_file() {
+        let (from, to) = (test_utils::temp_dir("move"), test_utils::temp_dir("move"));
+        let sidecars = vec![
+            from.join("X.m2ts-tmp"),
+            from.join("X.m2ts.sha256"),
+            from.join("log.txt"),
+            // Removed by an earlier step
+            from.join("X.ts"),
+        ];
+        touch(&from.join("X.m2ts"));
+        sidecars[..3].iter().for_each(|s| touch(s));
+        touch(&to.join("X.m2ts"));
+
+        let (file, moved) = move_files(&from.join("X.m2ts"), &sidecars, &to)
+            .await
+            .unwrap();
+
+        assert_eq!(file, to.join("X (2).m2ts"));
+        assert_eq!(
+            moved,
+            vec![
+                to.join("X (2).m2ts-tmp"),
+                to.join("X (2).m2ts.sha256"),
+                to.join("log.txt"),
+                from.join("X.ts"),
+            ]
+        );
+        for (before, after) in sidecars[..3].iter().zip(&moved) {
+            assert!(!before.exists());
+            assert_eq!(
+                std::fs::read_to_string(after).unwrap(),
+                before.to_string_lossy()
+            );
+        }
+        // The file which has taken the name is left as it is.
+        assert_eq!(
+            std::fs::read_to_string(to.join("X.m2ts")).unwrap(),
+            to.join("X.m2ts").to_string_lossy()
+        );
+    }
+
+    #[tokio::test]
+    async fn nothing_is_moved_if_sidecar_would_overwrite() {
+        let (from, to) = (test_utils::temp_dir("move"), test_utils::temp_dir("move"));
+        touch(&from.join("X.m2ts"));
+        touch(&from.join("log.txt"));
+        touch(&to.join("log.txt"));
+
+        let result = move_files(&from.join("X.m2ts"), &[from.join("log.txt")], &to).await;
+
+        assert_eq!(
+            result.unwrap_err().kind(),
+            std::io::ErrorKind::AlreadyExists
+        );
+        assert!(from.join("X.m2ts").exists());
+        assert!(from.join("log.txt").exists());
+        assert!(!to.join("X.m2ts").exists());
+    }
+}
@@ -1,9 +1,14 @@
 use serde_derive::{Deserialize, Serialize};
 use ulid::Ulid;
+use utoipa::ToSchema;
 
-#[derive(Clone, Serialize, Deserialize)]
+pub(crate) mod series;
+pub(crate) mod word;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, ToSchema)]
 pub(crate) enum PlanId {
-    Word(Ulid),
-    Series(Ulid),
+    Word(#[schema(value_type = String)] Ulid),
+    Series(#[schema(value_type = String)] Ulid),
+    #[default]
     None,
 }
@@ -0,0 +1,362 @@
+use chrono::Local;
+use meilisearch_sdk::errors::Error;
+use meilisearch_sdk::indexes::Index;
+use mirakurun_client::models::Program;
+use serde_derive::{Deserialize, Serialize};
+use ulid::Ulid;
+use utoipa::ToSchema;
+
+use crate::recording_planner::PlanId;
+use crate::recording_pool::library::Recording;
+use crate::sched_trigger::Schedule;
+
+// Upper bound of the episodes fetched from _programs for a plan at once.
+const SEARCH_LIMIT: usize = 1000;
+
+/// A plan to record every episode of a series, created from one of its programs.
+/// Plans are stored in the `_series` index.
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub(crate) struct SeriesPlan {
+    #[schema(value_type = String)]
+    pub(crate) id: Ulid,
+    // ARIB series_id is unique within a network.
+    pub(crate) network_id: i64,
+    pub(crate) series_id: i64,
+    pub(crate) name: Option<String>,
+    pub(crate) last_episode: Option<i32>,
+    // Milliseconds since the epoch, as Mirakurun reports it.
+    pub(crate) expires_at: Option<i64>,
+    // Episodes found recorded in the library. Rebroadcasts of them are skipped, even after the recordings are deleted.
+    #[serde(default)]
+    pub(crate) recorded_episodes: Vec<i32>,
+    // The last episode has gone on air, whether it has been recorded or not.
+    #[serde(default)]
+    pub(crate) finale_aired: bool,
+    #[serde(default)]
+    pub(crate) is_retired: bool,
+    // Given to the schedules created by the plan.
+    #[serde(default)]
+    pub(crate) priority: i32,
+    // Output profile of the schedules created by the plan. None means the default one.
+    #[serde(default)]
+    pub(crate) profile: Option<String>,
+}
+
+impl SeriesPlan {
+    /// Returns None if the program doesn't belong to any series.
+    pub(crate) fn from_program(p: &Program) -> Option<Self> {
+        let series = p.series.as_ref()?;
+        Some(Self {
+            id: Ulid::new(),
+            network_id: p.network_id as i64,
+            series_id: series.id? as i64,
+            name: series.name.clone(),
+            last_episode: series.last_episode.filter(|n| *n > 0),
+            expires_at: series.expires_at,
+            recorded_episodes: vec![],
+            finale_aired: false,
+            is_retired: false,
+            priority: 0,
+            profile: None,
+        })
+    }
+
+    pub(crate) fn plan_id(&self) -> PlanId {
+        PlanId::Series(self.id)
+    }
+
+    fn filter(&self) -> String {
+        format!(
+            "series.id = {} AND networkId = {}",
+            self.series_id, self.network_id
+        )
+    }
+
+    // The same series in the snapshots of the programs in `_recordings`
+    fn library_filter(&self) -> String {
+        format!(
+            "program.series.id = {} AND program.networkId = {}",
+            self.series_id, self.network_id
+        )
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires_at
+            .map_or(false, |t| t < Local::now().timestamp_millis())
+    }
+}
+
+fn episode_of(p: &Program) -> Option<i32> {
+    p.series.as_ref().and_then(|s| s.episode).filter(|n| *n > 0)
+}
+
+/// Follows the series of each plan in `_programs`, and returns the schedules for the future episodes.
+/// `plans` is updated in place with recorded episodes and retirement, so the caller should store it back.
+/// `current` is a snapshot of SchedQueue used to find episodes which have already been scheduled.
+/// Recorded episodes are looked up in the library, `index_recordings`.
+pub(crate) async fn collect_series_schedules(
+    index_programs: &Index,
+    index_recordings: &Index,
+    plans: &mut [SeriesPlan],
+    current: &[Schedule],
+) -> Result<Vec<Schedule>, Error> {
+    let mut found: Vec<Schedule> = Vec::new();
+
+    for plan in plans.iter_mut().filter(|p| !p.is_retired) {
+        let plan_id = plan.plan_id();
+        let owned = current
+            .iter()
+            .filter(|s| s.plan_id == plan_id)
+            .collect::<Vec<_>>();
+
+        // Only recordings which have lasted to the end count, so that failed ones are recorded again on a rebroadcast.
+        // Episodes on air are skipped as scheduled below until they reach the library.
+        let recorded = index_recordings
+            .search()
+            .with_filter(&plan.library_filter())
+            .with_limit(SEARCH_LIMIT)
+            .execute::<Recording>()
+            .await?
+            .hits;
+        for r in recorded
+            .iter()
+            .map(|hit| &hit.result)
+            .filter(|r| r.state.is_graceful_end())
+        {
+            if let Some(ep) = episode_of(&r.program) {
+                if !plan.recorded_episodes.contains(&ep) {
+                    plan.recorded_episodes.push(ep);
+                }
+            }
+        }
+
+        let hits = index_programs
+            .search()
+            .with_filter(&plan.filter())
+            .with_limit(SEARCH_LIMIT)
+            .execute::<Program>()
+            .await?
+            .hits;
+        let mut episodes = hits.into_iter().map(|hit| hit.result).collect::<Vec<_>>();
+        episodes.sort_by_key(|p| p.start_at);
+        if let Some(last) = episodes
+            .iter()
+            .filter_map(|p| p.series.as_ref()?.last_episode)
+            .filter(|n| *n > 0)
+            .last()
+        {
+            plan.last_episode = Some(last);
+        }
+
+        let mut pending = owned.iter().any(|s| s.program.start_at >= Local::now());
+        for p in episodes {
+            if p.start_at < Local::now() {
+                if plan.last_episode.is_some() && episode_of(&p) == plan.last_episode {
+                    plan.finale_aired = true;
+                }
+                continue;
+            }
+            if current
+                .iter()
+                .chain(found.iter())
+                .any(|s| s.program.id == p.id)
+            {
+                continue;
+            }
+            // Skip rebroadcasts of episodes which have been recorded or scheduled in the plan.
+            if let Some(ep) = episode_of(&p) {
+                let scheduled = owned
+                    .iter()
+                    .copied()
+                    .chain(found.iter().filter(|s| s.plan_id == plan_id))
+                    .any(|s| episode_of(&s.program) == Some(ep));
+                if plan.recorded_episodes.contains(&ep) || scheduled {
+                    continue;
+                }
+            }
+            pending = true;
+            found.push(Schedule {
+                program: p,
+                plan_id: plan_id.clone(),
+                is_active: true,
+                priority: plan.priority,
+                inactive_reason: None,
+                plan_name: plan.name.clone(),
+                profile: plan.profile.clone(),
+            });
+        }
+
+        // Retire the plan once the last episode has aired and nothing is left to record.
+        // A finale which has been missed doesn't keep the plan alive, unless it is rebroadcast.
+        let finished = plan.finale_aired
+            || plan
+                .last_episode
+                .map_or(false, |last| plan.recorded_episodes.contains(&last));
+        if (finished || plan.is_expired()) && !pending {
+            plan.is_retired = true;
+        }
+    }
+
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use serde_json::json;
+
+    use super::*;
+    use crate::db_utils::get_temporary_accessor;
+    use crate::test_utils;
+
+    const NETWORK_ID: u16 = 0x7fe0;
+
+    // An episode of the series starting `hours` from now, which is aired if negative
+    fn episode(series_id: i64, event_id: u16, hours: i64, ep: i32, last: i32) -> Program {
+        let start_at = Local::now() + Duration::hours(hours);
+        let program =
+            test_utils::program((NETWORK_ID, 0x0400, event_id), start_at, Some(1_800_000));
+        let mut value = serde_json::to_value(program).unwrap();
+        value["series"] = json!({
+            "id": series_id,
+            "episode": ep,
+            "lastEpisode": last,
+            "name": "テストシリーズ",
+        });
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn put(programs: &[&Program], recordings: &[Recording]) {
+        test_utils::put_documents(
+            "_programs",
+            programs
+                .iter()
+                .map(|p| serde_json::to_value(p).unwrap())
+                .collect(),
+        );
+        test_utils::put_documents(
+            "_recordings",
+            recordings
+                .iter()
+                .map(|r| serde_json::to_value(r).unwrap())
+                .collect(),
+        );
+    }
+
+    async fn collect(plans: &mut [SeriesPlan], current: &[Schedule]) -> Vec<Schedule> {
+        test_utils::config();
+        let client = get_temporary_accessor();
+        collect_series_schedules(
+            &client.index("_programs"),
+            &client.index("_recordings"),
+            plans,
+            current,
+        )
+        .await
+        .unwrap()
+    }
+
+    fn ids(schedules: &[Schedule]) -> Vec<i64> {
+        schedules.iter().map(|s| s.program.id).collect()
+    }
+
+    #[tokio::test]
+    async fn schedules_each_episode_once() {
+        let aired = episode(0x101, 0x3101, -24, 1, 3);
+        let second = episode(0x101, 0x3102, 24, 2, 3);
+        let rebroadcast = episode(0x101, 0x3103, 48, 2, 3);
+        let third = episode(0x101, 0x3104, 72, 3, 3);
+        // The same series id in another network is another series.
+        let mut other = serde_json::to_value(episode(0x101, 0x3105, 24, 1, 3)).unwrap();
+        other["networkId"] = json!(NETWORK_ID + 1);
+        let other: Program = serde_json::from_value(other).unwrap();
+        put(&[&aired, &second, &rebroadcast, &third, &other], &[]);
+
+        let mut plans = vec![SeriesPlan::from_program(&aired).unwrap()];
+        let found = collect(&mut plans, &[]).await;
+        assert_eq!(ids(&found), vec![second.id, third.id]);
+        assert!(found.iter().all(|s| s.plan_id == plans[0].plan_id()));
+        assert!(!plans[0].is_retired);
+
+        // Scheduled episodes are not returned again.
+        let found_again = collect(&mut plans, &found).await;
+        assert!(found_again.is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_recording_is_recorded_again() {
+        let failed = episode(0x102, 0x3111, -48, 1, 2);
+        let recorded = episode(0x102, 0x3112, -24, 2, 2);
+        let failed_rebroadcast = episode(0x102, 0x3113, 24, 1, 2);
+        let recorded_rebroadcast = episode(0x102, 0x3114, 48, 2, 2);
+        put(
+            &[
+                &failed,
+                &recorded,
+                &failed_rebroadcast,
+                &recorded_rebroadcast,
+            ],
+            &[
+                test_utils::recording(failed.clone(), false),
+                test_utils::recording(recorded.clone(), true),
+            ],
+        );
+
+        let mut plans = vec![SeriesPlan::from_program(&failed).unwrap()];
+        let found = collect(&mut plans, &[]).await;
+        assert_eq!(ids(&found), vec![failed_rebroadcast.id]);
+        assert_eq!(plans[0].recorded_episodes, vec![2]);
+        // The finale has aired, but the rebroadcast of episode 1 is still to be recorded.
+        assert!(!plans[0].is_retired);
+    }
+
+    #[tokio::test]
+    async fn retires_after_recording_finale() {
+        let first = episode(0x103, 0x3121, -48, 1, 2);
+        let finale = episode(0x103, 0x3122, -24, 2, 2);
+        put(
+            &[&first, &finale],
+            &[
+                test_utils::recording(first.clone(), true),
+                test_utils::recording(finale.clone(), true),
+            ],
+        );
+
+        let mut plans = vec![SeriesPlan::from_program(&first).unwrap()];
+        assert!(collect(&mut plans, &[]).await.is_empty());
+        assert!(plans[0].is_retired);
+    }
+
+    #[tokio::test]
+    async fn retires_after_missed_finale() {
+        let first = episode(0x104, 0x3131, -48, 1, 2);
+        let finale = episode(0x104, 0x3132, -24, 2, 2);
+        put(&[&first, &finale], &[]);
+
+        let mut plans = vec![SeriesPlan::from_program(&first).unwrap()];
+        assert!(collect(&mut plans, &[]).await.is_empty());
+        assert!(plans[0].finale_aired);
+        assert!(plans[0].is_retired);
+
+        // Retired plans are not followed any more.
+        let rebroadcast = episode(0x104, 0x3133, 24, 1, 2);
+        put(&[&rebroadcast], &[]);
+        assert!(collect(&mut plans, &[]).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn keeps_plan_for_rebroadcast_of_missed_finale() {
+        let finale = episode(0x105, 0x3141, -24, 2, 2);
+        let rebroadcast = episode(0x105, 0x3142, 24, 2, 2);
+        put(&[&finale, &rebroadcast], &[]);
+
+        let mut plans = vec![SeriesPlan::from_program(&finale).unwrap()];
+        let found = collect(&mut plans, &[]).await;
+        assert_eq!(ids(&found), vec![rebroadcast.id]);
+        assert!(!plans[0].is_retired);
+
+        // Still waiting for the scheduled rebroadcast
+        assert!(collect(&mut plans, &found).await.is_empty());
+        assert!(!plans[0].is_retired);
+    }
+}
@@ -0,0 +1,261 @@
+use chrono::{Local, NaiveTime};
+use log::warn;
+use meilisearch_sdk::errors::Error;
+use meilisearch_sdk::indexes::Index;
+use mirakurun_client::models::Program;
+use serde_derive::{Deserialize, Serialize};
+use ulid::Ulid;
+use utoipa::ToSchema;
+
+use crate::recording_planner::PlanId;
+use crate::sched_trigger::Schedule;
+
+// Programs fetched from _programs for a rule at once
+const SEARCH_PAGE: usize = 200;
+// Meilisearch returns no more hits of a search than this, i.e. `pagination.maxTotalHits` by default.
+const MAX_TOTAL_HITS: usize = 1000;
+
+/// An auto-recording rule. Every future program that matches `keyword` and all the filters is
+/// scheduled with `PlanId::Word(id)`. Rules are stored in the `_rules` index.
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub(crate) struct WordRule {
+    // Omitted when a new rule is posted.
+    #[serde(default = "Ulid::new")]
+    #[schema(value_type = String)]
+    pub(crate) id: Ulid,
+    // Passed to Meilisearch as it is, so its query syntax (e.g. "phrase search") is available.
+    pub(crate) keyword: String,
+    // Mirakurun's Service.id (network_id * 100000 + service_id). Empty means any service.
+    #[serde(default)]
+    pub(crate) service_ids: Vec<i64>,
+    // ARIB genre lv1 (e.g. 0x1 for sports). Empty means any genre.
+    #[serde(default)]
+    pub(crate) genres: Vec<i32>,
+    // Time of day when the program starts. `from` > `to` means a range over midnight.
+    #[serde(default)]
+    pub(crate) time_range: Option<TimeOfDayRange>,
+    // Given to the schedules created by the rule.
+    #[serde(default)]
+    pub(crate) priority: i32,
+    // Output profile of the schedules created by the rule. None means the default one.
+    #[serde(default)]
+    pub(crate) profile: Option<String>,
+    pub(crate) is_enabled: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub(crate) struct TimeOfDayRange {
+    #[schema(value_type = String, example = "23:00:00")]
+    pub(crate) from: NaiveTime,
+    #[schema(value_type = String, example = "05:00:00")]
+    pub(crate) to: NaiveTime,
+}
+
+impl TimeOfDayRange {
+    fn contains(&self, t: NaiveTime) -> bool {
+        if self.from <= self.to {
+            self.from <= t && t < self.to
+        } else {
+            self.from <= t || t < self.to
+        }
+    }
+}
+
+impl WordRule {
+    pub(crate) fn plan_id(&self) -> PlanId {
+        PlanId::Word(self.id)
+    }
+
+    /// Checks the filters other than the keyword, which is evaluated by Meilisearch.
+    pub(crate) fn is_match(&self, p: &Program) -> bool {
+        let service_id = p.network_id as i64 * 100000 + p.service_id as i64;
+        if !self.service_ids.is_empty() && !self.service_ids.contains(&service_id) {
+            return false;
+        }
+        if !self.genres.is_empty() {
+            let matched = p
+                .genres
+                .as_ref()
+                .map(|genres| {
+                    genres
+                        .iter()
+                        .any(|g| g.lv1.map_or(false, |lv1| self.genres.contains(&lv1)))
+                })
+                .unwrap_or(false);
+            if !matched {
+                return false;
+            }
+        }
+        if let Some(ref range) = self.time_range {
+            let start_at: chrono::DateTime<Local> = p.start_at.into();
+            if !range.contains(start_at.time()) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Evaluates enabled rules against `_programs` and returns the schedules for the future programs they match.
+pub(crate) async fn collect_word_schedules(
+    index_programs: &Index,
+    rules: &[WordRule],
+) -> Result<Vec<Schedule>, Error> {
+    let mut found: Vec<Schedule> = Vec::new();
+
+    for rule in rules.iter().filter(|r| r.is_enabled) {
+        // Fetched page by page, since a broad keyword matches many programs.
+        let mut offset = 0;
+        loop {
+            let hits = index_programs
+                .search()
+                .with_query(&rule.keyword)
+                .with_offset(offset)
+                .with_limit(SEARCH_PAGE)
+                .execute::<Program>()
+                .await?
+                .hits;
+            let n = hits.len();
+            offset += n;
+
+            for p in hits.into_iter().map(|hit| hit.result) {
+                if p.start_at < Local::now() || !rule.is_match(&p) {
+                    continue;
+                }
+                // The earlier rule wins if several rules match the same program.
+                if found.iter().any(|f| f.program.id == p.id) {
+                    continue;
+                }
+                found.push(Schedule {
+                    program: p,
+                    plan_id: rule.plan_id(),
+                    is_active: true,
+                    priority: rule.priority,
+                    inactive_reason: None,
+                    plan_name: Some(rule.keyword.clone()),
+                    profile: rule.profile.clone(),
+                });
+            }
+            if n < SEARCH_PAGE {
+                break;
+            }
+            if offset >= MAX_TOTAL_HITS {
+                warn!(
+                    "Rule {} ({:?}) matches more than {} programs. The rest are not scheduled.",
+                    rule.id, rule.keyword, MAX_TOTAL_HITS
+                );
+                break;
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone};
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_utils;
+
+    const NETWORK_ID: u16 = 0x7fe0;
+    const SERVICE_ID: u16 = 0x0400;
+
+    fn time(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    fn rule() -> WordRule {
+        WordRule {
+            id: Ulid::new(),
+            keyword: "ニュース".to_string(),
+            service_ids: Vec::new(),
+            genres: Vec::new(),
+            time_range: None,
+            priority: 0,
+            profile: None,
+            is_enabled: true,
+        }
+    }
+
+    // A program of the service starting at `h:m` tomorrow, in the ARIB genres of `lv1`
+    fn program(service_id: u16, (h, m): (u32, u32), lv1: &[i32]) -> Program {
+        let tomorrow = Local::now().date_naive() + Duration::days(1);
+        let start_at = Local
+            .from_local_datetime(&tomorrow.and_time(time(h, m)))
+            .unwrap();
+        let program =
+            test_utils::program((NETWORK_ID, service_id, 0x1001), start_at, Some(1_800_000));
+        let mut value = serde_json::to_value(program).unwrap();
+        value["genres"] = lv1.iter().map(|g| json!({"lv1": g, "lv2": 0})).collect();
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn time_range_contains_its_start_only() {
+        let range = TimeOfDayRange {
+            from: time(9, 0),
+            to: time(12, 0),
+        };
+        assert!(range.contains(time(9, 0)));
+        assert!(range.contains(time(11, 59)));
+        assert!(!range.contains(time(12, 0)));
+        assert!(!range.contains(time(8, 59)));
+    }
+
+    #[test]
+    fn time_range_crosses_midnight() {
+        let range = TimeOfDayRange {
+            from: time(23, 0),
+            to: time(5, 0),
+        };
+        assert!(range.contains(time(23, 0)));
+        assert!(range.contains(time(0, 0)));
+        assert!(range.contains(time(4, 59)));
+        assert!(!range.contains(time(5, 0)));
+        assert!(!range.contains(time(22, 59)));
+        assert!(!range.contains(time(12, 0)));
+    }
+
+    #[test]
+    fn rule_without_filters_matches_any_program() {
+        assert!(rule().is_match(&program(SERVICE_ID, (12, 0), &[])));
+    }
+
+    #[test]
+    fn rule_matches_listed_services() {
+        let rule = WordRule {
+            service_ids: vec![NETWORK_ID as i64 * 100000 + SERVICE_ID as i64],
+            ..rule()
+        };
+        assert!(rule.is_match(&program(SERVICE_ID, (12, 0), &[])));
+        assert!(!rule.is_match(&program(SERVICE_ID + 1, (12, 0), &[])));
+    }
+
+    #[test]
+    fn rule_matches_any_of_listed_genres() {
+        let rule = WordRule {
+            genres: vec![0x1, 0x6],
+            ..rule()
+        };
+        assert!(rule.is_match(&program(SERVICE_ID, (12, 0), &[0x0, 0x6])));
+        assert!(!rule.is_match(&program(SERVICE_ID, (12, 0), &[0x0])));
+        // Programs without genres don't match.
+        assert!(!rule.is_match(&program(SERVICE_ID, (12, 0), &[])));
+    }
+
+    #[test]
+    fn rule_matches_start_in_time_range() {
+        let rule = WordRule {
+            time_range: Some(TimeOfDayRange {
+                from: time(23, 0),
+                to: time(5, 0),
+            }),
+            ..rule()
+        };
+        assert!(rule.is_match(&program(SERVICE_ID, (1, 30), &[])));
+        assert!(!rule.is_match(&program(SERVICE_ID, (12, 0), &[])));
+    }
+}
@@ -0,0 +1,208 @@
+use std::path::PathBuf;
+
+use chrono::{DateTime, Local};
+use log::{error, info, warn};
+use meilisearch_sdk::errors::Error;
+use mirakurun_client::apis::configuration::Configuration;
+use mirakurun_client::models::{Program, Service};
+use serde_derive::{Deserialize, Serialize};
+use ulid::Ulid;
+use utoipa::ToSchema;
+
+use crate::config::get_config;
+use crate::db_utils::{delete_recording, get_temporary_accessor, pull_recording, push_recordings};
+use crate::hls;
+use crate::mirakurun_client::get_service_from_program;
+use crate::recording_planner::PlanId;
+use crate::recording_pool::recording_task::{RecordingState, RecordingTask};
+use crate::recording_pool::RecordingTaskDescription;
+
+/// A finished recording, stored in the `_recordings` index.
+/// Failed recordings are kept as well, so that users can see what has happened.
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub(crate) struct Recording {
+    #[schema(value_type = String)]
+    pub(crate) id: Ulid,
+    // Snapshot taken when the recording started
+    #[schema(value_type = Object)]
+    pub(crate) program: Program,
+    #[schema(value_type = Option<Object>)]
+    pub(crate) service: Option<Service>,
+    #[schema(value_type = Vec<String>)]
+    pub(crate) files: Vec<PathBuf>,
+    // Written along with the files, e.g. the pre-roll before the program started. Removed with the recording.
+    #[serde(default)]
+    #[schema(value_type = Vec<String>)]
+    pub(crate) sidecars: Vec<PathBuf>,
+    // Set if the recording has been resumed after restarts. One for each file.
+    #[serde(default)]
+    pub(crate) parts: Vec<Part>,
+    #[serde(default)]
+    pub(crate) volume: Option<String>,
+    // Total size of the files and the sidecars in bytes
+    pub(crate) size: u64,
+    pub(crate) started_at: DateTime<Local>,
+    pub(crate) ended_at: DateTime<Local>,
+    // Last state of the task, e.g. {"Lost": {"graceful": true}}
+    #[schema(value_type = Object)]
+    pub(crate) state: RecordingState,
+    pub(crate) packets: u64,
+    // Discontinuities of continuity_counter
+    pub(crate) drops: u64,
+    // Packets with transport_error_indicator
+    pub(crate) errors: u64,
+    pub(crate) plan_id: PlanId,
+    pub(crate) plan_name: Option<String>,
+    pub(crate) relayed_from: Option<i64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub(crate) struct Part {
+    #[schema(value_type = String)]
+    pub(crate) file: PathBuf,
+    pub(crate) started_at: DateTime<Local>,
+    pub(crate) ended_at: DateTime<Local>,
+    // Time not recorded since the end of the previous part
+    pub(crate) gap_secs: i64,
+}
+
+fn parts_of(info: &RecordingTaskDescription, rec: &RecordingTask) -> Vec<Part> {
+    let mut parts = Vec::new();
+    let mut last_end: Option<DateTime<Local>> = None;
+    let former = info
+        .parts
+        .iter()
+        .map(|p| (p.file.clone(), p.started_at, p.ended_at));
+    let current = (rec.file_location.clone(), rec.started_at, Local::now());
+    for (file, started_at, ended_at) in former.chain(std::iter::once(current)) {
+        parts.push(Part {
+            file,
+            started_at,
+            ended_at,
+            gap_secs: last_end.map_or(0, |end| (started_at - end).num_seconds().max(0)),
+        });
+        last_end = Some(ended_at);
+    }
+    parts
+}
+
+async fn total_size(files: &[PathBuf]) -> u64 {
+    let mut size = 0;
+    for file in files {
+        if let Ok(metadata) = tokio::fs::metadata(file).await {
+            size += metadata.len();
+        }
+    }
+    size
+}
+
+/// Stores the result of a task. Returns the id of the new entry, or None if the index is unavailable.
+pub(crate) async fn add_recording(
+    info: &RecordingTaskDescription,
+    rec: &RecordingTask,
+) -> Option<Ulid> {
+    let service = {
+        let mut c = Configuration::new();
+        c.base_path = get_config().mirakurun.base_uri.clone();
+        get_service_from_program(&c, &info.program).await
+    };
+    let parts = parts_of(info, rec);
+    let files = parts.iter().map(|p| p.file.clone()).collect::<Vec<_>>();
+    // Of the former runs, and of this one
+    let mut sidecars = Vec::new();
+    for file in info.sidecars.iter().chain(&rec.sidecars()) {
+        if !files.contains(file)
+            && !sidecars.contains(file)
+            && tokio::fs::metadata(file).await.is_ok()
+        {
+            sidecars.push(file.clone());
+        }
+    }
+    // Counted only in the last part
+    let stats = rec.stats();
+    let entry = Recording {
+        id: Ulid::new(),
+        program: info.program.clone(),
+        service,
+        size: total_size(&files).await + total_size(&sidecars).await,
+        files,
+        sidecars,
+        // A recording which has never been resumed consists of a single file.
+        parts: if parts.len() > 1 {
+            parts.clone()
+        } else {
+            Vec::new()
+        },
+        volume: info.volume.clone(),
+        started_at: parts[0].started_at,
+        ended_at: Local::now(),
+        state: rec.state,
+        packets: stats.packets,
+        drops: stats.drops,
+        errors: stats.errors,
+        plan_id: info.plan_id.clone(),
+        plan_name: info.plan_name.clone(),
+        relayed_from: info.relayed_from,
+    };
+
+    match push_recordings(&get_temporary_accessor(), &[entry.clone()]).await {
+        Ok(_) => {
+            info!(
+                "id: {} is stored in the library as {}. drops: {}, errors: {}",
+                info.program.id, entry.id, entry.drops, entry.errors
+            );
+            Some(entry.id)
+        }
+        Err(e) => {
+            error!(
+                "id: {} Failed to store the recording in the library. {}",
+                info.program.id, e
+            );
+            None
+        }
+    }
+}
+
+/// Follows the files moved by post-processing, given as pairs of the old and the new location.
+pub(crate) async fn relocate(id: &Ulid, moves: &[(PathBuf, PathBuf)]) -> Result<(), Error> {
+    let client = get_temporary_accessor();
+    let mut entry = pull_recording(&client, id).await?;
+    for (from, to) in moves {
+        for file in entry.files.iter_mut().filter(|f| *f == from) {
+            *file = to.clone();
+        }
+        for part in entry.parts.iter_mut().filter(|p| &p.file == from) {
+            part.file = to.clone();
+        }
+        for sidecar in entry.sidecars.iter_mut().filter(|f| *f == from) {
+            *sidecar = to.clone();
+        }
+    }
+    push_recordings(&client, &[entry]).await.map(|_| ())
+}
+
+/// Removes the entry, and its files if `remove_files` is set.
+/// Files which are already gone are ignored.
+pub(crate) async fn remove_recording(id: &Ulid, remove_files: bool) -> Result<Recording, String> {
+    let client = get_temporary_accessor();
+    let entry = pull_recording(&client, id)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if remove_files {
+        for file in entry.files.iter().chain(&entry.sidecars) {
+            match tokio::fs::remove_file(file).await {
+                Ok(()) => info!("{} is removed.", file.display()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    warn!("{} is already removed.", file.display())
+                }
+                Err(e) => return Err(format!("Failed to remove {}. {}", file.display(), e)),
+            }
+        }
+    }
+    delete_recording(&client, id)
+        .await
+        .map_err(|e| e.to_string())?;
+    hls::vod::forget(id).await;
+    Ok(entry)
+}
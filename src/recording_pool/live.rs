@@ -0,0 +1,57 @@
+//! Fan-out of the stream being recorded, served at `/api/v1/recordings/{id}/live` by the program id.
+//! Sending never waits for clients, so a slow client can't hold up the file writer. It misses chunks and is dropped instead.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+use bytes::Bytes;
+use once_cell::sync::Lazy;
+use tokio::sync::broadcast;
+
+// Chunks kept for each client until it receives them
+pub(crate) const CAPACITY: usize = 256;
+
+// (serial, sender) for each program id
+static FEEDS: Lazy<RwLock<HashMap<i64, (u64, broadcast::Sender<Bytes>)>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+static SERIAL: AtomicU64 = AtomicU64::new(0);
+
+/// Held by the task while it is recording. Dropping it ends the streams of the clients.
+pub(crate) struct LiveFeed {
+    id: i64,
+    serial: u64,
+    tx: broadcast::Sender<Bytes>,
+}
+
+impl LiveFeed {
+    pub(crate) fn open(id: i64) -> Self {
+        let (tx, _) = broadcast::channel(CAPACITY);
+        let serial = SERIAL.fetch_add(1, Ordering::Relaxed);
+        FEEDS.write().unwrap().insert(id, (serial, tx.clone()));
+        Self { id, serial, tx }
+    }
+
+    pub(crate) fn send(&self, chunk: &Bytes) {
+        // Fails only if nobody is watching.
+        let _ = self.tx.send(chunk.clone());
+    }
+}
+
+impl Drop for LiveFeed {
+    fn drop(&mut self) {
+        let mut feeds = FEEDS.write().unwrap();
+        // A newer task of the same program may have replaced it.
+        if feeds
+            .get(&self.id)
+            .map_or(false, |(serial, _)| *serial == self.serial)
+        {
+            feeds.remove(&self.id);
+        }
+    }
+}
+
+/// Returns None if the program is not being recorded.
+pub(crate) fn subscribe(id: i64) -> Option<broadcast::Receiver<Bytes>> {
+    FEEDS.read().unwrap().get(&id).map(|(_, tx)| tx.subscribe())
+}
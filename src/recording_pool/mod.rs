@@ -1,20 +1,33 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::RwLock;
 
-use log::info;
+use chrono::{DateTime, Local};
+use log::{error, info};
 use mirakurun_client::models::Program;
 use once_cell::sync::Lazy;
 use serde_derive::{Deserialize, Serialize};
 use tokio::sync::mpsc::Receiver;
+use utoipa::ToSchema;
 
+use crate::events::{publish, Event};
+use crate::recording_planner::PlanId;
 use crate::recording_pool::pool::RecTaskQueue;
+use crate::storage::volume;
 
+pub(crate) mod library;
+pub(crate) mod live;
+pub(crate) mod naming;
 pub(crate) mod pool;
-mod recording_task;
+pub(crate) mod recording_task;
 
 pub(crate) static REC_POOL: Lazy<RwLock<RecTaskQueue>> =
     Lazy::new(|| RwLock::new(RecTaskQueue::new()));
 
+/// Program id -> the program id the event is relayed to. Maintained by EpgSyncManager.
+pub(crate) static EVENT_RELAY: Lazy<RwLock<HashMap<i64, i64>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
 #[derive(Debug)]
 pub enum RecordControlMessage {
     CreateOrUpdate(RecordingTaskDescription),
@@ -23,10 +36,60 @@ pub enum RecordControlMessage {
 }
 
 // Recomposed from Program.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct RecordingTaskDescription {
+    #[schema(value_type = Object)]
     pub program: Program,
+    // Root of the volume. Chosen when the task is created.
+    #[schema(value_type = String)]
     pub save_dir_location: PathBuf,
+    #[serde(default)]
+    pub(crate) volume: Option<String>,
+    // Set if the task has been created by following the event relay of another recording.
+    #[serde(default)]
+    pub relayed_from: Option<i64>,
+    #[serde(default)]
+    pub(crate) plan_id: PlanId,
+    #[serde(default)]
+    pub(crate) plan_name: Option<String>,
+    // Output of the running task and when it was opened. Kept so that the task can be resumed after a restart.
+    #[serde(default)]
+    #[schema(value_type = Option<String>)]
+    pub(crate) file: Option<PathBuf>,
+    #[serde(default)]
+    pub(crate) started_at: Option<DateTime<Local>>,
+    // Files written before restarts
+    #[serde(default)]
+    pub(crate) parts: Vec<RecordingPart>,
+    // Outputs replaced by a later one, e.g. the temporary file of the pre-roll
+    #[serde(default)]
+    #[schema(value_type = Vec<String>)]
+    pub(crate) sidecars: Vec<PathBuf>,
+    // Output profile. None means the default one.
+    #[serde(default)]
+    pub(crate) profile: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub(crate) struct RecordingPart {
+    #[schema(value_type = String)]
+    pub(crate) file: PathBuf,
+    pub(crate) started_at: DateTime<Local>,
+    // The last modification of the file
+    pub(crate) ended_at: DateTime<Local>,
+}
+
+impl RecordingTaskDescription {
+    // Takes over the fields owned by the pool from the description being replaced.
+    fn inherit(&mut self, current: &RecordingTaskDescription) {
+        self.save_dir_location = current.save_dir_location.clone();
+        self.volume = current.volume.clone();
+        self.relayed_from = self.relayed_from.or(current.relayed_from);
+        self.file = current.file.clone();
+        self.started_at = current.started_at;
+        self.parts = current.parts.clone();
+        self.sidecars = current.sidecars.clone();
+    }
 }
 
 pub(crate) async fn recording_pool_startup(mut rx: Receiver<RecordControlMessage>) {
@@ -39,14 +102,45 @@ pub(crate) async fn recording_pool_startup(mut rx: Receiver<RecordControlMessage
         }
 
         match received {
-            Some(RecordControlMessage::CreateOrUpdate(info)) => REC_POOL.write().unwrap().add(info),
+            Some(RecordControlMessage::CreateOrUpdate(info)) => {
+                if let Some(info) = place(info).await {
+                    REC_POOL.write().unwrap().add(info)
+                }
+            }
             Some(RecordControlMessage::Remove(id)) => {
                 if REC_POOL.write().unwrap().try_remove(&id) {
                     info!("id: {} is removed from recording pool.", id)
                 }
             }
-            Some(RecordControlMessage::TryCreate(info)) => REC_POOL.write().unwrap().try_add(info),
+            Some(RecordControlMessage::TryCreate(info)) => {
+                if let Some(info) = place(info).await {
+                    REC_POOL.write().unwrap().try_add(info)
+                }
+            }
             None => continue,
         };
     }
 }
+
+// Chooses the volume of a new task. Volumes are inspected on a blocking thread, without holding the pool.
+// Tasks in the pool keep their volume, and are returned as they are.
+async fn place(mut info: RecordingTaskDescription) -> Option<RecordingTaskDescription> {
+    let id = info.program.id;
+    if REC_POOL.read().unwrap().at(&id).is_some() {
+        return Some(info);
+    }
+    let placed = tokio::task::spawn_blocking(move || volume::place(&mut info).map(|_| info))
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+    match placed {
+        Ok(info) => Some(info),
+        Err(e) => {
+            error!("id: {} cannot be recorded. {}", id, e);
+            publish(Event::Error {
+                program_id: Some(id),
+                message: e,
+            });
+            None
+        }
+    }
+}
@@ -0,0 +1,432 @@
+//! Template of the paths of recordings.
+//!
+//! A template is a relative path whose placeholders are replaced with the program's metadata.
+//! `/` separates directories, and `{{` / `}}` are literal braces.
+//!
+//! | Placeholder    | Value                                          |
+//! |----------------|------------------------------------------------|
+//! | `{id}`         | Program id                                     |
+//! | `{event_id}`   | Event id                                       |
+//! | `{service_id}` | Service id                                     |
+//! | `{channel}`    | Service name (service id if unknown)           |
+//! | `{title}`      | Program name ("untitled" if unknown)           |
+//! | `{date}`       | Start date (`%Y-%m-%d`)                        |
+//! | `{time}`       | Start time (`%H%M`)                            |
+//! | `{start:FMT}`  | Start date/time in strftime format             |
+//! | `{episode}`    | Episode number                                 |
+//! | `{series}`     | Series name                                    |
+//! | `{plan}`       | `common`, `word_{ulid}` or `series_{ulid}`     |
+//! | `{plan_name}`  | Keyword of the rule or name of the series plan |
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local};
+
+use crate::recording_planner::PlanId;
+use crate::recording_pool::RecordingTaskDescription;
+
+// Added by RecordingTask. The longest one is reserved.
+const EXTENSION_RESERVED: usize = ".m2ts-tmp".len();
+// Reserved for the suffix added on collision (e.g. " (12)")
+const SUFFIX_RESERVED: usize = 8;
+// Suffixes beyond this are not tried, which fits in SUFFIX_RESERVED.
+const MAX_COLLISIONS: usize = 9999;
+
+enum Token<'a> {
+    Literal(&'a str),
+    Placeholder(&'a str, Option<&'a str>),
+}
+
+fn tokenize(template: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = template;
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix("{{") {
+            tokens.push(Token::Literal("{"));
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("}}") {
+            tokens.push(Token::Literal("}"));
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix('{') {
+            let end = r
+                .find('}')
+                .ok_or_else(|| format!("Unclosed placeholder in {:?}.", template))?;
+            let (name, format) = match r[..end].split_once(':') {
+                Some((name, format)) => (name, Some(format)),
+                None => (&r[..end], None),
+            };
+            tokens.push(Token::Placeholder(name, format));
+            rest = &r[end + 1..];
+        } else if rest.starts_with('}') {
+            return Err(format!("Unmatched `}}` in {:?}.", template));
+        } else {
+            let end = rest
+                .find(|c: char| c == '{' || c == '}')
+                .unwrap_or(rest.len());
+            tokens.push(Token::Literal(&rest[..end]));
+            rest = &rest[end..];
+        }
+    }
+    Ok(tokens)
+}
+
+/// Checks the syntax and the placeholders of a template. Used in the config validation.
+pub(crate) fn validate_template(template: &str) -> Result<(), String> {
+    for token in tokenize(template)? {
+        if let Token::Placeholder(name, format) = token {
+            match (name, format) {
+                ("start", Some(format)) => {
+                    if StrftimeItems::new(format).any(|i| i == Item::Error) {
+                        return Err(format!("Invalid format in {{start:{}}}.", format));
+                    }
+                }
+                (
+                    "id" | "event_id" | "service_id" | "channel" | "title" | "date" | "time"
+                    | "episode" | "series" | "plan" | "plan_name",
+                    None,
+                ) => {}
+                _ => return Err(format!("Unknown placeholder {{{}}}.", name)),
+            }
+        }
+    }
+    if template.trim_matches('/').is_empty() {
+        return Err("The template is empty.".to_string());
+    }
+    Ok(())
+}
+
+fn plan_dir_name(plan_id: &PlanId) -> String {
+    match plan_id {
+        PlanId::Word(id) => format!("word_{}", id),
+        PlanId::Series(id) => format!("series_{}", id),
+        PlanId::None => "common".to_string(),
+    }
+}
+
+fn value_of(
+    name: &str,
+    format: Option<&str>,
+    info: &RecordingTaskDescription,
+    channel: Option<&str>,
+) -> String {
+    let p = &info.program;
+    let start_at: DateTime<Local> = p.start_at.into();
+    let series = p.series.as_ref();
+    match (name, format) {
+        ("start", Some(format)) => start_at.format(format).to_string(),
+        ("id", _) => p.id.to_string(),
+        ("event_id", _) => p.event_id.to_string(),
+        ("service_id", _) => p.service_id.to_string(),
+        ("channel", _) => channel.map_or_else(|| p.service_id.to_string(), str::to_string),
+        ("title", _) => p.name.clone().unwrap_or_else(|| "untitled".to_string()),
+        ("date", _) => start_at.format("%Y-%m-%d").to_string(),
+        ("time", _) => start_at.format("%H%M").to_string(),
+        ("episode", _) => series
+            .and_then(|s| s.episode)
+            .map_or_else(String::new, |n| n.to_string()),
+        ("series", _) => series.and_then(|s| s.name.clone()).unwrap_or_default(),
+        ("plan", _) => plan_dir_name(&info.plan_id),
+        ("plan_name", _) => info.plan_name.clone().unwrap_or_default(),
+        // Rejected by validate_template()
+        _ => String::new(),
+    }
+}
+
+/// Replaces the characters that are invalid in file names on common file systems.
+fn sanitize(value: &str) -> String {
+    let replaced = value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+    // Windows doesn't allow trailing dots and spaces.
+    replaced
+        .trim_end_matches(|c: char| c == '.' || c == ' ')
+        .to_string()
+}
+
+/// Cuts the string at a char boundary so that it fits in `max_bytes`.
+fn truncate_bytes(value: &str, max_bytes: usize) -> &str {
+    if value.len() <= max_bytes {
+        return value;
+    }
+    let mut end = max_bytes;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    &value[..end]
+}
+
+/// Renders the template into a path relative to the save directory, without extension.
+/// Every component is limited to `max_bytes` including the extension and a collision suffix.
+/// A resumed recording continues in a numbered part (e.g. " part2") next to the former ones.
+pub(crate) fn render(
+    template: &str,
+    info: &RecordingTaskDescription,
+    channel: Option<&str>,
+    max_bytes: usize,
+) -> Result<PathBuf, String> {
+    let mut rendered = String::new();
+    for token in tokenize(template)? {
+        match token {
+            Token::Literal(s) => rendered.push_str(s),
+            Token::Placeholder(name, format) => {
+                rendered.push_str(&sanitize(&value_of(name, format, info, channel)))
+            }
+        }
+    }
+
+    let limit = max_bytes.saturating_sub(EXTENSION_RESERVED + SUFFIX_RESERVED);
+    let mut components = rendered
+        .split('/')
+        // Trailing dots are removed, so `.` and `..` never escape from the save directory.
+        .map(|c| truncate_bytes(c.trim(), limit).trim_end_matches(|c: char| c == '.' || c == ' '))
+        // Empty values (e.g. no series) leave empty directories, which are skipped.
+        .filter(|c| !c.is_empty())
+        .collect::<Vec<_>>();
+    if components.is_empty() {
+        components.push("untitled");
+    }
+    let mut path = components.into_iter().collect::<PathBuf>();
+    if !info.parts.is_empty() {
+        let suffix = format!(" part{}", info.parts.len() + 1);
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let name = truncate_bytes(&name, limit.saturating_sub(suffix.len()))
+            .trim_end_matches(|c: char| c == '.' || c == ' ');
+        path = path.with_file_name(format!("{}{}", name, suffix));
+    }
+    Ok(path)
+}
+
+/// Appends the extension without replacing the dots in the title (e.g. "Dr.STONE").
+pub(crate) fn with_extension(stem: &Path, ext: &str) -> PathBuf {
+    let mut path = stem.as_os_str().to_os_string();
+    path.push(".");
+    path.push(ext);
+    PathBuf::from(path)
+}
+
+/// Appends " (2)", " (3)", ... to the file name until neither the recording nor its temporary file exists.
+pub(crate) fn resolve_collision(dir: &Path, stem: &Path) -> std::io::Result<PathBuf> {
+    let exists = |candidate: &Path| {
+        ["m2ts", "m2ts-tmp"]
+            .iter()
+            .any(|ext| with_extension(&dir.join(candidate), ext).exists())
+    };
+    if !exists(stem) {
+        return Ok(dir.join(stem));
+    }
+    let name = stem
+        .file_name()
+        .map(|f| f.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    for n in 2..=MAX_COLLISIONS {
+        let candidate = stem.with_file_name(format!("{} ({})", name, n));
+        if !exists(&candidate) {
+            return Ok(dir.join(candidate));
+        }
+    }
+    Err(std::io::Error::new(
+        ErrorKind::AlreadyExists,
+        format!(
+            "{} is taken up to ({}).",
+            dir.join(stem).display(),
+            MAX_COLLISIONS
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use ulid::Ulid;
+
+    use super::*;
+    use crate::recording_pool::RecordingPart;
+    use crate::test_utils;
+
+    const DEFAULT: &str = "{plan}/{id}_{title}";
+
+    fn info(title: &str) -> RecordingTaskDescription {
+        let start_at = Local.with_ymd_and_hms(2030, 1, 2, 21, 0, 0).unwrap();
+        let mut program =
+            test_utils::program((0x7fe0, 0x0400, 0x1001), start_at, Some(30 * 60 * 1000));
+        program.name = Some(title.to_string());
+        RecordingTaskDescription {
+            program,
+            save_dir_location: PathBuf::new(),
+            volume: None,
+            relayed_from: None,
+            plan_id: PlanId::None,
+            plan_name: None,
+            file: None,
+            started_at: None,
+            parts: Vec::new(),
+            sidecars: Vec::new(),
+            profile: None,
+        }
+    }
+
+    fn describe(template: &str) -> Result<Vec<String>, String> {
+        Ok(tokenize(template)?
+            .into_iter()
+            .map(|token| match token {
+                Token::Literal(s) => s.to_string(),
+                Token::Placeholder(name, None) => format!("<{}>", name),
+                Token::Placeholder(name, Some(format)) => format!("<{}:{}>", name, format),
+            })
+            .collect())
+    }
+
+    #[test]
+    fn tokenizes_placeholders_and_escaped_braces() {
+        assert_eq!(
+            describe("{{a}}/{start:%Y-%m}{id}b").unwrap(),
+            vec!["{", "a", "}", "/", "<start:%Y-%m>", "<id>", "b"]
+        );
+        assert!(describe("{id").is_err());
+        assert!(describe("a}b").is_err());
+        assert!(describe("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn validates_placeholders() {
+        assert!(validate_template(DEFAULT).is_ok());
+        assert!(validate_template("{series}/{start:%Y%m%d} {channel} #{episode}").is_ok());
+        assert!(validate_template("{name}").is_err());
+        assert!(validate_template("{id:%Y}").is_err());
+        assert!(validate_template("{start}").is_err());
+        assert!(validate_template("{start:%Q}").is_err());
+        assert!(validate_template("/").is_err());
+    }
+
+    #[test]
+    fn sanitizes_reserved_characters() {
+        assert_eq!(
+            sanitize("a/b\\c:d*e?f\"g<h>i|j\u{7}k"),
+            "a_b_c_d_e_f_g_h_i_j_k"
+        );
+        assert_eq!(sanitize("Dr.STONE"), "Dr.STONE");
+        assert_eq!(sanitize("続く... "), "続く");
+    }
+
+    #[test]
+    fn truncates_at_char_boundary() {
+        assert_eq!(truncate_bytes("abc", 3), "abc");
+        assert_eq!(truncate_bytes("abc", 2), "ab");
+        // 3 bytes each
+        assert_eq!(truncate_bytes("あいう", 6), "あい");
+        assert_eq!(truncate_bytes("あいう", 5), "あ");
+        assert_eq!(truncate_bytes("あいう", 2), "");
+    }
+
+    #[test]
+    fn default_template_keeps_former_names() {
+        let info = info("ニュース");
+        let expected = format!("common/{}_ニュース", info.program.id);
+        assert_eq!(
+            render(DEFAULT, &info, None, 255).unwrap(),
+            Path::new(&expected)
+        );
+
+        let id = Ulid::new();
+        let info = RecordingTaskDescription {
+            plan_id: PlanId::Series(id),
+            ..info
+        };
+        let expected = format!("series_{}/{}_ニュース", id, info.program.id);
+        assert_eq!(
+            render(DEFAULT, &info, None, 255).unwrap(),
+            Path::new(&expected)
+        );
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        let info = info("a/b");
+        let template = "{channel}/{date} {time} {start:%y%m%d} {title} {service_id}";
+        assert_eq!(
+            render(template, &info, Some("NHK総合"), 255).unwrap(),
+            Path::new("NHK総合/2030-01-02 2100 300102 a_b 1024")
+        );
+        // Without the service name
+        assert_eq!(
+            render("{channel}", &info, None, 255).unwrap(),
+            Path::new("1024")
+        );
+    }
+
+    #[test]
+    fn empty_and_dot_components_are_skipped() {
+        // No series
+        assert_eq!(
+            render("{series}/{plan_name}/{title}", &info("ニュース"), None, 255).unwrap(),
+            Path::new("ニュース")
+        );
+        assert_eq!(
+            render("{title}/..", &info(".."), None, 255).unwrap(),
+            Path::new("untitled")
+        );
+    }
+
+    #[test]
+    fn leaves_room_for_extension_and_suffixes() {
+        let max_bytes = 64;
+        let limit = max_bytes - EXTENSION_RESERVED - SUFFIX_RESERVED;
+        let mut info = info(&"あ".repeat(100));
+        let name = render("{title}", &info, None, max_bytes).unwrap();
+        let name = name.to_str().unwrap();
+        assert!(name.len() <= limit);
+        assert_eq!(name, "あ".repeat(limit / 3));
+
+        info.parts = vec![
+            RecordingPart {
+                file: PathBuf::from("a.m2ts"),
+                started_at: Local::now(),
+                ended_at: Local::now(),
+            };
+            11
+        ];
+        let part = render("{title}", &info, None, max_bytes).unwrap();
+        let part = part.to_str().unwrap();
+        assert!(part.len() <= limit);
+        assert!(part.ends_with("あ part12"));
+        assert!(part.starts_with(&"あ".repeat((limit - " part12".len()) / 3)));
+    }
+
+    #[test]
+    fn resolves_collisions_with_recordings_and_temporary_files() {
+        let dir = test_utils::temp_dir("naming");
+        let stem = Path::new("common/title");
+        std::fs::create_dir_all(dir.join("common")).unwrap();
+        assert_eq!(resolve_collision(&dir, stem).unwrap(), dir.join(stem));
+
+        std::fs::write(dir.join("common/title.m2ts"), b"").unwrap();
+        assert_eq!(
+            resolve_collision(&dir, stem).unwrap(),
+            dir.join("common/title (2)")
+        );
+        std::fs::write(dir.join("common/title (2).m2ts-tmp"), b"").unwrap();
+        assert_eq!(
+            resolve_collision(&dir, stem).unwrap(),
+            dir.join("common/title (3)")
+        );
+    }
+
+    #[test]
+    fn gives_up_on_too_many_collisions() {
+        let dir = test_utils::temp_dir("naming");
+        std::fs::write(dir.join("title.m2ts"), b"").unwrap();
+        for n in 2..=MAX_COLLISIONS {
+            std::fs::write(dir.join(format!("title ({}).m2ts", n)), b"").unwrap();
+        }
+        let e = resolve_collision(&dir, Path::new("title")).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::AlreadyExists);
+        // The largest suffix fits in the room reserved for it.
+        assert!(format!(" ({})", MAX_COLLISIONS).len() <= SUFFIX_RESERVED);
+    }
+}
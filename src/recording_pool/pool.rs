@@ -1,50 +1,115 @@
-/// Ser/des for recording_pool. Contents are serialized on drop automatically.
+/// Ser/des for recording_pool. Contents are written through to q_recording.json in `storage.state_dir` on every change.
 use std::collections::HashMap;
-use std::io::Error;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chrono::{DateTime, Local};
 
 use futures_util::TryStreamExt;
-use log::error;
+use log::{error, info, warn};
 use mirakurun_client::apis::configuration::Configuration;
 use mirakurun_client::apis::programs_api::get_program_stream;
-use structopt::StructOpt;
+use once_cell::sync::Lazy;
+use serde_derive::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWriteExt};
 use tokio::select;
 use tokio::sync::oneshot::{Receiver, Sender};
+use tokio::task::JoinHandle;
 use tokio_util::io::StreamReader;
 
-use crate::recording_pool::recording_task::RecordingTask;
-use crate::recording_pool::{RecordingTaskDescription, REC_POOL};
-use crate::Opt;
+use crate::config::get_config;
+use crate::db_utils::{get_temporary_accessor, pull_program};
+use crate::events::{publish, Event};
+use crate::post_process;
+use crate::recording_pool::library;
+use crate::recording_pool::live::LiveFeed;
+use crate::recording_pool::recording_task::{RecordingState, RecordingTask};
+use crate::recording_pool::{RecordingPart, RecordingTaskDescription, EVENT_RELAY, REC_POOL};
+use crate::sched_trigger::expected_end_at;
+use crate::sched_trigger::persistence::{backup_path, Writer};
+use crate::shutdown;
+use crate::storage;
 
 #[derive(Default)]
 pub(crate) struct RecTaskQueue {
     inner: HashMap<i64, RecordingTaskDescription>,
     inner_abort_handle: HashMap<i64, Sender<()>>,
+    // Programs whose recording has finished, and when they are regarded as over. They are never recorded again,
+    // even if sched_trigger requests them while they are regarded as on air. Forgotten once they are over.
+    finished: HashMap<i64, DateTime<Local>>,
+    // What is in the file now. Writing is skipped if nothing has changed.
+    saved: Vec<u8>,
+    // Awaited on shutdown so that every output is closed cleanly
+    handles: Vec<JoinHandle<()>>,
+}
+
+fn pool_file() -> PathBuf {
+    get_config().storage.state_dir.join("q_recording.json")
+}
+
+// Older files have a bare array of the tasks.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Stored {
+    Current {
+        items: Vec<RecordingTaskDescription>,
+        finished: HashMap<i64, DateTime<Local>>,
+    },
+    Tasks(Vec<RecordingTaskDescription>),
+}
+
+#[derive(Serialize)]
+struct StoredRef<'a> {
+    items: Vec<&'a RecordingTaskDescription>,
+    finished: &'a HashMap<i64, DateTime<Local>>,
+}
+
+// Snapshots of the pool are written on a thread of its own, so that the pool isn't held while the file is synced.
+static WRITER: Lazy<Arc<Writer>> = Lazy::new(|| Writer::spawn("the recording pool"));
+
+/// Blocks until every change of the pool so far has been written to the file.
+pub(crate) fn flush() {
+    WRITER.flush();
 }
 
 impl RecTaskQueue {
     pub(crate) fn new() -> Self {
         Self::default()
     }
-    pub(crate) fn add(&mut self, info: RecordingTaskDescription) {
+    /// New tasks must have been placed on a volume by volume::place.
+    pub(crate) fn add(&mut self, mut info: RecordingTaskDescription) {
         // 1. Insert RecordingTaskDescription regardless of its existence.
         // 2. Create new task only if there's no abort_handle that has the same id in inner_abort_handle.
         //    In this situation, RecordingTaskDescription should be overwritten.
         let id = info.program.id;
+        if self.finished.contains_key(&id) {
+            return;
+        }
+        // The volume is chosen only once, when the task is created.
+        if let Some(current) = self.inner.get(&id) {
+            info.inherit(current);
+        }
 
         self.inner.insert(id, info);
 
         if !self.inner_abort_handle.contains_key(&id) {
             let (tx, rx) = tokio::sync::oneshot::channel();
-            tokio::spawn(spawn_new(id, rx));
+            self.handles.push(tokio::spawn(spawn_new(id, rx)));
 
             self.inner_abort_handle.insert(id, tx);
         }
+        self.save();
     }
+    /// New tasks must have been placed on a volume by volume::place.
     pub(crate) fn try_add(&mut self, info: RecordingTaskDescription) {
         // 1. Create new task only if there's no abort_handle that has the same id in inner_abort_handle.
         //    In this situation, RecordingTaskDescription should be overwritten.
         // 2. Otherwise, create RecordingTaskDescription if it isn't exist.
         let id = info.program.id;
+        if self.finished.contains_key(&id) {
+            return;
+        }
 
         let insertion_result = {
             if !self.inner.contains_key(&id) {
@@ -52,7 +117,7 @@ impl RecTaskQueue {
 
                 if !self.inner_abort_handle.contains_key(&id) {
                     let (tx, rx) = tokio::sync::oneshot::channel();
-                    tokio::spawn(spawn_new(id, rx));
+                    self.handles.push(tokio::spawn(spawn_new(id, rx)));
 
                     self.inner_abort_handle.insert(id, tx);
                 }
@@ -61,6 +126,9 @@ impl RecTaskQueue {
                 false
             }
         };
+        if insertion_result {
+            self.save();
+        }
     }
     pub(crate) fn try_remove(&mut self, id: &i64) -> bool {
         let info_removal = self.inner.remove(&id);
@@ -68,8 +136,26 @@ impl RecTaskQueue {
             .inner_abort_handle
             .remove(&id)
             .and_then(|abort| abort.send(()).ok());
+        self.save();
         info_removal.is_some() || handle_removal.is_some()
     }
+    /// Called when the task has ended by itself. The abort handle is dropped without being used.
+    /// `done` keeps the program from being recorded again.
+    pub(crate) fn finish(&mut self, id: i64, done: bool) {
+        // A new task of the program may have been added after this one was removed.
+        // Its receiver is still alive, unlike the one of the task calling this.
+        if let Some(abort) = self.inner_abort_handle.get(&id) {
+            if !abort.is_closed() {
+                return;
+            }
+        }
+        let info = self.inner.remove(&id);
+        self.inner_abort_handle.remove(&id);
+        if let (true, Some(info)) = (done, info) {
+            self.finished.insert(id, expected_end_at(&info.program));
+        }
+        self.save();
+    }
     pub(crate) fn at(&self, id: &i64) -> Option<&RecordingTaskDescription> {
         self.inner.get(&id)
     }
@@ -79,48 +165,465 @@ impl RecTaskQueue {
     pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = &mut RecordingTaskDescription> {
         self.inner.values_mut()
     }
+    /// Takes the tasks which are still running.
+    pub(crate) fn take_handles(&mut self) -> Vec<JoinHandle<()>> {
+        self.handles.retain(|h| !h.is_finished());
+        std::mem::take(&mut self.handles)
+    }
+    /// Called by the task whenever it opens a new output.
+    pub(crate) fn set_output(&mut self, id: i64, file: &Path, started_at: DateTime<Local>) {
+        if let Some(info) = self.inner.get_mut(&id) {
+            if let Some(replaced) = info.file.replace(file.to_path_buf()) {
+                if replaced != file && !info.sidecars.contains(&replaced) {
+                    info.sidecars.push(replaced);
+                }
+            }
+            info.started_at = Some(started_at);
+            self.save();
+        }
+    }
+    /// Writes the descriptions through to the file. Must be called after every mutation.
+    /// Only a snapshot is taken here, and the file is written by another thread.
+    pub(crate) fn save(&mut self) {
+        let now = Local::now();
+        self.finished.retain(|_, end| now < *end);
+        let mut items = self.inner.values().collect::<Vec<_>>();
+        items.sort_by_key(|info| info.program.id);
+        let stored = StoredRef {
+            items,
+            finished: &self.finished,
+        };
+        let bytes = match serde_json::to_vec(&stored) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                error!("Failed to serialize the recording pool. {}", e);
+                return;
+            }
+        };
+        if bytes == self.saved {
+            return;
+        }
+        WRITER.send(pool_file(), bytes.clone());
+        self.saved = bytes;
+    }
+    /// Resumes the tasks which were running when the process stopped.
+    /// The file written so far is kept as a part, and the recording continues in a new file.
+    /// Fails if the file cannot be read, since it would be overwritten by the next save.
+    pub(crate) fn restore(&mut self) -> std::io::Result<()> {
+        let path = pool_file();
+        let bytes = match std::fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let items = match serde_json::from_slice::<Stored>(&bytes) {
+            Ok(Stored::Current { items, finished }) => {
+                self.finished = finished;
+                items
+            }
+            Ok(Stored::Tasks(items)) => items,
+            // Moved aside, so that it isn't overwritten.
+            Err(e) => {
+                let backup = backup_path(&path);
+                warn!(
+                    "{} is broken, and no recording is resumed. {} It is moved to {}.",
+                    path.display(),
+                    e,
+                    backup.display()
+                );
+                return std::fs::rename(&path, &backup);
+            }
+        };
+
+        for mut info in items {
+            if expected_end_at(&info.program) < Local::now() {
+                warn!(
+                    "id: {} has ended while the process was stopped. Files: {:?} {:?}",
+                    info.program.id,
+                    info.parts.iter().map(|p| &p.file).collect::<Vec<_>>(),
+                    info.file
+                );
+                continue;
+            }
+            if let (Some(file), Some(started_at)) = (info.file.take(), info.started_at.take()) {
+                let ended_at = std::fs::metadata(&file)
+                    .and_then(|m| m.modified())
+                    .map(DateTime::<Local>::from)
+                    .unwrap_or(started_at);
+                info.parts.push(RecordingPart {
+                    file,
+                    started_at,
+                    ended_at,
+                });
+            }
+            info!(
+                "id: {} is resumed. {} part(s) have been recorded so far.",
+                info.program.id,
+                info.parts.len()
+            );
+            self.add(info);
+        }
+        self.save();
+        Ok(())
+    }
 }
 
-async fn spawn_new(id: i64, rx: Receiver<()>) {
-    select! {
-        // If value is removed, abort the transmission.
-        //_ = || async{ while let Some(_) = REC_POOL.lock().await.inner.get(&id) {} }=> {},
-        _ = rx => {},
-        Err(e) = generate_task(id) => error!("{:#?}", e)
+async fn spawn_new(id: i64, mut rx: Receiver<()>) {
+    let result = match open_task(id, &mut rx).await {
+        Ok(Some((target, src, rec))) => generate_task(id, target, src, rec, rx).await,
+        // Removed before the recording starts
+        Ok(None) => return,
+        // Nothing has been recorded, so the task is removed and sched_trigger tries again on the next scan.
+        // The receiver is dropped first, so that finish() takes the task for this one.
+        Err(e) => {
+            drop(rx);
+            REC_POOL.write().unwrap().finish(id, false);
+            Err(e)
+        }
+    };
+    match result {
+        // Interrupted tasks are left in the pool to be resumed.
+        Ok(_) if shutdown::is_shutting_down() => {}
+        Ok(state) => {
+            info!("id: {} has finished. {:?}", id, state);
+            REC_POOL.write().unwrap().finish(id, true);
+        }
+        Err(e) => {
+            error!("{:#?}", e);
+            publish(Event::Error {
+                program_id: Some(id),
+                message: e.to_string(),
+            });
+        }
     }
 }
 
-async fn generate_task(id: i64) -> std::io::Result<u64> {
-    let (mut src, mut rec) = {
-        let target = REC_POOL
-            .read()
-            .unwrap()
-            .inner
-            .get(&id)
-            .expect(
-                "A new task cannot be spawned because the RecordingTaskDescription is not found. This is unreachable.",
-            )
-            .clone();
-
-        // Create a new task
-        let rec = RecordingTask::new(& target).await?;
-
-        let args = Opt::from_args();
-        let m_url = args.mirakurun_base_uri;
-        let mut c = Configuration::new();
-        c.base_path = m_url;
-        // Get Ts Stream
-        let src = match get_program_stream(&c, target.program.id, None, None).await {
-            Ok(value) => StreamReader::new(
-                value
-                    .bytes_stream()
-                    .map_err(|e: mirakurun_client::Error| Error::new(std::io::ErrorKind::Other, e)),
-            ),
-            Err(e) => return Err(Error::new(std::io::ErrorKind::Other, e)),
-        };
-        (src, rec)
+// Once the output is opened, the removal no longer drops it but closes it,
+// so that the filters of the profile write out what they hold.
+async fn open_task(
+    id: i64,
+    rx: &mut Receiver<()>,
+) -> std::io::Result<
+    Option<(
+        RecordingTaskDescription,
+        impl AsyncRead + Unpin,
+        RecordingTask,
+    )>,
+> {
+    let target = REC_POOL
+        .read()
+        .unwrap()
+        .inner
+        .get(&id)
+        .expect(
+            "A new task cannot be spawned because the RecordingTaskDescription is not found. This is unreachable.",
+        )
+        .clone();
+
+    // Create a new task
+    // 空き容量が足りなければ開始しない
+    storage::ensure_free_space(&target.save_dir_location)?;
+    let mut rec = select! {
+        rec = RecordingTask::new(&target) => rec?,
+        _ = &mut *rx => return Ok(None),
     };
 
+    let mut c = Configuration::new();
+    c.base_path = get_config().mirakurun.base_uri.clone();
+    // Get Ts Stream
+    // The chunks are shared with live clients as they are received.
+    let live = LiveFeed::open(id);
+    let stream = select! {
+        stream = get_program_stream(&c, target.program.id, None, None) => stream,
+        _ = &mut *rx => {
+            rec.shutdown().await?;
+            return Ok(None);
+        }
+    };
+    let src = match stream {
+        Ok(value) => StreamReader::new(
+            value
+                .bytes_stream()
+                .inspect_ok(move |chunk| live.send(chunk))
+                .map_err(|e: mirakurun_client::Error| Error::new(std::io::ErrorKind::Other, e)),
+        ),
+        Err(e) => return Err(Error::new(std::io::ErrorKind::Other, e)),
+    };
+    Ok(Some((target, src, rec)))
+}
+
+// The task stays in the pool until everything following the recording is done,
+// and is removed by spawn_new. Only the copy loop is cut off by the removal.
+async fn generate_task(
+    id: i64,
+    target: RecordingTaskDescription,
+    mut src: impl AsyncRead + Unpin,
+    mut rec: RecordingTask,
+    rx: Receiver<()>,
+) -> std::io::Result<RecordingState> {
     // Stream connection
-    tokio::io::copy(&mut src, &mut rec).await
+    let result = select! {
+        result = tokio::io::copy(&mut src, &mut rec) => result,
+        // Removed while the source is silent. The output is closed below.
+        _ = rx => Ok(0),
+        // The output is closed below, and the task is resumed after the restart.
+        _ = shutdown::wait_for_shutdown() => Ok(0),
+    };
+    let shutdown = rec.shutdown().await;
+    if shutdown::is_shutting_down() {
+        info!("id: {} is interrupted by the shutdown. {:?}", id, rec.state);
+        return shutdown.map(|_| rec.state);
+    }
+    let recording_id = library::add_recording(&target, &rec).await;
+    if let Err(e) = shutdown {
+        REC_POOL.write().unwrap().finish(id, false);
+        return Err(e);
+    }
+    match result {
+        // RecordingTask refuses to write after the program is over.
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::WriteZero => {}
+        Err(e) => {
+            REC_POOL.write().unwrap().finish(id, false);
+            return Err(e);
+        }
+    };
+
+    if rec.state.is_graceful_end() {
+        let sidecars = target
+            .sidecars
+            .iter()
+            .cloned()
+            .chain(rec.sidecars())
+            .collect();
+        post_process::enqueue(&target, &rec.file_location, sidecars, recording_id);
+        follow_event_relay(&target).await;
+    }
+    Ok(rec.state)
+}
+
+// Continues the recording on the service the event is relayed to (e.g. a sports broadcast extended on another channel).
+// It is saved as another file next to the original one, and linked by `relayed_from`.
+async fn follow_event_relay(from: &RecordingTaskDescription) {
+    let to = match EVENT_RELAY.read().unwrap().get(&from.program.id) {
+        Some(to) => *to,
+        None => return,
+    };
+    let program = match pull_program(&get_temporary_accessor(), to).await {
+        Ok(program) => program,
+        Err(e) => {
+            error!(
+                "id: {} is relayed to {}, but the program is not found. {}",
+                from.program.id, to, e
+            );
+            return;
+        }
+    };
+
+    info!(
+        "id: {} is relayed to id: {} (service_id={}).",
+        from.program.id, program.id, program.service_id
+    );
+    REC_POOL.write().unwrap().add(RecordingTaskDescription {
+        program,
+        save_dir_location: from.save_dir_location.clone(),
+        volume: from.volume.clone(),
+        relayed_from: Some(from.program.id),
+        plan_id: from.plan_id.clone(),
+        plan_name: from.plan_name.clone(),
+        file: None,
+        started_at: None,
+        parts: Vec::new(),
+        sidecars: Vec::new(),
+        profile: from.profile.clone(),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use bytes::Bytes;
+    use chrono::Duration;
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_utils;
+    use crate::ts::testing::{eit_event, eit_pf, filler, packetize};
+
+    const NETWORK_ID: u16 = 0x7fe0;
+    const SERVICE_ID: u16 = 0x0400;
+    const EVENT_ID: u16 = 0x1001;
+
+    #[test]
+    fn reads_finished_programs_and_older_files() {
+        let stored = r#"{"items": [], "finished": {"1": "2022-01-01T00:00:00+09:00"}}"#;
+        match serde_json::from_str::<Stored>(stored).unwrap() {
+            Stored::Current { items, finished } => {
+                assert!(items.is_empty());
+                assert!(finished.contains_key(&1));
+            }
+            Stored::Tasks(_) => panic!("Read as an older file"),
+        }
+        assert!(matches!(
+            serde_json::from_str::<Stored>("[]").unwrap(),
+            Stored::Tasks(_)
+        ));
+    }
+
+    // A task of a program on air, saved into `save_dir_location`
+    fn target(event_id: u16, save_dir_location: PathBuf) -> RecordingTaskDescription {
+        let program = test_utils::program(
+            (NETWORK_ID, SERVICE_ID, event_id),
+            Local::now() - Duration::minutes(1),
+            Some(30 * 60 * 1000),
+        );
+        RecordingTaskDescription {
+            program,
+            save_dir_location,
+            volume: None,
+            relayed_from: None,
+            plan_id: Default::default(),
+            plan_name: None,
+            file: None,
+            started_at: None,
+            parts: Vec::new(),
+            sidecars: Vec::new(),
+            profile: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn graceful_end_is_stored_in_library() {
+        test_utils::config();
+        let target = target(EVENT_ID, test_utils::temp_dir("pool"));
+        let id = target.program.id;
+        // Placed as sched_trigger does, without spawning the task
+        REC_POOL.write().unwrap().inner.insert(id, target.clone());
+        let rec = RecordingTask::new(&target).await.unwrap();
+
+        // Written one chunk at a time, so that the task sees each EIT[p/f] by itself.
+        // The program goes on air, and is then replaced with the next one.
+        let (mut eit_cc, mut video_cc) = (0, 0);
+        let chunks = [
+            packetize(
+                0x12,
+                &mut eit_cc,
+                &eit_pf(NETWORK_ID, SERVICE_ID, 0, &[eit_event(EVENT_ID, 20)]),
+            ),
+            filler(0x100, &mut video_cc),
+            packetize(
+                0x12,
+                &mut eit_cc,
+                &eit_pf(NETWORK_ID, SERVICE_ID, 0, &[eit_event(EVENT_ID + 1, 20)]),
+            ),
+            filler(0x100, &mut video_cc),
+        ];
+        let src = StreamReader::new(futures_util::stream::iter(
+            chunks.map(|chunk| Ok::<_, Error>(Bytes::from(chunk))),
+        ));
+        // Never removed
+        let (_tx, rx) = tokio::sync::oneshot::channel();
+
+        let state = generate_task(id, target, src, rec, rx).await.unwrap();
+        assert!(state.is_graceful_end());
+        let stored = test_utils::documents("_recordings")
+            .into_iter()
+            .find(|r| r["program"]["id"] == id)
+            .expect("The recording is not stored in the library.");
+        assert_eq!(stored["state"], json!({"Lost": {"graceful": true}}));
+        let file = stored["files"][0].as_str().unwrap();
+        assert!(file.ends_with(".m2ts") && Path::new(file).exists());
+        // The output of the pre-roll is kept along with the recording.
+        let pre_roll = stored["sidecars"][0].as_str().unwrap();
+        assert!(pre_roll.ends_with(".m2ts-tmp") && Path::new(pre_roll).exists());
+        let size =
+            std::fs::metadata(file).unwrap().len() + std::fs::metadata(pre_roll).unwrap().len();
+        assert_eq!(stored["size"], size);
+
+        let recording_id = stored["id"].as_str().unwrap().parse().unwrap();
+        library::remove_recording(&recording_id, true)
+            .await
+            .unwrap();
+        assert!(!Path::new(file).exists());
+        assert!(!Path::new(pre_roll).exists());
+
+        REC_POOL.write().unwrap().finish(id, true);
+    }
+
+    #[tokio::test]
+    async fn task_failing_to_start_is_removed() {
+        test_utils::config();
+        // The free space of a missing directory can't be checked.
+        let missing = test_utils::temp_dir("pool").join("missing");
+        let target = target(EVENT_ID + 2, missing);
+        let id = target.program.id;
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        {
+            let mut pool = REC_POOL.write().unwrap();
+            pool.inner.insert(id, target);
+            pool.inner_abort_handle.insert(id, tx);
+        }
+
+        spawn_new(id, rx).await;
+        let pool = REC_POOL.read().unwrap();
+        assert!(pool.at(&id).is_none());
+        assert!(!pool.inner_abort_handle.contains_key(&id));
+        assert!(!pool.finished.contains_key(&id));
+    }
+
+    #[tokio::test]
+    async fn relayed_event_is_recorded_as_linked_task() {
+        test_utils::config();
+        // The task of the relayed program fails to start in the missing directory.
+        let mut from = target(EVENT_ID + 3, test_utils::temp_dir("pool").join("missing"));
+        from.plan_name = Some("スポーツ".to_string());
+        let relayed = test_utils::program(
+            (NETWORK_ID, SERVICE_ID + 1, EVENT_ID + 4),
+            Local::now(),
+            Some(60 * 60 * 1000),
+        );
+        test_utils::put_documents("_programs", vec![serde_json::to_value(&relayed).unwrap()]);
+
+        // Not relayed
+        follow_event_relay(&from).await;
+        assert!(REC_POOL.read().unwrap().at(&relayed.id).is_none());
+
+        EVENT_RELAY
+            .write()
+            .unwrap()
+            .insert(from.program.id, relayed.id);
+        follow_event_relay(&from).await;
+        // Checked before the spawned task gets to run
+        let task = REC_POOL.read().unwrap().at(&relayed.id).cloned().unwrap();
+        assert_eq!(task.relayed_from, Some(from.program.id));
+        assert_eq!(task.program.service_id, relayed.service_id);
+        assert_eq!(task.save_dir_location, from.save_dir_location);
+        assert_eq!(task.plan_name, from.plan_name);
+        assert!(task.file.is_none());
+
+        // Then it leaves the pool by itself.
+        let removed = async {
+            while REC_POOL.read().unwrap().at(&relayed.id).is_some() {
+                tokio::task::yield_now().await;
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(10), removed)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn relay_to_unknown_program_is_ignored() {
+        test_utils::config();
+        let from = target(EVENT_ID + 5, test_utils::temp_dir("pool"));
+        let to = crate::mirakurun_client::get_program_id(
+            NETWORK_ID as i64,
+            SERVICE_ID as i64 + 1,
+            EVENT_ID as i64 + 6,
+        );
+        EVENT_RELAY.write().unwrap().insert(from.program.id, to);
+        follow_event_relay(&from).await;
+        assert!(REC_POOL.read().unwrap().at(&to).is_none());
+    }
 }
@@ -1,22 +1,34 @@
+use std::future::Future;
 use std::io::Error;
-use std::path::{Path, PathBuf};
+use std::mem::discriminant;
+use std::path::PathBuf;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
 use chrono::{DateTime, Duration, Local};
-use log::info;
-use pin_project_lite::pin_project;
+use futures_util::ready;
+use log::{info, warn};
+use mirakurun_client::apis::configuration::Configuration;
+use serde_derive::{Deserialize, Serialize};
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 
-use crate::recording_pool::recording_task::eit_parser::EitDetected;
-use crate::recording_pool::recording_task::{eit_parser::EitParser, io_object::IoObject};
+use crate::config::{get_config, ProfileConfig};
+use crate::events::{publish, Event};
+use crate::mirakurun_client::get_service_from_program;
+use crate::recording_pool::naming;
+use crate::recording_pool::recording_task::eit_parser::{EitDetected, EitParser, PacketStats};
+use crate::recording_pool::recording_task::sink::Sink;
 use crate::recording_pool::{RecordingTaskDescription, REC_POOL};
+use crate::sched_trigger::expected_end_at;
 
 mod eit_parser;
-mod io_object;
+pub(crate) mod sink;
+
+// EIT[p/f] is sent every few seconds. Once it has been missing for this long, the schedule is followed instead.
+const EIT_TIMEOUT_SECS: i64 = 60;
 
 machine!(
-    #[derive(Clone, Copy, PartialEq)]
+    #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
     pub(crate) enum RecordingState {
         A { since: DateTime<Local> },
         B1 { since: DateTime<Local> },
@@ -25,6 +37,12 @@ machine!(
         Lost { graceful: bool },
     }
 );
+impl RecordingState {
+    pub(crate) fn is_graceful_end(&self) -> bool {
+        matches!(self, RecordingState::Lost(Lost { graceful: true }))
+    }
+}
+
 impl IntoB2 for A {}
 impl IntoB2 for B1 {}
 impl IntoB2 for B2 {}
@@ -63,6 +81,15 @@ impl B2 {
     }
 }
 
+impl Rec {
+    fn on_found_in_present(self, _: FoundInPresent) -> Rec {
+        Rec { since: self.since }
+    }
+    fn on_present_program_lost(self, _: PresentProgramLost) -> Lost {
+        Lost { graceful: true }
+    }
+}
+
 transitions!(RecordingState,
     [
         (A, FoundInFollowing) => B2,
@@ -71,7 +98,9 @@ transitions!(RecordingState,
         (A, FoundInPresent) => Rec,
         (B1, FoundInPresent) => Rec,
         (B2, FoundInPresent) => Rec,
+        (Rec, FoundInPresent) => Rec,
         (B2, PresentProgramLost) => Lost,
+        (Rec, PresentProgramLost) => Lost,
         (A, WaitForPremiere) => [A, B1, Lost],
         (B1, WaitForPremiere) => [B1, Lost]
     ]
@@ -112,45 +141,155 @@ pub struct WaitForPremiere {
     start_at: DateTime<Local>,
 }
 
-pin_project! {
-    pub(crate) struct RecordingTask {
-        #[pin]
-        target: Option<IoObject>,
-        eit: EitParser,
-        next_state: RecordingState,
-        pub(crate) state: RecordingState,
-        pub(crate) id: i64,
-        pub(crate) file_location: PathBuf
-    }
+type Switching = Pin<Box<dyn Future<Output = Result<Option<Sink>, Error>> + Send>>;
+
+pub(crate) struct RecordingTask {
+    target: Option<Sink>,
+    // Shutting down the current output and opening the next one, if the state has changed
+    switching: Option<Switching>,
+    // Chosen when the task is created, and used for every output of the task
+    profile: ProfileConfig,
+    eit: EitParser,
+    // When the last EIT[p/f] section of the service has arrived
+    eit_received_at: Option<DateTime<Local>>,
+    pub(crate) state: RecordingState,
+    pub(crate) id: i64,
+    // Extracted by the service stages of the profile
+    service_id: u16,
+    pub(crate) file_location: PathBuf,
+    // Every output opened so far, e.g. the temporary one of the pre-roll
+    outputs: Vec<PathBuf>,
+    pub(crate) started_at: DateTime<Local>,
 }
 
 impl RecordingTask {
     pub(crate) async fn new(info: &RecordingTaskDescription) -> Result<Self, Error> {
         let info = info.clone();
-        let mut file_location = info.save_dir_location;
         // Specify file name here
-        file_location.push(format!(
-            "{}_{}.m2ts-tmp",
-            info.program.id,
-            info.program
-                .name
-                .as_ref()
-                .unwrap_or(&"untitled".to_string())
-        ));
-        let target = Some(IoObject::new(file_location.as_path()).await?);
+        let naming = &get_config().naming;
+        let channel = {
+            let mut c = Configuration::new();
+            c.base_path = get_config().mirakurun.base_uri.clone();
+            get_service_from_program(&c, &info.program)
+                .await
+                .map(|s| s.name)
+        };
+        let stem = naming::render(
+            &naming.template,
+            &info,
+            channel.as_deref(),
+            naming.max_bytes,
+        )
+        .unwrap_or_else(|e| {
+            warn!(
+                "id: {} Failed to render the file name. {}",
+                info.program.id, e
+            );
+            PathBuf::from(format!("{}_untitled", info.program.id))
+        });
+        if let Some(parent) = info.save_dir_location.join(&stem).parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let file_location = naming::with_extension(
+            &naming::resolve_collision(&info.save_dir_location, &stem)?,
+            "m2ts-tmp",
+        );
+        let profile = sink::find_profile(info.profile.as_deref()).unwrap_or_else(|| {
+            warn!(
+                "id: {} Profile {:?} is not found. The default one is used.",
+                info.program.id, info.profile
+            );
+            sink::find_profile(None).expect("The default profile always exists.")
+        });
+        let service_id = info.program.service_id as u16;
+        let target = Some(sink::open(&profile, file_location.as_path(), service_id).await?);
+        let started_at = Local::now();
+        REC_POOL
+            .write()
+            .unwrap()
+            .set_output(info.program.id, &file_location, started_at);
         Ok(Self {
             target,
+            switching: None,
+            profile,
             eit: EitParser::new(),
-            next_state: RecordingState::A(A {
-                since: Local::now(),
-            }),
+            eit_received_at: None,
             state: RecordingState::A(A {
                 since: Local::now(),
             }),
             id: info.program.id,
+            service_id,
+            outputs: vec![file_location.clone()],
             file_location,
+            started_at,
         })
     }
+
+    pub(crate) fn stats(&self) -> PacketStats {
+        self.eit.stats()
+    }
+
+    /// Files written by the task other than the current output, including the ones written by the profile
+    pub(crate) fn sidecars(&self) -> Vec<PathBuf> {
+        let artifacts = self
+            .outputs
+            .iter()
+            .flat_map(|output| sink::artifacts(&self.profile, output));
+        self.outputs
+            .iter()
+            .filter(|f| **f != self.file_location)
+            .cloned()
+            .chain(artifacts)
+            .collect()
+    }
+
+    fn poll_switching(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        if let Some(switching) = self.switching.as_mut() {
+            let result = ready!(switching.as_mut().poll(cx));
+            self.switching = None;
+            self.target = result?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+// The extension of the output for each state. None means the output is closed.
+fn extension_of(state: &RecordingState) -> Option<&'static str> {
+    match state {
+        RecordingState::Rec(_) => Some("m2ts"),
+        RecordingState::Lost(_) | RecordingState::Error => None,
+        _ => Some("m2ts-tmp"),
+    }
+}
+
+fn next_state(
+    state: RecordingState,
+    detected: EitDetected,
+    info: &RecordingTaskDescription,
+    eit_available: bool,
+) -> RecordingState {
+    // EIT[p/f] tells the end while it is received. Otherwise the scheduled end applies, which Mirakurun may update on air.
+    // The limit of unknown duration applies in any case.
+    let past_end = expected_end_at(&info.program) < Local::now();
+    let is_over = match info.program.duration {
+        Some(_) => past_end && !eit_available,
+        None => past_end,
+    };
+
+    match (state, detected) {
+        (RecordingState::Rec(_), EitDetected::FoundInP) if !is_over => {
+            state.on_found_in_present(FoundInPresent {})
+        }
+        // The present event has been replaced with another one.
+        (RecordingState::Rec(_), _) | (RecordingState::B2(_), EitDetected::NotFound) => {
+            state.on_present_program_lost(PresentProgramLost {})
+        }
+        (_, EitDetected::FoundInP) => state.on_found_in_present(FoundInPresent {}),
+        (_, EitDetected::FoundInF) => state.on_found_in_following(FoundInFollowing {}),
+        (_, EitDetected::NotFound) => state.on_wait_for_premiere(WaitForPremiere {
+            start_at: info.program.start_at.into(),
+        }),
+    }
 }
 
 impl AsyncWrite for RecordingTask {
@@ -159,69 +298,162 @@ impl AsyncWrite for RecordingTask {
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<std::io::Result<usize>> {
-        let mut me = self.project();
-
-        // Get RecordingDescription. If not exist, return error.
-        if let Some(item) = REC_POOL.read().unwrap().at(me.id) {
-            // Evaluate states and control IoObject
-            let after = match me.eit.push(buf, item) {
-                EitDetected::FoundInP => me.state.on_found_in_present(FoundInPresent {}),
-                EitDetected::FoundInF => me.state.on_found_in_following(FoundInFollowing {}),
-                EitDetected::NotFound => me.state.on_wait_for_premiere(WaitForPremiere {
-                    start_at: REC_POOL
-                        .read()
-                        .unwrap()
-                        .at(me.id)
-                        .unwrap()
-                        .program
-                        .start_at
-                        .into(),
-                }),
-            };
-            *me.next_state = after;
-
-            if me.state != me.next_state {
-                // Determine file name
-                match me.next_state {
-                    RecordingState::Rec(_) => me.file_location.set_extension("m2ts"),
-                    RecordingState::Error => todo!(),
-                    _ => me.file_location.set_extension("m2ts-tmp"),
-                };
-
-                let w = cx.waker().clone();
-
-                // Kill the current IoObject and create a new one
-                std::thread::scope(|s| {
-                    s.spawn(|| async {
-                        let new_writer = IoObject::new(Path::new("")).await.unwrap();
-                        if let Some(mut old_writer) = me.target.replace(new_writer) {
-                            old_writer.shutdown().await.unwrap()
-                        }
-                        w.wake()
-                    });
+        let me = self.get_mut();
+
+        ready!(me.poll_switching(cx))?;
+        let target = match me.target.as_mut() {
+            Some(target) => target,
+            // Returning 0 tells the source that the recording is over.
+            None => return Poll::Ready(Ok(0)),
+        };
+
+        // Get RecordingDescription. If not exist, close the output.
+        let pool = REC_POOL.read().unwrap();
+        let item = match pool.at(&me.id) {
+            Some(item) => item,
+            None => {
+                ready!(Pin::new(target).poll_shutdown(cx))?;
+                me.target = None;
+                return Poll::Ready(Ok(0));
+            }
+        };
+
+        // Only the bytes accepted by the output are inspected, so that none of them is parsed twice.
+        let n = ready!(Pin::new(target).poll_write(cx, buf))?;
+
+        // Evaluate states and control the output
+        let received = me.eit.sections_of_service();
+        let detected = me.eit.push(&buf[..n], item);
+        if me.eit.sections_of_service() != received {
+            me.eit_received_at = Some(Local::now());
+        }
+        let eit_available = me.eit_received_at.map_or(false, |at| {
+            Local::now() - at < Duration::seconds(EIT_TIMEOUT_SECS)
+        });
+        let next = next_state(me.state, detected, item, eit_available);
+        drop(pool);
+
+        if discriminant(&me.state) != discriminant(&next) {
+            info!("id: {} {:?} -> {:?}", me.id, me.state, next);
+            publish(Event::RecordingState {
+                program_id: me.id,
+                from: me.state,
+                to: next,
+            });
+        }
+        let (before, after) = (extension_of(&me.state), extension_of(&next));
+        if before != after {
+            // Determine file name
+            let next_location = after.map(|ext| {
+                me.file_location.set_extension(ext);
+                me.file_location.clone()
+            });
+            if let Some(location) = &next_location {
+                if !me.outputs.contains(location) {
+                    me.outputs.push(location.clone());
+                }
+                REC_POOL
+                    .write()
+                    .unwrap()
+                    .set_output(me.id, location, me.started_at);
+                publish(Event::FileRotated {
+                    program_id: me.id,
+                    file: location.clone(),
                 });
-                Poll::Pending
-            } else {
-                me.target.as_pin_mut().unwrap().poll_write(cx, buf)
             }
-        } else {
-            me.target
-                .as_pin_mut()
-                .unwrap()
-                .poll_shutdown(cx)
-                .map_ok(|_| 0)
+            let old_writer = me.target.take();
+            let (profile, service_id) = (me.profile.clone(), me.service_id);
+            // It is driven at the beginning of the next call.
+            me.switching = Some(Box::pin(async move {
+                if let Some(mut old_writer) = old_writer {
+                    old_writer.shutdown().await?;
+                }
+                match next_location {
+                    Some(location) => sink::open(&profile, location.as_path(), service_id)
+                        .await
+                        .map(Some),
+                    None => Ok(None),
+                }
+            }));
         }
+        me.state = next;
+
+        Poll::Ready(Ok(n))
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
-        let me = self.project();
-        me.target.as_pin_mut().unwrap().poll_flush(cx)
+        let me = self.get_mut();
+        ready!(me.poll_switching(cx))?;
+        match me.target.as_mut() {
+            Some(target) => Pin::new(target).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
     }
 
     fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
-        let me = self.project();
-        REC_POOL.write().unwrap().try_remove(me.id);
+        let me = self.get_mut();
+        ready!(me.poll_switching(cx))?;
+        // The task is removed from the pool by its owner once the output is closed.
         info!("id: {} is shutting down...", me.id);
-        me.target.as_pin_mut().unwrap().poll_shutdown(cx)
+        match me.target.as_mut() {
+            Some(target) => Pin::new(target).poll_shutdown(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    fn description(
+        start_at: DateTime<Local>,
+        duration_ms: Option<i64>,
+    ) -> RecordingTaskDescription {
+        RecordingTaskDescription {
+            program: test_utils::program((0x7fe0, 0x0400, 0x3001), start_at, duration_ms),
+            save_dir_location: PathBuf::new(),
+            volume: None,
+            relayed_from: None,
+            plan_id: Default::default(),
+            plan_name: None,
+            file: None,
+            started_at: None,
+            parts: Vec::new(),
+            sidecars: Vec::new(),
+            profile: None,
+        }
+    }
+
+    fn rec() -> RecordingState {
+        RecordingState::Rec(Rec {
+            since: Local::now(),
+        })
+    }
+
+    #[test]
+    fn eit_keeps_recording_past_scheduled_end() {
+        test_utils::config();
+        // Scheduled to have ended 10 minutes ago
+        let info = description(Local::now() - Duration::minutes(40), Some(30 * 60 * 1000));
+        let next = next_state(rec(), EitDetected::FoundInP, &info, true);
+        assert!(matches!(next, RecordingState::Rec(_)));
+        // The scheduled end applies once EIT is lost.
+        let next = next_state(rec(), EitDetected::FoundInP, &info, false);
+        assert!(next.is_graceful_end());
+    }
+
+    #[test]
+    fn limit_applies_to_unknown_duration() {
+        let config = test_utils::config();
+        let limit = Duration::minutes(config.recording.unknown_duration_limit_minutes);
+        let info = description(Local::now() - limit - Duration::minutes(1), None);
+        let next = next_state(rec(), EitDetected::FoundInP, &info, true);
+        assert!(next.is_graceful_end());
+
+        let info = description(Local::now() - Duration::minutes(1), None);
+        let next = next_state(rec(), EitDetected::FoundInP, &info, false);
+        assert!(matches!(next, RecordingState::Rec(_)));
     }
 }
@@ -1,27 +1,487 @@
-use chrono::{DateTime, Local};
 use crate::recording_pool::RecordingTaskDescription;
+use crate::ts::{crc32, payload_of, pid_of, Packets, CRC32_LENGTH, NULL_PID, PID_COUNT};
 
-struct EitParserInner {}
+// EIT is carried on 0x12. 0x26 and 0x27 are used by ARIB for additional EIT (e.g. one-seg, L-EIT).
+const EIT_PIDS: [u16; 3] = [0x12, 0x26, 0x27];
+// EIT[p/f] for the actual transport stream
+const TABLE_ID_EIT_PF_ACTUAL: u8 = 0x4E;
+// table_id .. last_table_id
+const EIT_HEADER_LENGTH: usize = 14;
+// Sections waiting in the buffer of a PID, including the one being reassembled
+const SECTION_BUF_SIZE: usize = 8192;
+// Initial value of `last_cc`
+const CC_UNKNOWN: u8 = 0xff;
+
+// Sections of a PID being reassembled. The PIDs send their sections interleaved.
+#[derive(Default)]
+struct SectionBuf {
+    // continuity_counter of the last packet. None means that nothing is being reassembled.
+    cc: Option<u8>,
+    buf: Vec<u8>,
+}
+
+impl SectionBuf {
+    fn reset(&mut self) {
+        self.cc = None;
+        self.buf.clear();
+    }
+}
+
+struct EitParserInner {
+    // One for each of EIT_PIDS
+    sections: [SectionBuf; EIT_PIDS.len()],
+    // Latest knowledge of the target event. None means that no section for the service has arrived yet.
+    in_present: Option<bool>,
+    in_following: Option<bool>,
+    // EIT[p/f] sections of the service parsed so far, which tells whether EIT is still received
+    sections_of_service: u64,
+    // Every packet passes through the parser, so the stream quality is measured here as well.
+    stats: PacketStats,
+    last_cc: Box<[u8; PID_COUNT]>,
+}
 
 pub(super) struct EitParser {
+    packets: Packets,
     state: EitParserInner,
-    buf: [u8; 8192],
 }
 
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct PacketStats {
+    pub(crate) packets: u64,
+    // Discontinuities of continuity_counter
+    pub(crate) drops: u64,
+    // Packets with transport_error_indicator
+    pub(crate) errors: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub(super) enum EitDetected {
     FoundInP,
     FoundInF,
-    NotFound
+    NotFound,
+}
+
+struct Target {
+    network_id: u16,
+    service_id: u16,
+    event_id: u16,
 }
 
 impl EitParser {
     pub fn new() -> Self {
         EitParser {
-            state: EitParserInner {},
-            buf: [0; 8192],
+            packets: Packets::new(),
+            state: EitParserInner {
+                sections: Default::default(),
+                in_present: None,
+                in_following: None,
+                sections_of_service: 0,
+                stats: PacketStats::default(),
+                last_cc: Box::new([CC_UNKNOWN; PID_COUNT]),
+            },
+        }
+    }
+    pub(super) fn stats(&self) -> PacketStats {
+        self.state.stats
+    }
+    pub(super) fn sections_of_service(&self) -> u64 {
+        self.state.sections_of_service
+    }
+    pub(super) fn push(&mut self, buf: &[u8], program: &RecordingTaskDescription) -> EitDetected {
+        let target = Target {
+            network_id: program.program.network_id as u16,
+            service_id: program.program.service_id as u16,
+            event_id: program.program.event_id as u16,
+        };
+        self.push_for(buf, &target)
+    }
+
+    fn push_for(&mut self, buf: &[u8], target: &Target) -> EitDetected {
+        let state = &mut self.state;
+        self.packets.push(buf, |packet, resynced| {
+            // Some bytes have been lost, and the sections under reassembly are broken.
+            if resynced {
+                state.sections.iter_mut().for_each(SectionBuf::reset);
+            }
+            state.feed_packet(packet, target);
+        });
+
+        state.detected()
+    }
+}
+
+impl EitParserInner {
+    fn detected(&self) -> EitDetected {
+        match (self.in_present, self.in_following) {
+            (Some(true), _) => EitDetected::FoundInP,
+            (_, Some(true)) => EitDetected::FoundInF,
+            _ => EitDetected::NotFound,
+        }
+    }
+
+    fn count_packet(&mut self, packet: &[u8], pid: u16) {
+        self.stats.packets += 1;
+        if packet[1] & 0x80 != 0 {
+            self.stats.errors += 1;
+            return;
+        }
+        let adaptation_field_control = (packet[3] >> 4) & 0x03;
+        // continuity_counter doesn't increase without payload.
+        if pid == NULL_PID || adaptation_field_control & 0x01 == 0 {
+            return;
+        }
+        let discontinuity =
+            adaptation_field_control & 0x02 != 0 && packet[4] > 0 && packet[5] & 0x80 != 0;
+        let cc = packet[3] & 0x0f;
+        let last = self.last_cc[pid as usize];
+        // A packet may be sent twice with the same counter.
+        if last != CC_UNKNOWN && !discontinuity && cc != last && cc != last.wrapping_add(1) & 0x0f {
+            self.stats.drops += 1;
+        }
+        self.last_cc[pid as usize] = cc;
+    }
+
+    fn feed_packet(&mut self, packet: &[u8], target: &Target) {
+        let pid = pid_of(packet);
+        self.count_packet(packet, pid);
+        let i = match EIT_PIDS.iter().position(|p| *p == pid) {
+            Some(i) => i,
+            None => return,
+        };
+        // transport_error_indicator
+        if packet[1] & 0x80 != 0 {
+            self.sections[i].reset();
+            return;
+        }
+        let payload_unit_start = packet[1] & 0x40 != 0;
+        let cc = packet[3] & 0x0f;
+        // A packet may be sent twice with the same counter. The copy would break the section.
+        if self.sections[i].cc == Some(cc) {
+            return;
+        }
+        let payload = match payload_of(packet) {
+            Some(payload) => payload,
+            None => return,
+        };
+        let continues = self.sections[i].cc.map(|last| last.wrapping_add(1) & 0x0f) == Some(cc);
+
+        if payload_unit_start {
+            let pointer = payload[0] as usize;
+            let payload = &payload[1..];
+            if pointer > payload.len() {
+                self.sections[i].reset();
+                return;
+            }
+            let (tail, head) = payload.split_at(pointer);
+
+            // The bytes before the pointer finish the section of the previous packet.
+            if continues {
+                self.append(i, tail, target);
+            }
+            self.sections[i].reset();
+            self.sections[i].cc = Some(cc);
+            self.append(i, head, target);
+        } else {
+            if !continues {
+                // Discontinuity, or nothing to continue; the section under reassembly is broken.
+                self.sections[i].reset();
+                return;
+            }
+            self.sections[i].cc = Some(cc);
+            self.append(i, payload, target);
+        }
+    }
+
+    fn append(&mut self, i: usize, data: &[u8], target: &Target) {
+        let section_buf = &mut self.sections[i];
+        if section_buf.buf.len() + data.len() > SECTION_BUF_SIZE {
+            section_buf.reset();
+            return;
+        }
+        section_buf.buf.extend_from_slice(data);
+
+        // A payload may contain several sections.
+        let buf = &mut section_buf.buf;
+        let mut consumed = 0;
+        loop {
+            let rest = &buf[consumed..];
+            // Stuffing bytes follow the last section.
+            if rest.first() == Some(&0xff) {
+                consumed = buf.len();
+                break;
+            }
+            if rest.len() < 3 {
+                break;
+            }
+            let total = 3 + (((rest[1] as usize) & 0x0f) << 8 | rest[2] as usize);
+            if rest.len() < total {
+                break;
+            }
+
+            if let Some((section_number, found)) = parse_eit_pf_section(&rest[..total], target) {
+                self.sections_of_service += 1;
+                match section_number {
+                    0 => self.in_present = Some(found),
+                    1 => self.in_following = Some(found),
+                    _ => {}
+                }
+            }
+            consumed += total;
+        }
+        buf.drain(..consumed);
+    }
+}
+
+// Returns (section_number, whether the target event is listed) if the section is EIT[p/f] of the target service.
+fn parse_eit_pf_section(section: &[u8], target: &Target) -> Option<(u8, bool)> {
+    if section.len() < EIT_HEADER_LENGTH + CRC32_LENGTH || section[0] != TABLE_ID_EIT_PF_ACTUAL {
+        return None;
+    }
+    // section_syntax_indicator
+    if section[1] & 0x80 == 0 {
+        return None;
+    }
+    if crc32(section) != 0 {
+        return None;
+    }
+    // current_next_indicator
+    if section[5] & 0x01 == 0 {
+        return None;
+    }
+
+    let service_id = u16::from_be_bytes([section[3], section[4]]);
+    let section_number = section[6];
+    let original_network_id = u16::from_be_bytes([section[10], section[11]]);
+    if service_id != target.service_id || original_network_id != target.network_id {
+        return None;
+    }
+
+    let end = section.len() - CRC32_LENGTH;
+    let mut pos = EIT_HEADER_LENGTH;
+    while pos + 12 <= end {
+        let event_id = u16::from_be_bytes([section[pos], section[pos + 1]]);
+        if event_id == target.event_id {
+            return Some((section_number, true));
         }
+        let descriptors_loop_length =
+            ((section[pos + 10] as usize) & 0x0f) << 8 | section[pos + 11] as usize;
+        pos += 12 + descriptors_loop_length;
+    }
+    Some((section_number, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ts::testing::{self, eit_event, filler, packetize};
+
+    const NETWORK_ID: u16 = 0x7fe0;
+    const SERVICE_ID: u16 = 0x0400;
+    const EVENT_ID: u16 = 0x1234;
+    const TARGET: Target = Target {
+        network_id: NETWORK_ID,
+        service_id: SERVICE_ID,
+        event_id: EVENT_ID,
+    };
+
+    fn eit_pf(service_id: u16, section_number: u8, events: &[Vec<u8>]) -> Vec<u8> {
+        testing::eit_pf(NETWORK_ID, service_id, section_number, events)
+    }
+
+    fn push(parser: &mut EitParser, stream: &[u8]) -> EitDetected {
+        parser.push_for(stream, &TARGET)
     }
-    pub(super) fn push(&self, buf: &[u8], program: &RecordingTaskDescription) -> EitDetected {
-        todo!()
+
+    #[test]
+    fn finds_event_in_present_and_following() {
+        let mut cc = 0;
+        let following = packetize(
+            0x12,
+            &mut cc,
+            &eit_pf(SERVICE_ID, 1, &[eit_event(EVENT_ID, 20)]),
+        );
+        let mut parser = EitParser::new();
+        assert_eq!(push(&mut parser, &following), EitDetected::FoundInF);
+
+        let present = packetize(
+            0x12,
+            &mut cc,
+            &eit_pf(SERVICE_ID, 0, &[eit_event(EVENT_ID, 20)]),
+        );
+        assert_eq!(push(&mut parser, &present), EitDetected::FoundInP);
+
+        // Replaced with the next event
+        let present = packetize(
+            0x12,
+            &mut cc,
+            &eit_pf(SERVICE_ID, 0, &[eit_event(0x1235, 20)]),
+        );
+        let following = packetize(
+            0x12,
+            &mut cc,
+            &eit_pf(SERVICE_ID, 1, &[eit_event(0x1236, 20)]),
+        );
+        assert_eq!(
+            push(&mut parser, &[present, following].concat()),
+            EitDetected::NotFound
+        );
+    }
+
+    #[test]
+    fn ignores_other_services_and_broken_sections() {
+        let mut cc = 0;
+        let other = packetize(
+            0x12,
+            &mut cc,
+            &eit_pf(0x0401, 0, &[eit_event(EVENT_ID, 20)]),
+        );
+        let mut broken = eit_pf(SERVICE_ID, 0, &[eit_event(EVENT_ID, 20)]);
+        let last = broken.len() - 1;
+        broken[last] ^= 0xff;
+        let broken = packetize(0x12, &mut cc, &broken);
+
+        let mut parser = EitParser::new();
+        assert_eq!(
+            push(&mut parser, &[other, broken].concat()),
+            EitDetected::NotFound
+        );
+        assert_eq!(parser.sections_of_service(), 0);
+    }
+
+    #[test]
+    fn reassembles_sections_across_packets_and_pushes() {
+        // Long enough to span three packets
+        let present = eit_pf(
+            SERVICE_ID,
+            0,
+            &[eit_event(0x1111, 200), eit_event(EVENT_ID, 150)],
+        );
+        let mut cc = 0;
+        let stream = packetize(0x12, &mut cc, &present);
+        assert_eq!(stream.len(), 3 * 188);
+
+        let mut parser = EitParser::new();
+        let mut detected = EitDetected::NotFound;
+        for chunk in stream.chunks(100) {
+            detected = push(&mut parser, chunk);
+        }
+        assert_eq!(detected, EitDetected::FoundInP);
+    }
+
+    #[test]
+    fn reassembles_sections_interleaved_between_pids() {
+        // One-seg EIT on 0x27 is sent between the packets of the EIT on 0x12.
+        let present = packetize(
+            0x12,
+            &mut 0,
+            &eit_pf(
+                SERVICE_ID,
+                0,
+                &[eit_event(0x1111, 200), eit_event(EVENT_ID, 150)],
+            ),
+        );
+        let one_seg = packetize(0x27, &mut 0, &eit_pf(0x0580, 0, &[eit_event(0x2222, 250)]));
+        let (present, one_seg) = (
+            present.chunks(188).collect::<Vec<_>>(),
+            one_seg.chunks(188).collect::<Vec<_>>(),
+        );
+        let mut stream = Vec::new();
+        for i in 0..present.len().max(one_seg.len()) {
+            stream.extend(present.get(i).copied().unwrap_or_default());
+            stream.extend(one_seg.get(i).copied().unwrap_or_default());
+        }
+
+        let mut parser = EitParser::new();
+        assert_eq!(push(&mut parser, &stream), EitDetected::FoundInP);
+    }
+
+    #[test]
+    fn drops_section_with_missing_packet() {
+        let mut cc = 0;
+        let present = packetize(
+            0x12,
+            &mut cc,
+            &eit_pf(
+                SERVICE_ID,
+                0,
+                &[eit_event(0x1111, 200), eit_event(EVENT_ID, 150)],
+            ),
+        );
+        // The second packet is lost.
+        let stream = [&present[..188], &present[2 * 188..]].concat();
+
+        let mut parser = EitParser::new();
+        assert_eq!(push(&mut parser, &stream), EitDetected::NotFound);
+        assert_eq!(parser.stats().drops, 1);
+    }
+
+    // The fixtures are laid out as BS muxers send them, by tests/fixtures/gen_eit_pf.py.
+    const FIXTURE_TARGET: Target = Target {
+        network_id: 0x0004,
+        service_id: 0x0065,
+        event_id: 0x3a5c,
+    };
+
+    #[test]
+    fn fixture_with_event_in_present() {
+        // EIT[schedule] before the present section, whose middle packet is sent twice
+        let stream = include_bytes!("../../../tests/fixtures/eit_pf_present.ts");
+        let mut parser = EitParser::new();
+        assert_eq!(
+            parser.push_for(stream, &FIXTURE_TARGET),
+            EitDetected::FoundInP
+        );
+        assert_eq!(parser.sections_of_service(), 2);
+        let stats = parser.stats();
+        assert_eq!((stats.packets, stats.drops, stats.errors), (14, 0, 0));
+
+        let mut parser = EitParser::new();
+        let mut detected = EitDetected::NotFound;
+        for chunk in stream.chunks(100) {
+            detected = parser.push_for(chunk, &FIXTURE_TARGET);
+        }
+        assert_eq!(detected, EitDetected::FoundInP);
+    }
+
+    #[test]
+    fn fixture_with_event_in_following() {
+        // Starts with the tail of a section of another service
+        let stream = include_bytes!("../../../tests/fixtures/eit_pf_following.ts");
+        let mut parser = EitParser::new();
+        assert_eq!(
+            parser.push_for(stream, &FIXTURE_TARGET),
+            EitDetected::FoundInF
+        );
+        assert_eq!(parser.sections_of_service(), 2);
+    }
+
+    #[test]
+    fn fixture_of_other_services() {
+        // The same event in another service, in EIT[p/f] other, in another network and in EIT[schedule]
+        let stream = include_bytes!("../../../tests/fixtures/eit_pf_other_services.ts");
+        let mut parser = EitParser::new();
+        assert_eq!(
+            parser.push_for(stream, &FIXTURE_TARGET),
+            EitDetected::NotFound
+        );
+        assert_eq!(parser.sections_of_service(), 0);
+    }
+
+    #[test]
+    fn counts_drops_and_errors() {
+        let mut cc = 0;
+        let mut stream = [filler(0x100, &mut cc), filler(0x100, &mut cc)].concat();
+        // Skips a counter
+        cc += 1;
+        stream.extend(filler(0x100, &mut cc));
+        let mut broken = filler(0x100, &mut cc);
+        // transport_error_indicator
+        broken[1] |= 0x80;
+        stream.extend(broken);
+
+        let mut parser = EitParser::new();
+        push(&mut parser, &stream);
+        let stats = parser.stats();
+        assert_eq!((stats.packets, stats.drops, stats.errors), (4, 1, 1));
     }
 }
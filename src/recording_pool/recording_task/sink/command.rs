@@ -0,0 +1,103 @@
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::{Context, Poll};
+
+use log::warn;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::process::{Child, ChildStdin, Command};
+use tokio::task::JoinHandle;
+
+use super::Sink;
+
+type Closing = Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>;
+
+/// External filter which reads the stream from stdin and writes the result to stdout.
+pub(super) struct CommandStage {
+    name: String,
+    child: Option<Child>,
+    stdin: Option<ChildStdin>,
+    // Copies stdout to the next stage, and shuts it down at EOF.
+    pump: Option<JoinHandle<Result<(), Error>>>,
+    closing: Option<Closing>,
+}
+
+impl CommandStage {
+    pub(super) fn spawn(program: &Path, args: &[String]) -> Result<Self, Error> {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        Ok(Self {
+            name: program.display().to_string(),
+            stdin: child.stdin.take(),
+            child: Some(child),
+            pump: None,
+            closing: None,
+        })
+    }
+
+    pub(super) fn pipe_to(mut self, mut next: Sink) -> Self {
+        if let Some(mut stdout) = self.child.as_mut().and_then(|c| c.stdout.take()) {
+            self.pump = Some(tokio::spawn(async move {
+                tokio::io::copy(&mut stdout, &mut next).await?;
+                next.shutdown().await
+            }));
+        }
+        self
+    }
+
+    fn stdin(&mut self) -> Result<&mut ChildStdin, Error> {
+        self.stdin
+            .as_mut()
+            .ok_or_else(|| Error::new(ErrorKind::BrokenPipe, "The stdin is closed."))
+    }
+}
+
+impl AsyncWrite for CommandStage {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        Pin::new(self.get_mut().stdin()?).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(self.get_mut().stdin()?).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let me = self.get_mut();
+        if me.closing.is_none() {
+            let (name, stdin, child, pump) = (
+                me.name.clone(),
+                me.stdin.take(),
+                me.child.take(),
+                me.pump.take(),
+            );
+            me.closing = Some(Box::pin(async move {
+                // EOF lets the program write out the rest and exit.
+                if let Some(mut stdin) = stdin {
+                    stdin.shutdown().await?;
+                }
+                // Otherwise the child is killed on drop, and the end of the file may be lost.
+                if let Some(mut child) = child {
+                    let status = child.wait().await?;
+                    if !status.success() {
+                        warn!("{} exited with {}.", name, status);
+                    }
+                }
+                match pump {
+                    Some(pump) => pump.await.map_err(|e| Error::new(ErrorKind::Other, e))?,
+                    None => Ok(()),
+                }
+            }));
+        }
+        me.closing.as_mut().unwrap().as_mut().poll(cx)
+    }
+}
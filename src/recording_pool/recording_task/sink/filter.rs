@@ -0,0 +1,106 @@
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_util::ready;
+use tokio::io::AsyncWrite;
+
+use super::Sink;
+use crate::ts::{pid_of, Packets, PID_COUNT};
+
+/// In-process processing of whole TS packets
+pub(super) trait PacketFilter: Send + Unpin {
+    /// Appends what is passed on for the packet to `out`.
+    fn filter(&mut self, packet: &[u8], out: &mut Vec<u8>);
+}
+
+pub(super) struct DropPids {
+    drop: [bool; PID_COUNT],
+}
+
+impl DropPids {
+    pub(super) fn new(pids: &[u16]) -> Self {
+        let mut drop = [false; PID_COUNT];
+        for pid in pids {
+            drop[*pid as usize & 0x1fff] = true;
+        }
+        Self { drop }
+    }
+}
+
+impl PacketFilter for DropPids {
+    fn filter(&mut self, packet: &[u8], out: &mut Vec<u8>) {
+        if !self.drop[pid_of(packet) as usize] {
+            out.extend_from_slice(packet);
+        }
+    }
+}
+
+/// Runs a PacketFilter in the pipeline. The output of a write is passed on before the next write is accepted.
+pub(super) struct FilterStage<F> {
+    filter: F,
+    packets: Packets,
+    out: Vec<u8>,
+    // Bytes of `out` which have been passed on
+    written: usize,
+    next: Sink,
+}
+
+impl<F: PacketFilter> FilterStage<F> {
+    pub(super) fn new(filter: F, next: Sink) -> Self {
+        Self {
+            filter,
+            packets: Packets::new(),
+            out: Vec::new(),
+            written: 0,
+            next,
+        }
+    }
+
+    fn process(&mut self, buf: &[u8]) {
+        let (filter, out) = (&mut self.filter, &mut self.out);
+        self.packets
+            .push(buf, |packet, _| filter.filter(packet, out));
+    }
+
+    fn poll_pass_on(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        while self.written < self.out.len() {
+            let n = ready!(Pin::new(&mut self.next).poll_write(cx, &self.out[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(Error::new(
+                    ErrorKind::WriteZero,
+                    "The next stage is closed.",
+                )));
+            }
+            self.written += n;
+        }
+        self.out.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<F: PacketFilter> AsyncWrite for FilterStage<F> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        let me = self.get_mut();
+        ready!(me.poll_pass_on(cx))?;
+        me.process(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let me = self.get_mut();
+        ready!(me.poll_pass_on(cx))?;
+        Pin::new(&mut me.next).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let me = self.get_mut();
+        ready!(me.poll_pass_on(cx))?;
+        Pin::new(&mut me.next).poll_shutdown(cx)
+    }
+}
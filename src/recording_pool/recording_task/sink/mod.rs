@@ -0,0 +1,258 @@
+//! Output pipeline of recordings. A profile lists the stages the stream passes through, and the last stage
+//! writes the file (or discards the stream). Schedules and plans choose a profile by name.
+
+use std::io::Error;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncWrite, BufWriter};
+
+use crate::config::{get_config, ProfileConfig, StageConfig};
+
+mod command;
+mod filter;
+mod service;
+mod tee;
+
+pub(crate) const DEFAULT_PROFILE: &str = "default";
+
+/// A stage of the pipeline. Each stage writes what it passes on to the next one,
+/// and shutting it down shuts down the following stages.
+pub(crate) trait Stage: AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncWrite + Send + Unpin + ?Sized> Stage for T {}
+
+pub(crate) type Sink = Box<dyn Stage>;
+
+// Used unless `recording.profiles` has "default".
+fn builtin_default() -> ProfileConfig {
+    let tsreadex = StageConfig::Command {
+        program: get_config().recording.tsreadex_path.clone(),
+        // tsreadex reads the stream from stdin ("-") and writes the result to stdout.
+        args: vec![
+            // 取り除く TS パケットの10進数の PID
+            // EIT の PID を指定
+            "-x", "18/38/39",
+            // 特定サービスのみを選択して出力するフィルタを有効にする
+            // 有効にすると、特定のストリームのみ PID を固定して出力される
+            "-n", "-1",
+            // 主音声ストリームが常に存在する状態にする
+            // ストリームが存在しない場合、無音の AAC ストリームが出力される
+            // 音声がモノラルであればステレオにする
+            // デュアルモノを2つのモノラル音声に分離し、右チャンネルを副音声として扱う
+            "-a", "13",
+            // 副音声ストリームが常に存在する状態にする
+            // ストリームが存在しない場合、無音の AAC ストリームが出力される
+            // 音声がモノラルであればステレオにする
+            "-b", "5",
+            // 字幕ストリームが常に存在する状態にする
+            // ストリームが存在しない場合、PMT の項目が補われて出力される
+            "-c", "1",
+            // 文字スーパーストリームが常に存在する状態にする
+            // ストリームが存在しない場合、PMT の項目が補われて出力される
+            "-u", "1",
+            // 字幕と文字スーパーを aribb24.js が解釈できる ID3 timed-metadata に変換する
+            // +4: FFmpeg のバグを打ち消すため、変換後のストリームに規格外の5バイトのデータを追加する
+            // +8: FFmpeg のエラーを防ぐため、変換後のストリームの PTS が単調増加となるように調整する
+            "-d", "13", "-",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect(),
+        // The stream filtered by the service stage is recorded if tsreadex is not installed.
+        optional: true,
+    };
+    // tsreadex is given the service alone, so that the output is the same without tsreadex.
+    let service = StageConfig::Service {
+        service_id: None,
+        drop_pids: vec![0x12, 0x26, 0x27],
+    };
+    ProfileConfig {
+        name: DEFAULT_PROFILE.to_string(),
+        stages: vec![service, tsreadex, StageConfig::File],
+    }
+}
+
+/// Looks up the profile. None means the default one.
+pub(crate) fn find_profile(name: Option<&str>) -> Option<ProfileConfig> {
+    let name = name.unwrap_or(DEFAULT_PROFILE);
+    get_config()
+        .recording
+        .profiles
+        .iter()
+        .find(|p| p.name == name)
+        .cloned()
+        .or_else(|| (name == DEFAULT_PROFILE).then(builtin_default))
+}
+
+/// For the API. Profiles are checked when schedules and plans are saved.
+pub(crate) fn check_profile(name: Option<&str>) -> Result<(), String> {
+    match find_profile(name) {
+        Some(_) => Ok(()),
+        None => Err(format!(
+            "Profile {:?} is not found.",
+            name.unwrap_or_default()
+        )),
+    }
+}
+
+/// Files the stages of the profile write next to the output, i.e. the digest and the copies
+pub(crate) fn artifacts(profile: &ProfileConfig, output: &Path) -> Vec<PathBuf> {
+    profile
+        .stages
+        .iter()
+        .filter_map(|stage| match stage {
+            StageConfig::Checksum => Some(checksum_of(output)),
+            StageConfig::Tee { extension } => Some(copy_of(output, extension)),
+            _ => None,
+        })
+        .collect()
+}
+
+fn checksum_of(output: &Path) -> PathBuf {
+    let mut name = output.file_name().unwrap_or_default().to_os_string();
+    name.push(".sha256");
+    output.with_file_name(name)
+}
+
+// The temporary output is copied to a temporary copy, so that each output has its own one.
+fn copy_of(output: &Path, extension: &str) -> PathBuf {
+    match output.extension() {
+        Some(ext) if ext.to_string_lossy().ends_with("-tmp") => {
+            output.with_extension(format!("{}-tmp", extension))
+        }
+        _ => output.with_extension(extension),
+    }
+}
+
+/// Opens the stages from the last one, so that each of them is given the next one.
+/// `service_id` is the service of the program, extracted by `service` stages.
+pub(crate) async fn open(
+    profile: &ProfileConfig,
+    output: &Path,
+    service_id: u16,
+) -> Result<Sink, Error> {
+    info!("Saving stream at: {:?} ({})", output, profile.name);
+
+    let mut next: Option<Sink> = None;
+    for stage in profile.stages.iter().rev() {
+        let opened: Sink = match (stage, next.take()) {
+            (StageConfig::File, _) => Box::new(BufWriter::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(output)
+                    .await?,
+            )),
+            (StageConfig::Null, _) => Box::new(tokio::io::sink()),
+            (
+                StageConfig::Command {
+                    program,
+                    args,
+                    optional,
+                },
+                Some(next),
+            ) => match command::CommandStage::spawn(program, args) {
+                Ok(stage) => Box::new(stage.pipe_to(next)),
+                Err(e) if *optional => {
+                    warn!("{} is skipped. {}", program.display(), e);
+                    next
+                }
+                Err(e) => return Err(e),
+            },
+            (StageConfig::DropPids { pids }, Some(next)) => {
+                Box::new(filter::FilterStage::new(filter::DropPids::new(pids), next))
+            }
+            (
+                StageConfig::Service {
+                    service_id: id,
+                    drop_pids,
+                },
+                Some(next),
+            ) => Box::new(filter::FilterStage::new(
+                service::ServiceFilter::new(id.unwrap_or(service_id), drop_pids),
+                next,
+            )),
+            (StageConfig::Checksum, Some(next)) => {
+                Box::new(tee::Checksum::new(output, checksum_of(output), next))
+            }
+            (StageConfig::Tee { extension }, Some(next)) => {
+                Box::new(tee::Tee::open(&copy_of(output, extension), next).await?)
+            }
+            // Rejected by the validation of the config
+            (_, None) => unreachable!("The last stage of {} is not a file.", profile.name),
+        };
+        next = Some(opened);
+    }
+    next.ok_or_else(|| Error::new(std::io::ErrorKind::InvalidInput, "No stage is given."))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn each_output_has_own_copy() {
+        let dir = test_utils::temp_dir("sink");
+        let profile = ProfileConfig {
+            name: "copy".to_string(),
+            stages: vec![
+                StageConfig::Tee {
+                    extension: "raw.m2ts".to_string(),
+                },
+                StageConfig::Checksum,
+                StageConfig::File,
+            ],
+        };
+        // The pre-roll, then the program
+        for (output, data) in [("X.m2ts-tmp", b"pre-roll"), ("X.m2ts", b"program!")] {
+            let mut sink = open(&profile, &dir.join(output), 0x0400).await.unwrap();
+            sink.write_all(data).await.unwrap();
+            sink.shutdown().await.unwrap();
+        }
+
+        let read = |name: &str| std::fs::read(dir.join(name)).unwrap();
+        assert_eq!(read("X.m2ts-tmp"), b"pre-roll");
+        assert_eq!(read("X.raw.m2ts-tmp"), b"pre-roll");
+        assert_eq!(read("X.m2ts"), b"program!");
+        assert_eq!(read("X.raw.m2ts"), b"program!");
+        assert_eq!(
+            artifacts(&profile, &dir.join("X.m2ts-tmp")),
+            vec![dir.join("X.raw.m2ts-tmp"), dir.join("X.m2ts-tmp.sha256")]
+        );
+        assert_eq!(
+            artifacts(&profile, &dir.join("X.m2ts")),
+            vec![dir.join("X.raw.m2ts"), dir.join("X.m2ts.sha256")]
+        );
+        for artifact in artifacts(&profile, &dir.join("X.m2ts")) {
+            assert!(artifact.exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_command_to_exit() {
+        let dir = test_utils::temp_dir("sink");
+        let profile = ProfileConfig {
+            name: "command".to_string(),
+            stages: vec![
+                // Writes a trailer a while after the input has ended, as tsreadex flushes what it holds.
+                StageConfig::Command {
+                    program: PathBuf::from("sh"),
+                    args: vec!["-c".to_string(), "cat; sleep 1; printf trailer".to_string()],
+                    optional: false,
+                },
+                StageConfig::File,
+            ],
+        };
+        let output = dir.join("X.m2ts");
+        let mut sink = open(&profile, &output, 0x0400).await.unwrap();
+        sink.write_all(b"stream ").await.unwrap();
+        sink.shutdown().await.unwrap();
+
+        assert_eq!(std::fs::read(&output).unwrap(), b"stream trailer");
+    }
+}
@@ -0,0 +1,404 @@
+//! Native replacement of `tsreadex -x ... -n -1`. Only the PIDs of a single service are kept,
+//! and the PAT is rewritten so that it lists the service alone.
+
+use super::filter::PacketFilter;
+use crate::ts::{
+    crc32, payload_of, payload_unit_start, pid_of, seal_section, NULL_PID, PAT_PID, PID_COUNT,
+    TABLE_ID_PAT, TABLE_ID_PMT, TS_PACKET_SIZE,
+};
+
+// PSI and SI of ARIB STD-B10 (e.g. NIT, SDT, EIT, TOT) are on the PIDs below this.
+const SI_PID_END: u16 = 0x30;
+const CA_DESCRIPTOR: u8 = 0x09;
+const MAX_SECTION_LENGTH: usize = 1024;
+
+// Reassembles a section over packets. Sections are expected to start at the beginning of a packet,
+// which is how PAT and PMT are sent.
+#[derive(Default)]
+struct SectionBuf {
+    buf: Vec<u8>,
+}
+
+impl SectionBuf {
+    // Returns the section once it is complete and its CRC_32 is correct.
+    fn push(&mut self, payload: &[u8], payload_unit_start: bool) -> Option<Vec<u8>> {
+        if payload_unit_start {
+            let pointer = *payload.first()? as usize;
+            self.buf.clear();
+            self.buf.extend_from_slice(payload.get(1 + pointer..)?);
+        } else if !self.buf.is_empty() {
+            self.buf.extend_from_slice(payload);
+        }
+        if self.buf.len() < 3 {
+            return None;
+        }
+        let len = 3 + (((self.buf[1] as usize & 0x0f) << 8) | self.buf[2] as usize);
+        if len > MAX_SECTION_LENGTH {
+            self.buf.clear();
+            return None;
+        }
+        if self.buf.len() < len {
+            return None;
+        }
+        let section = self.buf[..len].to_vec();
+        self.buf.clear();
+        (crc32(&section) == 0).then_some(section)
+    }
+}
+
+pub(super) struct ServiceFilter {
+    // The first service in the PAT is taken if it isn't there.
+    service_id: u16,
+    drop: Box<[bool; PID_COUNT]>,
+    pat: SectionBuf,
+    pmt: SectionBuf,
+    // (program_number, PID of the PMT) of the service
+    pmt_pid: Option<(u16, u16)>,
+    // PIDs listed in the PMT, including PCR and ECM
+    keep: Box<[bool; PID_COUNT]>,
+    pat_cc: u8,
+}
+
+impl ServiceFilter {
+    pub(super) fn new(service_id: u16, drop_pids: &[u16]) -> Self {
+        let mut drop = Box::new([false; PID_COUNT]);
+        for pid in drop_pids {
+            drop[*pid as usize & 0x1fff] = true;
+        }
+        Self {
+            service_id,
+            drop,
+            pat: SectionBuf::default(),
+            pmt: SectionBuf::default(),
+            pmt_pid: None,
+            keep: Box::new([false; PID_COUNT]),
+            pat_cc: 0,
+        }
+    }
+
+    // Returns the rewritten PAT.
+    fn on_pat(&mut self, section: &[u8]) -> Option<[u8; TS_PACKET_SIZE]> {
+        if section[0] != TABLE_ID_PAT || section.len() < 12 {
+            return None;
+        }
+        let programs = section[8..section.len() - 4]
+            .chunks_exact(4)
+            .map(|p| {
+                let program_number = ((p[0] as u16) << 8) | p[1] as u16;
+                (program_number, ((p[2] as u16 & 0x1f) << 8) | p[3] as u16)
+            })
+            // 0 is for the NIT.
+            .filter(|(program_number, _)| *program_number != 0)
+            .collect::<Vec<_>>();
+        let (program_number, pmt_pid) = programs
+            .iter()
+            .find(|(n, _)| *n == self.service_id)
+            .or_else(|| programs.first())
+            .copied()?;
+        if self.pmt_pid != Some((program_number, pmt_pid)) {
+            self.pmt_pid = Some((program_number, pmt_pid));
+            self.pmt = SectionBuf::default();
+            self.keep.fill(false);
+        }
+
+        let mut pat = vec![
+            TABLE_ID_PAT,
+            // section_syntax_indicator. section_length is set by seal_section.
+            0xb0,
+            0x00,
+            // transport_stream_id, version_number and current_next_indicator as they are
+            section[3],
+            section[4],
+            section[5],
+            // section_number, last_section_number
+            0x00,
+            0x00,
+            (program_number >> 8) as u8,
+            program_number as u8,
+            0xe0 | (pmt_pid >> 8) as u8,
+            pmt_pid as u8,
+        ];
+        seal_section(&mut pat);
+
+        let mut packet = [0xff; TS_PACKET_SIZE];
+        packet[..5].copy_from_slice(&[0x47, 0x40, 0x00, 0x10 | self.pat_cc, 0x00]);
+        packet[5..5 + pat.len()].copy_from_slice(&pat);
+        self.pat_cc = (self.pat_cc + 1) & 0x0f;
+        Some(packet)
+    }
+
+    fn on_pmt(&mut self, program_number: u16, section: &[u8]) {
+        // Services may share the PID of their PMT (e.g. one-seg).
+        if section[0] != TABLE_ID_PMT
+            || section.len() < 16
+            || ((section[3] as u16) << 8) | section[4] as u16 != program_number
+        {
+            return;
+        }
+        let end = section.len() - 4;
+        let pcr_pid = ((section[8] as usize & 0x1f) << 8) | section[9] as usize;
+        let program_info_length = ((section[10] as usize & 0x0f) << 8) | section[11] as usize;
+
+        self.keep.fill(false);
+        self.keep[pcr_pid] = true;
+        let mut i = 12;
+        if let Some(descriptors) = section.get(i..(i + program_info_length).min(end)) {
+            self.keep_ecm(descriptors);
+        }
+        i += program_info_length;
+        while i + 5 <= end {
+            let pid = ((section[i + 1] as usize & 0x1f) << 8) | section[i + 2] as usize;
+            let es_info_length = ((section[i + 3] as usize & 0x0f) << 8) | section[i + 4] as usize;
+            self.keep[pid] = true;
+            if let Some(descriptors) = section.get(i + 5..(i + 5 + es_info_length).min(end)) {
+                self.keep_ecm(descriptors);
+            }
+            i += 5 + es_info_length;
+        }
+    }
+
+    fn keep_ecm(&mut self, mut descriptors: &[u8]) {
+        while descriptors.len() >= 2 {
+            let (tag, len) = (descriptors[0], descriptors[1] as usize);
+            let body = match descriptors.get(2..2 + len) {
+                Some(body) => body,
+                None => return,
+            };
+            if tag == CA_DESCRIPTOR && body.len() >= 4 {
+                self.keep[((body[2] as usize & 0x1f) << 8) | body[3] as usize] = true;
+            }
+            descriptors = &descriptors[2 + len..];
+        }
+    }
+}
+
+impl PacketFilter for ServiceFilter {
+    fn filter(&mut self, packet: &[u8], out: &mut Vec<u8>) {
+        let pid = pid_of(packet);
+        let payload_unit_start = payload_unit_start(packet);
+        // transport_error_indicator
+        let payload = match packet[1] & 0x80 {
+            0 => payload_of(packet),
+            _ => None,
+        };
+
+        if pid == PAT_PID {
+            // Only the rewritten one is passed on.
+            let section = payload.and_then(|p| self.pat.push(p, payload_unit_start));
+            if let Some(pat) = section.and_then(|s| self.on_pat(&s)) {
+                out.extend_from_slice(&pat);
+            }
+            return;
+        }
+        if let Some((program_number, _)) = self.pmt_pid.filter(|(_, pmt_pid)| *pmt_pid == pid) {
+            if let Some(section) = payload.and_then(|p| self.pmt.push(p, payload_unit_start)) {
+                self.on_pmt(program_number, &section);
+            }
+            out.extend_from_slice(packet);
+            return;
+        }
+        let is_si = pid < SI_PID_END;
+        if pid != NULL_PID && !self.drop[pid as usize] && (is_si || self.keep[pid as usize]) {
+            out.extend_from_slice(packet);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ts::testing::{filler, packetize, pat, pids, pmt};
+
+    const EIT_PIDS: [u16; 3] = [0x12, 0x26, 0x27];
+    // H.264 and AAC
+    const VIDEO: u8 = 0x1b;
+    const AUDIO: u8 = 0x0f;
+
+    fn run(filter: &mut ServiceFilter, stream: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for packet in stream.chunks(TS_PACKET_SIZE) {
+            filter.filter(packet, &mut out);
+        }
+        out
+    }
+
+    // (program_number, PID of the PMT) listed in the PAT of the output
+    fn programs_in_pat(out: &[u8]) -> Vec<(u16, u16)> {
+        let packet = out
+            .chunks(TS_PACKET_SIZE)
+            .find(|p| pid_of(p) == PAT_PID)
+            .expect("The PAT is passed on.");
+        let section = &payload_of(packet).unwrap()[1..];
+        let len = 3 + (((section[1] as usize & 0x0f) << 8) | section[2] as usize);
+        assert_eq!(crc32(&section[..len]), 0);
+        section[8..len - 4]
+            .chunks_exact(4)
+            .map(|p| {
+                (
+                    ((p[0] as u16) << 8) | p[1] as u16,
+                    ((p[2] as u16 & 0x1f) << 8) | p[3] as u16,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn extracts_service_of_multi_program_pat() {
+        let mut cc = [0u8; 8];
+        let stream = [
+            packetize(
+                PAT_PID,
+                &mut cc[0],
+                &pat(&[(0, 0x10), (0x0400, 0x1f0), (0x0401, 0x1f1)]),
+            ),
+            packetize(
+                0x1f0,
+                &mut cc[1],
+                &pmt(
+                    0x0400,
+                    0x100,
+                    &[],
+                    &[(VIDEO, 0x100, &[]), (AUDIO, 0x110, &[])],
+                ),
+            ),
+            packetize(
+                0x1f1,
+                &mut cc[2],
+                &pmt(
+                    0x0401,
+                    0x101,
+                    &[],
+                    &[(VIDEO, 0x101, &[]), (AUDIO, 0x111, &[])],
+                ),
+            ),
+            filler(0x100, &mut cc[3]),
+            filler(0x101, &mut cc[4]),
+            filler(0x110, &mut cc[5]),
+            filler(0x111, &mut cc[6]),
+        ]
+        .concat();
+
+        let out = run(&mut ServiceFilter::new(0x0401, &EIT_PIDS), &stream);
+        assert_eq!(programs_in_pat(&out), [(0x0401, 0x1f1)]);
+        assert_eq!(pids(&out), [PAT_PID, 0x1f1, 0x101, 0x111]);
+    }
+
+    #[test]
+    fn takes_first_service_if_not_listed() {
+        let mut cc = [0u8; 4];
+        let stream = [
+            packetize(
+                PAT_PID,
+                &mut cc[0],
+                &pat(&[(0x0400, 0x1f0), (0x0401, 0x1f1)]),
+            ),
+            packetize(
+                0x1f0,
+                &mut cc[1],
+                &pmt(0x0400, 0x100, &[], &[(VIDEO, 0x100, &[])]),
+            ),
+            filler(0x100, &mut cc[2]),
+        ]
+        .concat();
+
+        let out = run(&mut ServiceFilter::new(0x0999, &EIT_PIDS), &stream);
+        assert_eq!(programs_in_pat(&out), [(0x0400, 0x1f0)]);
+        assert_eq!(pids(&out), [PAT_PID, 0x1f0, 0x100]);
+    }
+
+    #[test]
+    fn picks_pmt_of_service_from_shared_pid() {
+        // e.g. one-seg, whose services send their PMT on the same PID
+        let mut cc = [0u8; 4];
+        let stream = [
+            packetize(
+                PAT_PID,
+                &mut cc[0],
+                &pat(&[(0x0400, 0x1fc8), (0x0401, 0x1fc8)]),
+            ),
+            packetize(
+                0x1fc8,
+                &mut cc[1],
+                &pmt(0x0400, 0x100, &[], &[(VIDEO, 0x100, &[])]),
+            ),
+            packetize(
+                0x1fc8,
+                &mut cc[1],
+                &pmt(0x0401, 0x101, &[], &[(VIDEO, 0x101, &[])]),
+            ),
+            // The PMT of the other service must not replace the PIDs.
+            packetize(
+                0x1fc8,
+                &mut cc[1],
+                &pmt(0x0400, 0x100, &[], &[(VIDEO, 0x100, &[])]),
+            ),
+            filler(0x100, &mut cc[2]),
+            filler(0x101, &mut cc[3]),
+        ]
+        .concat();
+
+        let out = run(&mut ServiceFilter::new(0x0401, &EIT_PIDS), &stream);
+        assert_eq!(programs_in_pat(&out), [(0x0401, 0x1fc8)]);
+        assert_eq!(pids(&out), [PAT_PID, 0x1fc8, 0x1fc8, 0x1fc8, 0x101]);
+    }
+
+    #[test]
+    fn keeps_ecm_of_ca_descriptors() {
+        // CA_system_id 0x0005, CA_PID
+        let ca = |pid: u16| {
+            [
+                CA_DESCRIPTOR,
+                4,
+                0x00,
+                0x05,
+                0xe0 | (pid >> 8) as u8,
+                pid as u8,
+            ]
+        };
+        let mut cc = [0u8; 6];
+        let stream = [
+            packetize(PAT_PID, &mut cc[0], &pat(&[(0x0400, 0x1f0)])),
+            packetize(
+                0x1f0,
+                &mut cc[1],
+                &pmt(
+                    0x0400,
+                    0x100,
+                    &ca(0x130),
+                    &[(VIDEO, 0x100, &ca(0x131)), (AUDIO, 0x110, &[])],
+                ),
+            ),
+            filler(0x130, &mut cc[2]),
+            filler(0x131, &mut cc[3]),
+            filler(0x132, &mut cc[4]),
+        ]
+        .concat();
+
+        let out = run(&mut ServiceFilter::new(0x0400, &EIT_PIDS), &stream);
+        assert_eq!(pids(&out), [PAT_PID, 0x1f0, 0x130, 0x131]);
+    }
+
+    #[test]
+    fn drops_eit_and_null_but_keeps_other_si() {
+        let mut cc = [0u8; 8];
+        let stream = [
+            packetize(PAT_PID, &mut cc[0], &pat(&[(0x0400, 0x1f0)])),
+            packetize(
+                0x1f0,
+                &mut cc[1],
+                &pmt(0x0400, 0x100, &[], &[(VIDEO, 0x100, &[])]),
+            ),
+            // NIT, SDT, EIT, TOT
+            filler(0x10, &mut cc[2]),
+            filler(0x11, &mut cc[3]),
+            filler(0x12, &mut cc[4]),
+            filler(0x14, &mut cc[5]),
+            filler(0x27, &mut cc[6]),
+            filler(NULL_PID, &mut cc[7]),
+        ]
+        .concat();
+
+        let out = run(&mut ServiceFilter::new(0x0400, &EIT_PIDS), &stream);
+        assert_eq!(pids(&out), [PAT_PID, 0x1f0, 0x10, 0x11, 0x14]);
+    }
+}
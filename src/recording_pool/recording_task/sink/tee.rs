@@ -0,0 +1,173 @@
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_util::ready;
+use log::{error, info};
+use sha2::{Digest, Sha256};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWrite, BufWriter};
+
+use super::Sink;
+
+type Writing = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Hashes what passes through, and writes it in the format of sha256sum when shut down.
+/// It is placed right before the file, so that the digest matches the file.
+pub(super) struct Checksum {
+    // Taken when the digest is written
+    hasher: Option<Sha256>,
+    // File name of the output, written next to the digest
+    name: String,
+    path: PathBuf,
+    next: Sink,
+    // Writing the digest, once the next stage has been shut down
+    writing: Option<Writing>,
+}
+
+impl Checksum {
+    pub(super) fn new(output: &Path, path: PathBuf, next: Sink) -> Self {
+        Self {
+            hasher: Some(Sha256::new()),
+            name: output
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+            path,
+            next,
+            writing: None,
+        }
+    }
+
+    // None if the digest has already been written.
+    fn write_digest(&mut self) -> Option<Writing> {
+        let digest = self
+            .hasher
+            .take()?
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        let line = format!("{}  {}\n", digest, self.name);
+        let path = self.path.clone();
+        Some(Box::pin(async move {
+            match tokio::fs::write(&path, line).await {
+                Ok(()) => info!("{} is written.", path.display()),
+                Err(e) => error!("Failed to write {}. {}", path.display(), e),
+            }
+        }))
+    }
+}
+
+impl AsyncWrite for Checksum {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        let me = self.get_mut();
+        let n = ready!(Pin::new(&mut me.next).poll_write(cx, buf))?;
+        if let Some(hasher) = me.hasher.as_mut() {
+            hasher.update(&buf[..n]);
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.get_mut().next).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let me = self.get_mut();
+        if me.writing.is_none() {
+            ready!(Pin::new(&mut me.next).poll_shutdown(cx))?;
+            me.writing = me.write_digest();
+        }
+        if let Some(writing) = me.writing.as_mut() {
+            ready!(writing.as_mut().poll(cx));
+            me.writing = None;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Copies what passes through to another file.
+/// Each write is held until both of the copy and the next stage have taken it.
+pub(super) struct Tee {
+    copy: BufWriter<File>,
+    next: Sink,
+    pending: Vec<u8>,
+    // Bytes of `pending` taken by each side
+    to_copy: usize,
+    to_next: usize,
+}
+
+impl Tee {
+    pub(super) async fn open(path: &Path, next: Sink) -> Result<Self, Error> {
+        info!("Copying stream to: {:?}", path);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(Self {
+            copy: BufWriter::new(file),
+            next,
+            pending: Vec::new(),
+            to_copy: 0,
+            to_next: 0,
+        })
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        while self.to_copy < self.pending.len() {
+            let n = ready!(Pin::new(&mut self.copy).poll_write(cx, &self.pending[self.to_copy..]))?;
+            self.to_copy += check_written(n)?;
+        }
+        while self.to_next < self.pending.len() {
+            let n = ready!(Pin::new(&mut self.next).poll_write(cx, &self.pending[self.to_next..]))?;
+            self.to_next += check_written(n)?;
+        }
+        self.pending.clear();
+        self.to_copy = 0;
+        self.to_next = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn check_written(n: usize) -> Result<usize, Error> {
+    match n {
+        0 => Err(Error::new(ErrorKind::WriteZero, "The output is closed.")),
+        n => Ok(n),
+    }
+}
+
+impl AsyncWrite for Tee {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        let me = self.get_mut();
+        ready!(me.poll_drain(cx))?;
+        me.pending.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let me = self.get_mut();
+        ready!(me.poll_drain(cx))?;
+        ready!(Pin::new(&mut me.copy).poll_flush(cx))?;
+        Pin::new(&mut me.next).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let me = self.get_mut();
+        ready!(me.poll_drain(cx))?;
+        ready!(Pin::new(&mut me.copy).poll_shutdown(cx))?;
+        Pin::new(&mut me.next).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,619 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, RwLock};
+
+use chrono::{DateTime, Local};
+use log::warn;
+use mirakurun_client::models::{ChannelType, Program, Service, TunerDevice};
+use once_cell::sync::Lazy;
+use serde_derive::Serialize;
+use utoipa::ToSchema;
+
+use crate::mirakurun_client::get_channel_of_service;
+use crate::sched_trigger::{expected_end_at, pre_roll, Schedule};
+
+/// Tuners and channels reported by Mirakurun. Updated by EpgSyncManager.
+pub(crate) static TUNER_MODEL: Lazy<RwLock<TunerModel>> =
+    Lazy::new(|| RwLock::new(TunerModel::default()));
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, ToSchema)]
+pub(crate) enum TunerType {
+    GR,
+    BS,
+    CS,
+    SKY,
+}
+
+impl From<ChannelType> for TunerType {
+    fn from(t: ChannelType) -> Self {
+        match t {
+            ChannelType::Gr => TunerType::GR,
+            ChannelType::Bs => TunerType::BS,
+            ChannelType::Cs => TunerType::CS,
+            ChannelType::Sky => TunerType::SKY,
+        }
+    }
+}
+
+/// Programs on the same physical channel share one tuner in Mirakurun.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, ToSchema)]
+pub(crate) struct ChannelKey {
+    pub(crate) r#type: TunerType,
+    pub(crate) channel: String,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct Conflict {
+    // The moment when the number of channels first exceeds what the tuners can receive.
+    pub(crate) at: DateTime<Local>,
+    // All of the programs on air at that moment.
+    pub(crate) program_ids: Vec<i64>,
+    pub(crate) channels: Vec<ChannelKey>,
+}
+
+#[derive(Default)]
+pub(crate) struct TunerModel {
+    // Types each usable tuner can receive. Multi-type tuners (e.g. BS/CS) are common.
+    tuners: Vec<Vec<TunerType>>,
+    // (network_id, service_id) -> channel
+    channels: HashMap<(i64, i64), ChannelKey>,
+    // Services whose channel has been reported unknown, so that the queue checked every few seconds doesn't repeat it.
+    unknown: Mutex<HashSet<(i64, i64)>>,
+}
+
+impl TunerModel {
+    pub(crate) fn update_tuners(&mut self, tuners: &[TunerDevice]) {
+        self.tuners = tuners
+            .iter()
+            .filter(|t| !t.is_fault)
+            .map(|t| t.types.iter().map(|ty| TunerType::from(*ty)).collect())
+            .collect();
+    }
+    pub(crate) fn update_services(&mut self, services: &[Service]) {
+        for s in services {
+            if let Some((r#type, channel)) = get_channel_of_service(s) {
+                let key = (s.network_id as i64, s.service_id as i64);
+                self.unknown.get_mut().unwrap().remove(&key);
+                self.channels.insert(
+                    key,
+                    ChannelKey {
+                        r#type: r#type.into(),
+                        channel,
+                    },
+                );
+            }
+        }
+    }
+    pub(crate) fn channel_of(&self, p: &Program) -> Option<&ChannelKey> {
+        self.channels
+            .get(&(p.network_id as i64, p.service_id as i64))
+    }
+    pub(crate) fn is_ready(&self) -> bool {
+        !self.tuners.is_empty()
+    }
+    // Whether every channel can be assigned to its own tuner. Solved as a bipartite matching.
+    pub(crate) fn can_receive(&self, channels: &[&ChannelKey]) -> bool {
+        if channels.len() > self.tuners.len() {
+            return false;
+        }
+        let mut assigned: Vec<Option<usize>> = vec![None; self.tuners.len()];
+        (0..channels.len()).all(|c| {
+            let mut visited = vec![false; self.tuners.len()];
+            self.try_assign(c, channels, &mut assigned, &mut visited)
+        })
+    }
+    fn try_assign(
+        &self,
+        c: usize,
+        channels: &[&ChannelKey],
+        assigned: &mut Vec<Option<usize>>,
+        visited: &mut Vec<bool>,
+    ) -> bool {
+        for (t, types) in self.tuners.iter().enumerate() {
+            if visited[t] || !types.contains(&channels[c].r#type) {
+                continue;
+            }
+            visited[t] = true;
+            let movable = match assigned[t] {
+                None => true,
+                Some(other) => self.try_assign(other, channels, assigned, visited),
+            };
+            if movable {
+                assigned[t] = Some(c);
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// The span a tuner is occupied by the program, including the pre-roll of sched_trigger.
+pub(crate) fn occupied_span(p: &Program) -> (DateTime<Local>, DateTime<Local>) {
+    let start_at: DateTime<Local> = p.start_at.into();
+    (start_at - pre_roll(), expected_end_at(p))
+}
+
+struct Span<'a> {
+    id: i64,
+    channel: &'a ChannelKey,
+    from: DateTime<Local>,
+    to: DateTime<Local>,
+}
+
+impl<'a> Span<'a> {
+    fn overlaps(&self, other: &Span) -> bool {
+        self.from < other.to && other.from < self.to
+    }
+}
+
+fn span_of<'a>(s: &Schedule, model: &'a TunerModel) -> Option<Span<'a>> {
+    match model.channel_of(&s.program) {
+        Some(channel) => {
+            let (from, to) = occupied_span(&s.program);
+            Some(Span {
+                id: s.program.id,
+                channel,
+                from,
+                to,
+            })
+        }
+        None => {
+            let key = (s.program.network_id as i64, s.program.service_id as i64);
+            if model.unknown.lock().unwrap().insert(key) {
+                warn!(
+                    "The channel of program {} (service_id={}) is unknown. Its programs are excluded from conflict detection.",
+                    s.program.id, s.program.service_id
+                );
+            }
+            None
+        }
+    }
+}
+
+/// Finds the moments when the active schedules need more tuners than available.
+pub(crate) fn detect_conflicts(items: &[Schedule], model: &TunerModel) -> Vec<Conflict> {
+    if !model.is_ready() {
+        return vec![];
+    }
+    let spans = items
+        .iter()
+        .filter(|s| s.is_active)
+        .filter_map(|s| span_of(s, model))
+        .collect::<Vec<_>>();
+    find_conflicts(&spans.iter().collect::<Vec<_>>(), model)
+}
+
+fn find_conflicts(spans: &[&Span], model: &TunerModel) -> Vec<Conflict> {
+    // The set of programs on air only grows at their start.
+    let mut starts = spans.iter().map(|s| s.from).collect::<Vec<_>>();
+    starts.sort();
+    starts.dedup();
+
+    let mut conflicts: Vec<Conflict> = Vec::new();
+    for t in starts {
+        let on_air = spans
+            .iter()
+            .filter(|s| s.from <= t && t < s.to)
+            .collect::<Vec<_>>();
+        let mut channels: Vec<&ChannelKey> = Vec::new();
+        for s in on_air.iter() {
+            if !channels.contains(&s.channel) {
+                channels.push(s.channel);
+            }
+        }
+        if model.can_receive(&channels) {
+            continue;
+        }
+
+        let mut program_ids = on_air.iter().map(|s| s.id).collect::<Vec<_>>();
+        program_ids.sort();
+        if conflicts
+            .last()
+            .map_or(false, |c| c.program_ids == program_ids)
+        {
+            continue;
+        }
+        conflicts.push(Conflict {
+            at: t,
+            program_ids,
+            channels: channels.into_iter().cloned().collect(),
+        });
+    }
+    conflicts
+}
+
+/// Deactivates the schedules that lose tuner conflicts, and reactivates the ones whose conflicts are gone.
+/// Schedules are accepted one by one in the order of priority (higher first), start time and program id,
+/// so the result only depends on the queue. Schedules deactivated by users are left untouched.
+/// Active schedules whose recording has started, i.e. in the pre-roll or on air, are accepted first whatever their
+/// priority, since deactivating them doesn't stop the recording which holds the tuner.
+/// Returns the ids of the schedules whose `is_active` has been changed.
+pub(crate) fn resolve_conflicts(items: &mut [Schedule], model: &TunerModel) -> Vec<i64> {
+    if !model.is_ready() {
+        return vec![];
+    }
+
+    let now = Local::now();
+    let started = |s: &Schedule| s.is_active && occupied_span(&s.program).0 <= now;
+    let mut order = (0..items.len())
+        .filter(|i| items[*i].is_active || items[*i].inactive_reason.is_some())
+        .collect::<Vec<_>>();
+    order.sort_by(|a, b| {
+        let (a, b) = (&items[*a], &items[*b]);
+        started(b)
+            .cmp(&started(a))
+            .then(b.priority.cmp(&a.priority))
+            .then(a.program.start_at.cmp(&b.program.start_at))
+            .then(a.program.id.cmp(&b.program.id))
+    });
+
+    // (index, reason). None means accepted.
+    let mut decisions: Vec<(usize, Option<String>)> = Vec::new();
+    {
+        let mut accepted: Vec<Span> = Vec::new();
+        for i in order {
+            let span = match span_of(&items[i], model) {
+                Some(span) => span,
+                // Unknown channels can't be judged.
+                None => {
+                    decisions.push((i, None));
+                    continue;
+                }
+            };
+            let trial = accepted.iter().chain(Some(&span)).collect::<Vec<_>>();
+            if find_conflicts(&trial, model).is_empty() {
+                accepted.push(span);
+                decisions.push((i, None));
+            } else {
+                let winners = accepted
+                    .iter()
+                    .filter(|s| s.overlaps(&span))
+                    .map(|s| s.id)
+                    .collect::<Vec<_>>();
+                decisions.push((
+                    i,
+                    Some(format!(
+                        "No tuner is left for {:?} at {}. Programs with higher priority: {:?}",
+                        span.channel, span.from, winners
+                    )),
+                ));
+            }
+        }
+    }
+
+    let mut changed = Vec::new();
+    for (i, reason) in decisions {
+        let item = &mut items[i];
+        let is_active = reason.is_none();
+        if item.is_active != is_active {
+            changed.push(item.program.id);
+        }
+        item.is_active = is_active;
+        item.inactive_reason = reason;
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone};
+
+    use super::TunerType::{BS, CS, GR};
+    use super::*;
+    use crate::recording_planner::PlanId;
+    use crate::test_utils;
+
+    const NETWORK_ID: u16 = 0x7fe0;
+    const BS_NETWORK_ID: u16 = 0x0004;
+
+    // GR services 0x0400 and 0x0401 share channel 27, 0x0408 is on 26, and BS/CS services have one of their own.
+    fn model(tuners: &[&[TunerType]]) -> TunerModel {
+        test_utils::config();
+        let mut channels = HashMap::new();
+        for (nid, sid, r#type, channel) in [
+            (NETWORK_ID, 0x0400, GR, "27"),
+            (NETWORK_ID, 0x0401, GR, "27"),
+            (NETWORK_ID, 0x0408, GR, "26"),
+            (BS_NETWORK_ID, 0x0065, BS, "BS01_0"),
+            (BS_NETWORK_ID, 0x0067, BS, "BS03_1"),
+            (0x0007, 0x00f1, CS, "CS2"),
+        ] {
+            channels.insert(
+                (nid as i64, sid as i64),
+                ChannelKey {
+                    r#type,
+                    channel: channel.to_string(),
+                },
+            );
+        }
+        TunerModel {
+            tuners: tuners.iter().map(|t| t.to_vec()).collect(),
+            channels,
+            unknown: Default::default(),
+        }
+    }
+
+    fn channel(r#type: TunerType, channel: &str) -> ChannelKey {
+        ChannelKey {
+            r#type,
+            channel: channel.to_string(),
+        }
+    }
+
+    // A program of 30 minutes starting at `h:m` on 2030-01-01
+    fn schedule(ids: (u16, u16, u16), (h, m): (u32, u32)) -> Schedule {
+        let start_at = Local.with_ymd_and_hms(2030, 1, 1, h, m, 0).unwrap();
+        schedule_at(ids, start_at, 30)
+    }
+
+    fn schedule_at(ids: (u16, u16, u16), start_at: DateTime<Local>, minutes: i64) -> Schedule {
+        Schedule {
+            program: test_utils::program(ids, start_at, Some(minutes * 60 * 1000)),
+            plan_id: PlanId::None,
+            is_active: true,
+            priority: 0,
+            inactive_reason: None,
+            plan_name: None,
+            profile: None,
+        }
+    }
+
+    #[test]
+    fn multi_type_tuner_is_left_for_the_type_only_it_receives() {
+        // The GR/BS/CS tuner has to take GR, since the other tuner can only receive BS.
+        let model = model(&[&[GR, BS, CS], &[BS]]);
+        let gr = channel(GR, "27");
+        let bs = channel(BS, "BS01_0");
+        let cs = channel(CS, "CS2");
+        assert!(model.can_receive(&[&bs, &gr]));
+        assert!(model.can_receive(&[&gr, &bs]));
+        assert!(!model.can_receive(&[&gr, &cs]));
+        assert!(!model.can_receive(&[&bs, &gr, &cs]));
+    }
+
+    #[test]
+    fn channels_of_a_type_without_tuners_cannot_be_received() {
+        let model = model(&[&[GR], &[GR]]);
+        assert!(model.can_receive(&[]));
+        assert!(!model.can_receive(&[&channel(BS, "BS01_0")]));
+    }
+
+    #[test]
+    fn programs_on_the_same_channel_share_a_tuner() {
+        let model = model(&[&[GR]]);
+        let items = [
+            schedule((NETWORK_ID, 0x0400, 0x4001), (12, 0)),
+            schedule((NETWORK_ID, 0x0401, 0x4002), (12, 0)),
+        ];
+        assert!(detect_conflicts(&items, &model).is_empty());
+    }
+
+    #[test]
+    fn conflict_lists_programs_on_air() {
+        let model = model(&[&[GR], &[BS, CS]]);
+        let items = [
+            schedule((NETWORK_ID, 0x0400, 0x4011), (12, 0)),
+            schedule((BS_NETWORK_ID, 0x0065, 0x4012), (12, 0)),
+            schedule((NETWORK_ID, 0x0408, 0x4013), (12, 10)),
+        ];
+        let conflicts = detect_conflicts(&items, &model);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].at, occupied_span(&items[2].program).0);
+        let mut ids = items.iter().map(|s| s.program.id).collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(conflicts[0].program_ids, ids);
+        assert_eq!(conflicts[0].channels.len(), 3);
+
+        // Inactive schedules don't take tuners.
+        let mut items = items;
+        items[1].is_active = false;
+        items[2].is_active = false;
+        assert!(detect_conflicts(&items, &model).is_empty());
+    }
+
+    #[test]
+    fn back_to_back_programs_conflict_during_pre_roll() {
+        let model = model(&[&[GR]]);
+        // On the same channel, the tuner is handed over.
+        let same_channel = [
+            schedule((NETWORK_ID, 0x0400, 0x4021), (12, 0)),
+            schedule((NETWORK_ID, 0x0401, 0x4022), (12, 30)),
+        ];
+        assert!(detect_conflicts(&same_channel, &model).is_empty());
+
+        // The next program on another channel starts to occupy a tuner before the previous one ends.
+        let other_channel = [
+            schedule((NETWORK_ID, 0x0400, 0x4023), (12, 0)),
+            schedule((NETWORK_ID, 0x0408, 0x4024), (12, 30)),
+        ];
+        let conflicts = detect_conflicts(&other_channel, &model);
+        assert_eq!(conflicts.len(), 1);
+        let start_at: DateTime<Local> = other_channel[1].program.start_at.into();
+        assert_eq!(conflicts[0].at, start_at - pre_roll());
+
+        // Without overlap, there is no conflict.
+        let apart = [
+            schedule((NETWORK_ID, 0x0400, 0x4025), (12, 0)),
+            schedule((NETWORK_ID, 0x0408, 0x4026), (13, 0)),
+        ];
+        assert!(detect_conflicts(&apart, &model).is_empty());
+    }
+
+    #[test]
+    fn programs_of_unknown_channels_are_excluded() {
+        let model = model(&[&[GR]]);
+        let items = [
+            schedule((NETWORK_ID, 0x0400, 0x4031), (12, 0)),
+            schedule((NETWORK_ID, 0x0999, 0x4032), (12, 0)),
+            schedule((NETWORK_ID, 0x0999, 0x4033), (12, 5)),
+        ];
+        assert!(detect_conflicts(&items, &model).is_empty());
+        // Reported once for the service
+        assert_eq!(model.unknown.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn nothing_is_detected_before_tuners_are_known() {
+        let model = model(&[]);
+        let items = [
+            schedule((NETWORK_ID, 0x0400, 0x4041), (12, 0)),
+            schedule((NETWORK_ID, 0x0408, 0x4042), (12, 0)),
+        ];
+        assert!(detect_conflicts(&items, &model).is_empty());
+    }
+
+    #[test]
+    fn conflict_is_reported_again_only_when_programs_change() {
+        let model = model(&[&[GR]]);
+        let items = [
+            schedule((NETWORK_ID, 0x0400, 0x4051), (12, 0)),
+            schedule((NETWORK_ID, 0x0408, 0x4052), (12, 0)),
+            // Starts while the two above are still on air, on a channel already taken
+            schedule((NETWORK_ID, 0x0401, 0x4053), (12, 10)),
+        ];
+        let conflicts = detect_conflicts(&items, &model);
+        assert_eq!(conflicts.len(), 2);
+        assert_eq!(conflicts[0].program_ids.len(), 2);
+        assert_eq!(conflicts[1].program_ids.len(), 3);
+        assert!(conflicts[0].at < conflicts[1].at);
+    }
+
+    fn active(items: &[Schedule]) -> Vec<bool> {
+        items.iter().map(|s| s.is_active).collect()
+    }
+
+    #[test]
+    fn higher_priority_wins() {
+        let model = model(&[&[GR]]);
+        let mut items = vec![
+            schedule((NETWORK_ID, 0x0400, 0x4061), (12, 0)),
+            Schedule {
+                priority: 1,
+                ..schedule((NETWORK_ID, 0x0408, 0x4062), (12, 10))
+            },
+        ];
+        assert_eq!(
+            resolve_conflicts(&mut items, &model),
+            vec![items[0].program.id]
+        );
+        assert_eq!(active(&items), vec![false, true]);
+        let reason = items[0].inactive_reason.as_ref().unwrap();
+        assert!(reason.contains(&items[1].program.id.to_string()));
+        assert!(items[1].inactive_reason.is_none());
+        assert!(detect_conflicts(&items, &model).is_empty());
+    }
+
+    #[test]
+    fn earlier_start_then_lower_id_wins_among_equal_priorities() {
+        let model = model(&[&[GR]]);
+        let mut items = vec![
+            schedule((NETWORK_ID, 0x0408, 0x4071), (12, 10)),
+            schedule((NETWORK_ID, 0x0400, 0x4072), (12, 0)),
+        ];
+        resolve_conflicts(&mut items, &model);
+        assert_eq!(active(&items), vec![false, true]);
+
+        let mut items = vec![
+            schedule((NETWORK_ID, 0x0408, 0x4073), (12, 0)),
+            schedule((NETWORK_ID, 0x0400, 0x4074), (12, 0)),
+        ];
+        resolve_conflicts(&mut items, &model);
+        let winner = items.iter().map(|s| s.program.id).min().unwrap();
+        assert!(items
+            .iter()
+            .all(|s| s.is_active == (s.program.id == winner)));
+    }
+
+    #[test]
+    fn result_does_not_depend_on_queue_order() {
+        let model = model(&[&[GR], &[GR, BS]]);
+        let items = vec![
+            schedule((NETWORK_ID, 0x0400, 0x4081), (12, 0)),
+            Schedule {
+                priority: 2,
+                ..schedule((NETWORK_ID, 0x0408, 0x4082), (12, 20))
+            },
+            schedule((BS_NETWORK_ID, 0x0065, 0x4083), (12, 0)),
+            schedule((BS_NETWORK_ID, 0x0067, 0x4084), (12, 5)),
+            schedule((NETWORK_ID, 0x0401, 0x4085), (11, 50)),
+        ];
+        let resolved = |order: &[usize]| {
+            let mut items = order.iter().map(|i| items[*i].clone()).collect::<Vec<_>>();
+            resolve_conflicts(&mut items, &model);
+            let mut winners = items
+                .iter()
+                .filter(|s| s.is_active)
+                .map(|s| s.program.id)
+                .collect::<Vec<_>>();
+            winners.sort();
+            winners
+        };
+        let winners = resolved(&[0, 1, 2, 3, 4]);
+        assert_eq!(resolved(&[4, 3, 2, 1, 0]), winners);
+        assert_eq!(resolved(&[2, 0, 4, 1, 3]), winners);
+        assert!(winners.contains(&items[1].program.id));
+    }
+
+    #[test]
+    fn loser_is_reactivated_once_conflict_is_gone() {
+        let model = model(&[&[GR]]);
+        let mut items = vec![
+            Schedule {
+                priority: 1,
+                ..schedule((NETWORK_ID, 0x0400, 0x4091), (12, 0))
+            },
+            schedule((NETWORK_ID, 0x0408, 0x4092), (12, 0)),
+        ];
+        resolve_conflicts(&mut items, &model);
+        assert_eq!(active(&items), vec![true, false]);
+
+        // Deactivated by the user, as PATCH /api/v1/schedules/{id} does
+        items[0].is_active = false;
+        items[0].inactive_reason = None;
+        assert_eq!(
+            resolve_conflicts(&mut items, &model),
+            vec![items[1].program.id]
+        );
+        assert_eq!(active(&items), vec![false, true]);
+        assert!(items[1].inactive_reason.is_none());
+
+        // Nothing changes any more.
+        assert!(resolve_conflicts(&mut items, &model).is_empty());
+    }
+
+    #[test]
+    fn schedules_deactivated_by_users_are_left_inactive() {
+        let model = model(&[&[GR]]);
+        let mut items = vec![Schedule {
+            is_active: false,
+            ..schedule((NETWORK_ID, 0x0400, 0x40a1), (12, 0))
+        }];
+        assert!(resolve_conflicts(&mut items, &model).is_empty());
+        assert!(!items[0].is_active);
+        assert!(items[0].inactive_reason.is_none());
+    }
+
+    #[test]
+    fn started_recording_keeps_its_tuner() {
+        let model = model(&[&[GR]]);
+        let now = Local::now();
+        let mut items = vec![
+            schedule_at((NETWORK_ID, 0x0400, 0x40b1), now - Duration::minutes(5), 60),
+            Schedule {
+                priority: 10,
+                ..schedule_at(
+                    (NETWORK_ID, 0x0408, 0x40b2),
+                    now + Duration::minutes(30),
+                    30,
+                )
+            },
+        ];
+        resolve_conflicts(&mut items, &model);
+        assert_eq!(active(&items), vec![true, false]);
+
+        // The schedule of higher priority wins once the recording is over.
+        items[0].is_active = false;
+        items[0].inactive_reason = None;
+        resolve_conflicts(&mut items, &model);
+        assert_eq!(active(&items), vec![false, true]);
+    }
+}
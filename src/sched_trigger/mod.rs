@@ -7,67 +7,96 @@ use mirakurun_client::models::Program;
 use serde_derive::{Deserialize, Serialize};
 use tokio::sync::mpsc::Sender;
 use tokio::sync::Mutex;
+use utoipa::ToSchema;
 
+use crate::config::get_config;
+use crate::events::{publish, Event};
 use crate::recording_planner::PlanId;
 use crate::recording_pool::{RecordControlMessage, RecordingTaskDescription};
+use crate::sched_trigger::conflict::{resolve_conflicts, TUNER_MODEL};
+use crate::sched_trigger::persistence::Writer;
+
+pub(crate) mod conflict;
+pub(crate) mod persistence;
+
+/// Recording tasks are created this long before the program starts.
+pub(crate) fn pre_roll() -> Duration {
+    Duration::minutes(get_config().recording.pre_roll_minutes)
+}
 
 pub(crate) struct SchedQueue {
     pub(crate) items: Vec<Schedule>,
+    path: PathBuf,
+    // What is in the file now. Writing is skipped if nothing has changed.
+    saved: Vec<u8>,
+    // The queue is held by async tasks, so the file is synced on another thread.
+    writer: Arc<Writer>,
 }
 
-impl Drop for SchedQueue {
-    fn drop(&mut self) {
-        //Export remaining tasks
-        let path = Path::new("./q_schedules.json")
-            .canonicalize()
-            .unwrap_or(PathBuf::from("./q_schedules.json"));
-        let result = match serde_json::to_string(&self.items) {
-            Ok(str) => std::fs::write(&path, str),
-            Err(e) => panic!("Serialization failed. {}", e),
+impl SchedQueue {
+    /// Imports all the previously stored schedules.
+    pub(crate) fn load(path: &Path) -> std::io::Result<Self> {
+        let items = persistence::load(path)?;
+        let saved = persistence::serialize(&items).unwrap_or_default();
+        Ok(Self {
+            items,
+            path: path.to_path_buf(),
+            saved,
+            writer: Writer::spawn("schedules"),
+        })
+    }
+
+    /// Writes the items through to the file. Must be called after every mutation of `items`.
+    /// Only a snapshot is taken here. See `flush`.
+    pub(crate) fn save(&mut self) {
+        let bytes = match persistence::serialize(&self.items) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                error!("Failed to serialize schedules. {}", e);
+                return;
+            }
         };
-        if result.is_ok() {
-            println!("q_schedules is saved in {}.", path.display())
+        if bytes == self.saved {
+            return;
         }
+        self.writer.send(self.path.clone(), bytes.clone());
+        self.saved = bytes;
+    }
+
+    /// Returns a function which blocks until every change so far has been written to the file.
+    /// It is meant for a blocking thread, and doesn't need the queue to be held.
+    pub(crate) fn flush(&self) -> impl FnOnce() + Send + 'static {
+        let writer = self.writer.clone();
+        move || writer.flush()
     }
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
 pub(crate) struct Schedule {
+    #[schema(value_type = Object)]
     pub(crate) program: Program,
     pub(crate) plan_id: PlanId,
     // If it is added through a plan (e.g. Record all of the items in the series), its uuid is stored here.
     pub(crate) is_active: bool,
+    // Higher one wins a tuner conflict. Inherited from the plan.
+    #[serde(default)]
+    pub(crate) priority: i32,
+    // Set when the schedule is deactivated by the conflict resolver, which reactivates it once the conflict is gone.
+    // Schedules deactivated by users have None here.
+    #[serde(default)]
+    pub(crate) inactive_reason: Option<String>,
+    // Keyword of the word rule or name of the series plan, used in file names
+    #[serde(default)]
+    pub(crate) plan_name: Option<String>,
+    // Output profile inherited from the plan. None means the default one.
+    #[serde(default)]
+    pub(crate) profile: Option<String>,
 }
 
 pub(crate) async fn scheduler_startup(
     q_schedules: Arc<Mutex<SchedQueue>>,
     tx: Sender<RecordControlMessage>,
 ) -> Result<(), std::io::Error> {
-    //Import all the previously stored schedules
-    {
-        q_schedules.lock().await.items.append(&mut {
-            let path = Path::new("./q_schedules.json");
-            let schedules =
-                if path.exists() {
-                    let str = std::fs::read(path.canonicalize()?)?;
-                    match serde_json::from_slice::<Vec<Schedule>>(&str)
-                    {
-                        Ok(items) => Some(items),
-                        Err(e) => {
-                            warn!("{}", e);
-                            None
-                        }
-                    }
-                } else {
-                    None
-                };
-            schedules.unwrap_or_else(|| {
-                info!("No valid q_schedules.json is found. It'll be created or overwritten just before exiting.");
-                Vec::new()
-            })
-        });
-    }
-
     loop {
         info!("Now locking q_schedules.");
         {
@@ -76,19 +105,16 @@ pub(crate) async fn scheduler_startup(
             let (found, mut remainder) = (q_schedules.items.len(), 0usize);
 
             // Drop expired item
+            // 長さ未定のときは、開始時刻から上限時間が経過したらドロップ
             q_schedules.items.retain(|item| {
-                let start_at = item.program.start_at;
-
-                match item {
-                    Schedule { program: Program{ duration: Some(length_msec), .. }, .. }  => {
-                        //長さ有限かつ現在放送終了してたらドロップ
-                        Local::now() < start_at + Duration::milliseconds(*length_msec as i64)
-                    }
-                    Schedule { program: Program{ duration: None, .. }, .. }  => {
-                        //長さ未定のときは、開始時刻から１時間経過したらドロップ
-                        Local::now() < start_at + Duration::hours(1)
-                    }
+                let keep = Local::now() < expected_end_at(&item.program);
+                if !keep {
+                    publish(Event::ScheduleRemoved {
+                        program_id: item.program.id,
+                        reason: "expired".to_string(),
+                    });
                 }
+                keep
             });
             remainder = q_schedules.items.len();
 
@@ -98,33 +124,46 @@ pub(crate) async fn scheduler_startup(
                 found - remainder
             );
 
+            // Settle tuner conflicts, including the ones caused or solved by EIT updates
+            for id in resolve_conflicts(&mut q_schedules.items, &TUNER_MODEL.read().unwrap()) {
+                if let Some(item) = q_schedules.items.iter().find(|f| f.program.id == id) {
+                    match &item.inactive_reason {
+                        Some(reason) => warn!("Program {} is deactivated. {}", id, reason),
+                        None => info!("Program {} is reactivated.", id),
+                    }
+                    publish(Event::ScheduleUpdated {
+                        schedule: item.clone(),
+                    });
+                }
+            }
+            q_schedules.save();
 
             for item in q_schedules.items.iter() {
                 match item {
-                    // 有効かつ長さ有限
-                    Schedule {is_active: true, program: Program{ duration: Some(length_msec),  .. }, ..} => {
+                    // 有効
+                    // 長さ未定の場合も開始時刻から録画し、終了はタスク側でEIT[p/f]と上限時間により判断する
+                    Schedule {
+                        is_active: true, ..
+                    } => {
                         //保存場所の決定
-                        let save_location = {
-                            let candidate = match item.plan_id {
-                                PlanId::Word(id) => format!("./word_{}/", id),
-                                PlanId::Series(id) => format!("./series_{}/", id),
-                                PlanId::None => "./common/".to_string(),
-                            };
-                            if let Err(e) = std::fs::create_dir_all(&candidate) {
-                                error!("Failed to create dir at {}.\n{}", &candidate, e);
-                                continue
-                            }
-                            std::fs::canonicalize(candidate).unwrap()
-                        };
-
+                        // ボリュームはタスク作成時にプール側で決め、サブディレクトリとファイル名はテンプレートからタスク側で決める
                         let task = RecordingTaskDescription {
                             program: item.program.clone(),
-                            save_dir_location: save_location,
+                            save_dir_location: PathBuf::new(),
+                            volume: None,
+                            relayed_from: None,
+                            plan_id: item.plan_id.clone(),
+                            plan_name: item.plan_name.clone(),
+                            file: None,
+                            started_at: None,
+                            parts: Vec::new(),
+                            sidecars: Vec::new(),
+                            profile: item.profile.clone(),
                         };
 
                         if is_in_the_recording_range(
-                            // 放送開始10分以内前
-                            (item.program.start_at - Duration::minutes(10)).into(),
+                            // 放送開始前 (既定では10分以内)
+                            (item.program.start_at - pre_roll()).into(),
                             item.program.start_at.into(),
                             Local::now(),
                         ) {
@@ -135,8 +174,7 @@ pub(crate) async fn scheduler_startup(
                         } else if is_in_the_recording_range(
                             // 放送中
                             item.program.start_at.into(),
-                            (item.program.start_at + Duration::milliseconds(*length_msec as i64))
-                                .into(),
+                            expected_end_at(&item.program),
                             Local::now(),
                         ) {
                             // Mirakurun側の更新を取り入れず、タスク側の状態遷移に一任する
@@ -147,15 +185,25 @@ pub(crate) async fn scheduler_startup(
                                 .unwrap();
                         }
                     }
-                    Schedule{is_active: true, program: Program { duration: None, .. }, ..} => {
-                        error!("未実装：録画開始時点でduration不明")
-                    }
                     _ => continue,
                 }
             }
         }
         info!("Scanning schedules completed. Now releasing q_schedules.");
-        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        tokio::time::sleep(std::time::Duration::from_secs(
+            get_config().timers.scheduler_scan_secs,
+        ))
+        .await;
+    }
+}
+
+/// When the program is regarded as over.
+/// Programs with unknown duration may last until the limit, unless EIT[p/f] shows the end earlier.
+pub(crate) fn expected_end_at(p: &Program) -> DateTime<Local> {
+    let start_at: DateTime<Local> = p.start_at.into();
+    match p.duration {
+        Some(length_msec) => start_at + Duration::milliseconds(length_msec as i64),
+        None => start_at + Duration::minutes(get_config().recording.unknown_duration_limit_minutes),
     }
 }
 
@@ -165,6 +213,83 @@ fn is_in_the_recording_range(
     right: DateTime<Local>,
     value: DateTime<Local>,
 ) -> bool {
-    assert!(left < right);
+    // Empty if there is no pre-roll, or the duration is 0.
     (left < value) && (value < right)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn unreadable_file_is_not_taken_as_empty() {
+        let dir = test_utils::temp_dir("sched");
+        // A directory cannot be read as a file.
+        assert!(SchedQueue::load(&dir).is_err());
+        let queue = SchedQueue::load(&dir.join("q_schedules.json")).unwrap();
+        assert!(queue.items.is_empty());
+    }
+
+    #[test]
+    fn broken_file_is_moved_aside() {
+        let path = test_utils::temp_dir("sched").join("q_schedules.json");
+        std::fs::write(&path, b"{").unwrap();
+        let queue = SchedQueue::load(&path).unwrap();
+        assert!(queue.items.is_empty());
+        assert!(!path.exists());
+        let backups = std::fs::read_dir(path.parent().unwrap()).unwrap().count();
+        assert_eq!(backups, 1);
+    }
+
+    #[test]
+    fn broken_file_is_kept_if_it_cannot_be_moved_aside() {
+        let path = test_utils::temp_dir("sched").join("q_schedules.json");
+        std::fs::write(&path, b"{").unwrap();
+        // A file cannot replace a directory. The name of the backup has the time in seconds.
+        let now = Local::now();
+        for secs in 0..3 {
+            let mut name = path.file_name().unwrap().to_os_string();
+            let at = now + Duration::seconds(secs);
+            name.push(format!(".corrupt-{}", at.format("%Y%m%d%H%M%S")));
+            std::fs::create_dir(path.with_file_name(name)).unwrap();
+        }
+
+        assert!(SchedQueue::load(&path).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"{");
+    }
+
+    #[test]
+    fn saved_items_are_loaded() {
+        let path = test_utils::temp_dir("sched").join("q_schedules.json");
+        let mut queue = SchedQueue::load(&path).unwrap();
+        queue.items.push(Schedule {
+            program: test_utils::program((0x7fe0, 0x0400, 0x6001), Local::now(), None),
+            plan_id: PlanId::None,
+            is_active: true,
+            priority: 1,
+            inactive_reason: None,
+            plan_name: Some("plan".to_string()),
+            profile: None,
+        });
+        queue.save();
+        queue.flush()();
+
+        let loaded = SchedQueue::load(&path).unwrap();
+        assert_eq!(loaded.items.len(), 1);
+        assert_eq!(loaded.items[0].program.id, queue.items[0].program.id);
+        assert_eq!(loaded.items[0].priority, 1);
+        assert_eq!(loaded.items[0].plan_name.as_deref(), Some("plan"));
+    }
+
+    #[test]
+    fn empty_range_contains_nothing() {
+        let now = Local::now();
+        assert!(!is_in_the_recording_range(now, now, now));
+        assert!(is_in_the_recording_range(
+            now - Duration::minutes(1),
+            now + Duration::minutes(1),
+            now
+        ));
+    }
+}
@@ -0,0 +1,174 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+
+use chrono::Local;
+use log::{error, info, warn};
+use serde_derive::Serialize;
+use serde_json::Value;
+
+use crate::sched_trigger::Schedule;
+
+// 1: A bare array of Schedule
+// 2: {"version": 2, "items": [...]}
+const SCHEMA_VERSION: u64 = 2;
+
+#[derive(Serialize)]
+struct Stored<'a> {
+    version: u64,
+    items: &'a [Schedule],
+}
+
+pub(super) fn serialize(items: &[Schedule]) -> serde_json::Result<Vec<u8>> {
+    serde_json::to_vec(&Stored {
+        version: SCHEMA_VERSION,
+        items,
+    })
+}
+
+// Converts any known version into the current Schedule.
+fn migrate(value: Value) -> Result<Vec<Schedule>, String> {
+    let (version, items) = match value {
+        Value::Array(items) => (1, Value::Array(items)),
+        Value::Object(mut obj) => {
+            let version = obj
+                .get("version")
+                .and_then(Value::as_u64)
+                .ok_or("`version` is missing.")?;
+            (version, obj.remove("items").ok_or("`items` is missing.")?)
+        }
+        _ => return Err("Unknown format.".to_string()),
+    };
+    match version {
+        // Fields added in version 2 (e.g. priority, plan_name) are filled with their defaults.
+        1 | 2 => serde_json::from_value(items).map_err(|e| e.to_string()),
+        v => Err(format!(
+            "Version {} is newer than this build supports ({}).",
+            v, SCHEMA_VERSION
+        )),
+    }
+}
+
+/// Where a broken file is moved, next to it.
+pub(crate) fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".corrupt-{}", Local::now().format("%Y%m%d%H%M%S")));
+    path.with_file_name(name)
+}
+
+/// Reads the schedules. A file which cannot be parsed is moved aside, so that it isn't overwritten.
+/// Fails if the file cannot be read or moved aside, since it would be overwritten by the next save.
+pub(super) fn load(path: &Path) -> std::io::Result<Vec<Schedule>> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            info!("{} is not found. It'll be created.", path.display());
+            return Ok(Vec::new());
+        }
+        Err(e) => return Err(e),
+    };
+
+    let result = serde_json::from_slice::<Value>(&bytes)
+        .map_err(|e| e.to_string())
+        .and_then(migrate);
+    match result {
+        Ok(items) => {
+            info!(
+                "{} schedules are loaded from {}.",
+                items.len(),
+                path.display()
+            );
+            Ok(items)
+        }
+        Err(e) => {
+            let backup = backup_path(path);
+            warn!(
+                "{} is broken. {} It is moved to {}.",
+                path.display(),
+                e,
+                backup.display()
+            );
+            // Taken as empty, the file would be overwritten by the next save.
+            std::fs::rename(path, &backup).map_err(|e| {
+                std::io::Error::new(
+                    e.kind(),
+                    format!("Failed to move it to {}. {}", backup.display(), e),
+                )
+            })?;
+            Ok(Vec::new())
+        }
+    }
+}
+
+/// Replaces the file with a temporary one, so that a crash never leaves a half-written file.
+pub(crate) fn write_atomically(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    let tmp = path.with_file_name(name);
+
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+/// Writes snapshots on a thread of its own, so that the owner of the data isn't held while the file is synced.
+/// Only the latest snapshot is written.
+pub(crate) struct Writer {
+    // Named in the logs
+    name: &'static str,
+    pending: Mutex<Pending>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct Pending {
+    snapshot: Option<(PathBuf, Vec<u8>)>,
+    writing: bool,
+}
+
+impl Writer {
+    pub(crate) fn spawn(name: &'static str) -> Arc<Self> {
+        let writer = Arc::new(Self {
+            name,
+            pending: Mutex::new(Pending::default()),
+            changed: Condvar::new(),
+        });
+        let running = writer.clone();
+        std::thread::spawn(move || running.run());
+        writer
+    }
+
+    fn run(&self) {
+        loop {
+            let (path, bytes) = {
+                let mut pending = self.pending.lock().unwrap();
+                loop {
+                    if let Some(snapshot) = pending.snapshot.take() {
+                        pending.writing = true;
+                        break snapshot;
+                    }
+                    pending = self.changed.wait(pending).unwrap();
+                }
+            };
+            if let Err(e) = write_atomically(&path, &bytes) {
+                error!("Failed to save {} in {}. {}", self.name, path.display(), e);
+            }
+            self.pending.lock().unwrap().writing = false;
+            self.changed.notify_all();
+        }
+    }
+
+    pub(crate) fn send(&self, path: PathBuf, bytes: Vec<u8>) {
+        self.pending.lock().unwrap().snapshot = Some((path, bytes));
+        self.changed.notify_all();
+    }
+
+    /// Blocks until every snapshot sent so far has been written.
+    pub(crate) fn flush(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        while pending.snapshot.is_some() || pending.writing {
+            pending = self.changed.wait(pending).unwrap();
+        }
+    }
+}
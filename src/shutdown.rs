@@ -0,0 +1,70 @@
+use once_cell::sync::Lazy;
+use tokio::sync::watch;
+
+// Turns true on the first signal. Never turns back.
+static SHUTDOWN: Lazy<watch::Sender<bool>> = Lazy::new(|| watch::channel(false).0);
+
+pub(crate) fn begin() {
+    SHUTDOWN.send_replace(true);
+}
+
+pub(crate) fn is_shutting_down() -> bool {
+    *SHUTDOWN.borrow()
+}
+
+/// Resolves once the shutdown has begun.
+pub(crate) async fn wait_for_shutdown() {
+    let mut rx = SHUTDOWN.subscribe();
+    while !*rx.borrow_and_update() {
+        if rx.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Resolves on ctrl_c, or SIGTERM (e.g. `systemctl stop`) on Unix.
+pub(crate) async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {},
+                    _ = sigterm.recv() => {},
+                }
+                return;
+            }
+            Err(e) => log::warn!("SIGTERM cannot be handled. {}", e),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn sigterm_is_taken_as_signal() {
+        use tokio::signal::unix::{signal, SignalKind};
+        // Keeps the process alive even if the task below has not started listening.
+        let _handler = signal(SignalKind::terminate()).unwrap();
+        let waiting = tokio::spawn(wait_for_signal());
+        tokio::task::yield_now().await;
+
+        let pid = std::process::id().to_string();
+        let status = std::process::Command::new("kill")
+            .args(["-TERM", &pid])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        tokio::time::timeout(Duration::from_secs(5), waiting)
+            .await
+            .expect("SIGTERM is not taken as a signal.")
+            .unwrap();
+    }
+}
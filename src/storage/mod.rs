@@ -0,0 +1,65 @@
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::time::Duration;
+
+use log::{error, info, warn};
+
+use crate::config::get_config;
+use crate::events::{publish, Event};
+use crate::storage::retention::apply_retention;
+
+mod retention;
+pub(crate) mod volume;
+
+const MIB: u64 = 1024 * 1024;
+
+/// Fails if the free space of `dir` is below `storage.min_free_mib`.
+/// Checked before a recording starts, so that it doesn't break off when the disk fills up.
+pub(crate) fn ensure_free_space(dir: &Path) -> Result<(), Error> {
+    let available = fs2::available_space(dir)?;
+    let required = get_config().storage.min_free_mib * MIB;
+    if available < required {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!(
+                "Only {} MiB is left in {}. {} MiB is required.",
+                available / MIB,
+                dir.display(),
+                required / MIB
+            ),
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) async fn storage_startup() {
+    let sec = get_config().storage.retention.interval_secs;
+    info!("Retention policies are applied every {} seconds.", sec);
+    loop {
+        if let Err(e) = apply_retention().await {
+            error!("Failed to apply retention policies. {}", e);
+        }
+        if let Err(e) = tokio::task::spawn_blocking(warn_full_volumes).await {
+            error!("Failed to check volumes. {}", e);
+        }
+        tokio::time::sleep(Duration::from_secs(sec)).await;
+    }
+}
+
+// Retention may not be enough to make room, e.g. if no policy is configured.
+fn warn_full_volumes() {
+    for v in volume::volumes() {
+        match volume::status_of(&v) {
+            Ok(status) if status.room_bytes == 0 => {
+                warn!("Volume {} has no room for new recordings.", status.name);
+                publish(Event::DiskWarning {
+                    volume: status.name,
+                    path: status.path,
+                    room_bytes: status.room_bytes,
+                });
+            }
+            Ok(_) => {}
+            Err(e) => error!("Failed to check volume {}. {}", v.name, e),
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Local};
+use log::{info, warn};
+use meilisearch_sdk::errors::Error;
+use ulid::Ulid;
+
+use crate::config::{get_config, RetentionConfig};
+use crate::db_utils::{get_all_recordings, get_temporary_accessor};
+use crate::post_process::JOB_QUEUE;
+use crate::recording_planner::PlanId;
+use crate::recording_pool::library::{remove_recording, Recording};
+
+const GIB: u64 = 1024 * 1024 * 1024;
+
+/// Deletes the recordings, with their files, that the policies don't keep.
+pub(super) async fn apply_retention() -> Result<(), Error> {
+    let config = &get_config().storage.retention;
+    if config.keep_per_series.is_none()
+        && config.max_age_days.is_none()
+        && config.quota_gib.is_none()
+    {
+        return Ok(());
+    }
+
+    let recordings = get_all_recordings(&get_temporary_accessor()).await?;
+    let expired = {
+        let jobs = JOB_QUEUE.read().unwrap();
+        select_expired(&recordings, config, Local::now(), |id| jobs.is_busy(id))
+    };
+
+    for (id, reason) in expired {
+        match remove_recording(&id, true).await {
+            Ok(entry) => info!(
+                "Recording {} (id: {}, {:?}) is deleted. {}",
+                id, entry.program.id, entry.program.name, reason
+            ),
+            Err(e) => warn!("Failed to delete recording {}. {}", id, e),
+        }
+    }
+    Ok(())
+}
+
+// Returns the recordings to be deleted and the reasons.
+// Recordings which are still in use (e.g. being post-processed) are never selected.
+fn select_expired(
+    recordings: &[Recording],
+    config: &RetentionConfig,
+    now: DateTime<Local>,
+    is_busy: impl Fn(&Ulid) -> bool,
+) -> Vec<(Ulid, String)> {
+    let mut expired: Vec<(Ulid, String)> = Vec::new();
+    let is_selected =
+        |expired: &Vec<(Ulid, String)>, id: &Ulid| expired.iter().any(|(e, _)| e == id);
+    let candidates = recordings
+        .iter()
+        .filter(|r| !is_busy(&r.id))
+        .collect::<Vec<_>>();
+
+    if let Some(n) = config.keep_per_series {
+        let mut by_plan: HashMap<&Ulid, Vec<&Recording>> = HashMap::new();
+        for r in candidates.iter() {
+            if let PlanId::Series(plan) = &r.plan_id {
+                by_plan.entry(plan).or_default().push(r);
+            }
+        }
+        for (plan, mut items) in by_plan {
+            items.sort_by(|a, b| b.started_at.cmp(&a.started_at));
+            for r in items.into_iter().skip(n) {
+                expired.push((
+                    r.id,
+                    format!("Only the latest {} of series plan {} are kept.", n, plan),
+                ));
+            }
+        }
+    }
+
+    if let Some(days) = config.max_age_days {
+        let limit = now - Duration::days(days);
+        for r in candidates.iter().filter(|r| r.ended_at < limit) {
+            if !is_selected(&expired, &r.id) {
+                expired.push((r.id, format!("It is older than {} days.", days)));
+            }
+        }
+    }
+
+    if let Some(quota) = config.quota_gib {
+        // Recordings in use count toward the quota, even though they cannot be deleted.
+        let mut total: u64 = recordings
+            .iter()
+            .filter(|r| !is_selected(&expired, &r.id))
+            .map(|r| r.size)
+            .sum();
+        let mut oldest = candidates
+            .iter()
+            .filter(|r| !is_selected(&expired, &r.id))
+            .collect::<Vec<_>>();
+        oldest.sort_by_key(|r| r.started_at);
+        for r in oldest {
+            if total <= quota * GIB {
+                break;
+            }
+            total -= r.size;
+            expired.push((r.id, format!("The library exceeds {} GiB.", quota)));
+        }
+    }
+
+    expired
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    // A recording of 30 minutes which has started `days` ago
+    fn recording(plan_id: PlanId, days: i64, gib: u64, now: DateTime<Local>) -> Recording {
+        let program = test_utils::program(
+            (0x7fe0, 0x0400, 0x1001),
+            now - Duration::days(days),
+            Some(30 * 60 * 1000),
+        );
+        Recording {
+            size: gib * GIB,
+            plan_id,
+            ..test_utils::recording(program, true)
+        }
+    }
+
+    fn ids(expired: &[(Ulid, String)]) -> Vec<Ulid> {
+        expired.iter().map(|(id, _)| *id).collect()
+    }
+
+    #[test]
+    fn keeps_latest_of_each_series() {
+        let now = Local::now();
+        let (plan, other) = (Ulid::new(), Ulid::new());
+        let recordings = [
+            recording(PlanId::Series(plan), 3, 1, now),
+            recording(PlanId::Series(plan), 1, 1, now),
+            recording(PlanId::Series(plan), 2, 1, now),
+            recording(PlanId::Series(other), 9, 1, now),
+            recording(PlanId::None, 9, 1, now),
+        ];
+        let config = RetentionConfig {
+            keep_per_series: Some(2),
+            ..Default::default()
+        };
+        let expired = select_expired(&recordings, &config, now, |_| false);
+        assert_eq!(ids(&expired), [recordings[0].id]);
+    }
+
+    #[test]
+    fn deletes_older_than_max_age() {
+        let now = Local::now();
+        let recordings = [
+            recording(PlanId::None, 8, 1, now),
+            recording(PlanId::None, 6, 1, now),
+        ];
+        let config = RetentionConfig {
+            max_age_days: Some(7),
+            ..Default::default()
+        };
+        let expired = select_expired(&recordings, &config, now, |_| false);
+        assert_eq!(ids(&expired), [recordings[0].id]);
+    }
+
+    #[test]
+    fn deletes_oldest_beyond_quota() {
+        let now = Local::now();
+        let recordings = [
+            recording(PlanId::None, 1, 4, now),
+            recording(PlanId::None, 3, 4, now),
+            recording(PlanId::None, 2, 4, now),
+        ];
+        let config = RetentionConfig {
+            quota_gib: Some(5),
+            ..Default::default()
+        };
+        let expired = select_expired(&recordings, &config, now, |_| false);
+        assert_eq!(ids(&expired), [recordings[1].id, recordings[2].id]);
+    }
+
+    #[test]
+    fn busy_recordings_are_kept_but_counted() {
+        let now = Local::now();
+        let recordings = [
+            recording(PlanId::None, 3, 4, now),
+            recording(PlanId::None, 2, 4, now),
+            recording(PlanId::None, 1, 4, now),
+        ];
+        let busy = recordings[0].id;
+        let config = RetentionConfig {
+            max_age_days: Some(2),
+            quota_gib: Some(5),
+            ..Default::default()
+        };
+        let expired = select_expired(&recordings, &config, now, |id| *id == busy);
+        // Not deleted for its age, while its size leaves no room for the others
+        assert_eq!(ids(&expired), [recordings[1].id, recordings[2].id]);
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use serde_derive::Serialize;
+use utoipa::ToSchema;
+
+use crate::config::{get_config, PlacementStrategy, VolumeConfig};
+use crate::recording_planner::PlanId;
+use crate::recording_pool::RecordingTaskDescription;
+use crate::storage::MIB;
+
+const GIB: u64 = 1024 * MIB;
+
+static ROUND_ROBIN: AtomicUsize = AtomicUsize::new(0);
+
+// Volumes may hold many files, so each of them is walked at most once in this period.
+const USED_BYTES_TTL: Duration = Duration::from_secs(60);
+static USED_BYTES: Lazy<Mutex<HashMap<PathBuf, (Instant, u64)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Current usage of a volume. Reported in the API.
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct VolumeStatus {
+    pub(crate) name: String,
+    #[schema(value_type = String)]
+    pub(crate) path: PathBuf,
+    pub(crate) available_bytes: u64,
+    pub(crate) used_bytes: Option<u64>,
+    pub(crate) max_bytes: Option<u64>,
+    // Bytes new recordings may use, considering `min_free_mib` and `max_gib`
+    pub(crate) room_bytes: u64,
+}
+
+// Used when no volume is configured, which keeps the former behavior.
+fn default_volume() -> VolumeConfig {
+    VolumeConfig {
+        name: "default".to_string(),
+        path: PathBuf::from("./"),
+        max_gib: None,
+        allowed_plans: Vec::new(),
+    }
+}
+
+pub(crate) fn volumes() -> Vec<VolumeConfig> {
+    let volumes = &get_config().storage.volumes;
+    if volumes.is_empty() {
+        vec![default_volume()]
+    } else {
+        volumes.clone()
+    }
+}
+
+/// Total size of the files under `path`. Blocks while walking the directory.
+pub(crate) fn dir_size(path: &Path) -> u64 {
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries
+        .filter_map(Result::ok)
+        .map(|e| match e.file_type() {
+            Ok(t) if t.is_dir() => dir_size(&e.path()),
+            Ok(_) => e.metadata().map(|m| m.len()).unwrap_or(0),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+fn used_bytes(path: &Path) -> u64 {
+    if let Some((at, used)) = USED_BYTES.lock().unwrap().get(path) {
+        if at.elapsed() < USED_BYTES_TTL {
+            return *used;
+        }
+    }
+    let used = dir_size(path);
+    USED_BYTES
+        .lock()
+        .unwrap()
+        .insert(path.to_path_buf(), (Instant::now(), used));
+    used
+}
+
+/// Blocks while inspecting the file system.
+pub(crate) fn status_of(volume: &VolumeConfig) -> std::io::Result<VolumeStatus> {
+    std::fs::create_dir_all(&volume.path)?;
+    let available = fs2::available_space(&volume.path)?;
+    let min_free = get_config().storage.min_free_mib * MIB;
+    // Walking the directory is needed only if the volume has a limit.
+    let used = volume.max_gib.map(|_| used_bytes(&volume.path));
+    let max = volume.max_gib.map(|gib| gib * GIB);
+
+    let mut room = available.saturating_sub(min_free);
+    if let (Some(used), Some(max)) = (used, max) {
+        room = room.min(max.saturating_sub(used));
+    }
+    Ok(VolumeStatus {
+        name: volume.name.clone(),
+        path: volume.path.clone(),
+        available_bytes: available,
+        used_bytes: used,
+        max_bytes: max,
+        room_bytes: room,
+    })
+}
+
+fn allows(volume: &VolumeConfig, plan_id: &PlanId) -> bool {
+    volume.allowed_plans.is_empty() || volume.allowed_plans.iter().any(|p| names(p, plan_id))
+}
+
+// Whether the entry of `allowed_plans` names the plan itself, not its kind
+fn pins(volume: &VolumeConfig, plan_id: &PlanId) -> bool {
+    volume.allowed_plans.iter().any(|p| match plan_id {
+        PlanId::Word(id) | PlanId::Series(id) => p == &id.to_string(),
+        PlanId::None => false,
+    })
+}
+
+fn names(entry: &str, plan_id: &PlanId) -> bool {
+    match plan_id {
+        PlanId::Word(id) => entry == "word" || entry == id.to_string(),
+        PlanId::Series(id) => entry == "series" || entry == id.to_string(),
+        PlanId::None => entry == "common",
+    }
+}
+
+/// Chooses the volume of a new task and sets `volume` and `save_dir_location`.
+/// Tasks which already have a volume (e.g. following an event relay) stay there.
+/// Blocks while inspecting the volumes, so it is called on a blocking thread.
+pub(crate) fn place(info: &mut RecordingTaskDescription) -> Result<(), String> {
+    if info.volume.is_some() {
+        return Ok(());
+    }
+
+    let mut candidates = volumes()
+        .into_iter()
+        .filter(|v| allows(v, &info.plan_id))
+        .filter_map(|v| match status_of(&v) {
+            Ok(status) if status.room_bytes > 0 => Some((v, status)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if candidates.is_empty() {
+        return Err("No volume has room for the recording.".to_string());
+    }
+
+    let (volume, _) = match get_config().storage.placement {
+        PlacementStrategy::MostFree => candidates
+            .into_iter()
+            .max_by_key(|(_, status)| status.room_bytes)
+            .unwrap(),
+        PlacementStrategy::RoundRobin => {
+            let i = ROUND_ROBIN.fetch_add(1, Ordering::Relaxed) % candidates.len();
+            candidates.swap_remove(i)
+        }
+        PlacementStrategy::Pinned => {
+            // A stable sort keeps the order in the config.
+            candidates.sort_by_key(|(v, _)| !pins(v, &info.plan_id));
+            candidates.swap_remove(0)
+        }
+    };
+
+    info.save_dir_location = volume
+        .path
+        .canonicalize()
+        .map_err(|e| format!("Failed to resolve {}. {}", volume.path.display(), e))?;
+    info.volume = Some(volume.name);
+    Ok(())
+}
@@ -0,0 +1,273 @@
+//! Environment of the tests which need the config. The config cannot be replaced once initialized,
+//! so one is shared by the whole test binary, with Mirakurun and Meilisearch played by a fake server.
+
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use chrono::{DateTime, Duration, Local};
+use mirakurun_client::models::Program;
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use ulid::Ulid;
+
+use crate::config::{init_for_tests, Config, ProfileConfig, StageConfig};
+use crate::recording_pool::library::Recording;
+
+// Documents posted to each index of the fake Meilisearch
+static INDEXES: Lazy<Mutex<HashMap<String, Vec<Value>>>> = Lazy::new(Default::default);
+static TASK_UID: AtomicU32 = AtomicU32::new(0);
+
+const SOME_TIME: &str = "2022-01-01T00:00:00Z";
+
+pub(crate) fn config() -> &'static Config {
+    init_for_tests(|| {
+        let addr = start_fake_server();
+        // Files written by the tests (e.g. the queues) are kept out of the working tree.
+        let dir = temp_dir("state");
+
+        let mut config = Config::default();
+        config.mirakurun.base_uri = format!("http://{}/api", addr);
+        config.meilisearch.base_uri = format!("http://{}", addr);
+        config.storage.min_free_mib = 0;
+        config.storage.state_dir = dir.clone();
+        config.hls.cache_dir = dir.join("hls");
+        // Written as it is, whether tsreadex is installed or not
+        config.recording.profiles = vec![ProfileConfig {
+            name: "default".to_string(),
+            stages: vec![StageConfig::File],
+        }];
+        config
+    })
+}
+
+/// A new empty directory
+pub(crate) fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("recorder-test-{}-{}", name, Ulid::new()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Documents posted to the index so far
+pub(crate) fn documents(index: &str) -> Vec<Value> {
+    INDEXES
+        .lock()
+        .unwrap()
+        .get(index)
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Adds the documents to the index, replacing the ones of the same id.
+pub(crate) fn put_documents(index: &str, documents: Vec<Value>) {
+    let mut indexes = INDEXES.lock().unwrap();
+    let stored = indexes.entry(index.to_string()).or_default();
+    for document in documents {
+        stored.retain(|d| d["id"] != document["id"]);
+        stored.push(document);
+    }
+}
+
+/// A program as Mirakurun returns it
+pub(crate) fn program(
+    (network_id, service_id, event_id): (u16, u16, u16),
+    start_at: DateTime<Local>,
+    duration_ms: Option<i64>,
+) -> Program {
+    serde_json::from_value(json!({
+        "id": crate::mirakurun_client::get_program_id(
+            network_id as i64,
+            service_id as i64,
+            event_id as i64
+        ),
+        "eventId": event_id,
+        "serviceId": service_id,
+        "networkId": network_id,
+        "startAt": start_at.timestamp_millis(),
+        "duration": duration_ms,
+        "isFree": true,
+        "name": "テスト番組",
+    }))
+    .unwrap()
+}
+
+/// A recording of the program, which has lasted to the end if `graceful`
+pub(crate) fn recording(program: Program, graceful: bool) -> Recording {
+    let started_at: DateTime<Local> = program.start_at.into();
+    let ended_at = started_at + Duration::milliseconds(program.duration.unwrap_or(0) as i64);
+    Recording {
+        id: Ulid::new(),
+        program,
+        service: None,
+        files: Vec::new(),
+        sidecars: Vec::new(),
+        parts: Vec::new(),
+        volume: None,
+        size: 0,
+        started_at,
+        ended_at,
+        state: serde_json::from_value(json!({ "Lost": { "graceful": graceful } })).unwrap(),
+        packets: 0,
+        drops: 0,
+        errors: 0,
+        plan_id: Default::default(),
+        plan_name: None,
+        relayed_from: None,
+    }
+}
+
+// Serves on a thread of its own, since each test has its own runtime.
+// Requests to Mirakurun (/api/...) are answered with 404.
+fn start_fake_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = Router::new()
+        .route("/indexes/:uid", get(get_index))
+        .route("/indexes/:uid/documents", post(add_documents))
+        .route(
+            "/indexes/:uid/documents/:id",
+            get(get_document).delete(delete_document),
+        )
+        .route("/indexes/:uid/search", post(search))
+        .route("/tasks/:uid", get(get_task));
+    std::thread::spawn(move || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async move {
+                axum::Server::from_tcp(listener)
+                    .unwrap()
+                    .serve(app.into_make_service())
+                    .await
+                    .unwrap()
+            })
+    });
+    addr
+}
+
+// Every index exists.
+async fn get_index(Path(uid): Path<String>) -> Json<Value> {
+    Json(json!({
+        "uid": uid,
+        "primaryKey": "id",
+        "createdAt": SOME_TIME,
+        "updatedAt": SOME_TIME,
+    }))
+}
+
+async fn get_document(Path((uid, id)): Path<(String, String)>) -> (StatusCode, Json<Value>) {
+    // Ids are either numbers or strings.
+    let found = documents(&uid)
+        .into_iter()
+        .find(|d| d["id"].to_string().trim_matches('"') == id);
+    match found {
+        Some(document) => (StatusCode::OK, Json(document)),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "message": format!("Document `{}` not found.", id),
+                "code": "document_not_found",
+                "type": "invalid_request",
+                "link": "https://docs.meilisearch.com/errors#document_not_found",
+            })),
+        ),
+    }
+}
+
+async fn delete_document(Path((uid, id)): Path<(String, String)>) -> (StatusCode, Json<Value>) {
+    if let Some(stored) = INDEXES.lock().unwrap().get_mut(&uid) {
+        stored.retain(|d| d["id"].to_string().trim_matches('"') != id);
+    }
+    let task_uid = TASK_UID.fetch_add(1, Ordering::SeqCst);
+    (
+        StatusCode::ACCEPTED,
+        Json(json!({
+            "taskUid": task_uid,
+            "indexUid": uid,
+            "status": "enqueued",
+            "type": "documentDeletion",
+            "enqueuedAt": SOME_TIME,
+        })),
+    )
+}
+
+async fn add_documents(
+    Path(uid): Path<String>,
+    Json(documents): Json<Vec<Value>>,
+) -> (StatusCode, Json<Value>) {
+    put_documents(&uid, documents);
+    let task_uid = TASK_UID.fetch_add(1, Ordering::SeqCst);
+    (
+        StatusCode::ACCEPTED,
+        Json(json!({
+            "taskUid": task_uid,
+            "indexUid": uid,
+            "status": "enqueued",
+            "type": "documentAdditionOrUpdate",
+            "enqueuedAt": SOME_TIME,
+        })),
+    )
+}
+
+// Only `path = value` conditions joined with AND are understood in `filter`, and `q` is looked up in `name`.
+// Hits are in the order the documents have been added.
+async fn search(Path(uid): Path<String>, Json(query): Json<Value>) -> Json<Value> {
+    let conditions = query["filter"]
+        .as_str()
+        .map(|filter| {
+            filter
+                .split(" AND ")
+                .filter_map(|c| c.split_once('='))
+                .map(|(path, value)| (path.trim().to_string(), value.trim().to_string()))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let q = query["q"].as_str().unwrap_or_default();
+    let hits = documents(&uid)
+        .into_iter()
+        .filter(|d| {
+            conditions.iter().all(|(path, value)| {
+                path.split('.')
+                    .try_fold(d, |v, key| v.get(key))
+                    .map_or(false, |v| v.to_string().trim_matches('"') == value)
+            })
+        })
+        .filter(|d| {
+            d["name"]
+                .as_str()
+                .map_or(q.is_empty(), |name| name.contains(q))
+        })
+        .collect::<Vec<_>>();
+    let offset = query["offset"].as_u64().unwrap_or(0) as usize;
+    let limit = query["limit"].as_u64().unwrap_or(20) as usize;
+    Json(json!({
+        "hits": hits.iter().skip(offset).take(limit).collect::<Vec<_>>(),
+        "offset": offset,
+        "limit": limit,
+        "estimatedTotalHits": hits.len(),
+        "processingTimeMs": 0,
+        "query": q,
+    }))
+}
+
+// Every task has already succeeded.
+async fn get_task(Path(uid): Path<u32>) -> Json<Value> {
+    Json(json!({
+        "uid": uid,
+        "indexUid": null,
+        "status": "succeeded",
+        "type": "documentAdditionOrUpdate",
+        "details": { "receivedDocuments": 1, "indexedDocuments": 1 },
+        "duration": "PT0.001S",
+        "enqueuedAt": SOME_TIME,
+        "startedAt": SOME_TIME,
+        "finishedAt": SOME_TIME,
+    }))
+}
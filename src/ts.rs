@@ -0,0 +1,339 @@
+//! MPEG-TS primitives shared by the parsers and filters of the stream.
+
+pub(crate) const TS_PACKET_SIZE: usize = 188;
+pub(crate) const TS_SYNC_BYTE: u8 = 0x47;
+pub(crate) const PAT_PID: u16 = 0x00;
+pub(crate) const NULL_PID: u16 = 0x1fff;
+pub(crate) const PID_COUNT: usize = 0x2000;
+pub(crate) const TABLE_ID_PAT: u8 = 0x00;
+pub(crate) const TABLE_ID_PMT: u8 = 0x02;
+pub(crate) const CRC32_LENGTH: usize = 4;
+
+/// Cuts a byte stream into TS packets. Bytes out of sync are dropped.
+pub(crate) struct Packets {
+    // Remainder of the previous push() which didn't fill a whole TS packet
+    carry: [u8; TS_PACKET_SIZE],
+    carry_len: usize,
+}
+
+impl Packets {
+    pub(crate) fn new() -> Self {
+        Packets {
+            carry: [0; TS_PACKET_SIZE],
+            carry_len: 0,
+        }
+    }
+
+    /// Calls `on_packet` for each packet completed by `buf`.
+    /// The flag tells that some bytes have been dropped just before the packet.
+    pub(crate) fn push(&mut self, buf: &[u8], mut on_packet: impl FnMut(&[u8], bool)) {
+        let mut rest = buf;
+
+        // Complete the packet left over from the previous call
+        if self.carry_len > 0 {
+            let need = TS_PACKET_SIZE - self.carry_len;
+            if rest.len() < need {
+                let len = self.carry_len;
+                self.carry[len..len + rest.len()].copy_from_slice(rest);
+                self.carry_len += rest.len();
+                return;
+            }
+            let len = self.carry_len;
+            self.carry[len..].copy_from_slice(&rest[..need]);
+            rest = &rest[need..];
+            self.carry_len = 0;
+
+            on_packet(&self.carry, false);
+        }
+
+        let mut resynced = false;
+        while !rest.is_empty() {
+            // Resynchronize
+            if rest[0] != TS_SYNC_BYTE {
+                match rest.iter().position(|b| *b == TS_SYNC_BYTE) {
+                    Some(pos) => rest = &rest[pos..],
+                    None => break,
+                }
+                resynced = true;
+                continue;
+            }
+            if rest.len() < TS_PACKET_SIZE {
+                self.carry[..rest.len()].copy_from_slice(rest);
+                self.carry_len = rest.len();
+                break;
+            }
+            let (packet, remainder) = rest.split_at(TS_PACKET_SIZE);
+            on_packet(packet, std::mem::take(&mut resynced));
+            rest = remainder;
+        }
+    }
+
+    /// Forgets the incomplete packet, e.g. when some of the input has been lost.
+    pub(crate) fn clear(&mut self) {
+        self.carry_len = 0;
+    }
+}
+
+pub(crate) fn pid_of(packet: &[u8]) -> u16 {
+    ((packet[1] as u16 & 0x1f) << 8) | packet[2] as u16
+}
+
+pub(crate) fn payload_unit_start(packet: &[u8]) -> bool {
+    packet[1] & 0x40 != 0
+}
+
+/// None if the packet has no payload.
+pub(crate) fn payload_of(packet: &[u8]) -> Option<&[u8]> {
+    let adaptation_field_control = (packet[3] >> 4) & 0x03;
+    if adaptation_field_control & 0x01 == 0 {
+        return None;
+    }
+    let offset = if adaptation_field_control & 0x02 != 0 {
+        5 + packet[4] as usize
+    } else {
+        4
+    };
+    packet.get(offset..).filter(|p| !p.is_empty())
+}
+
+/// Sets section_length of `section` and appends CRC_32.
+pub(crate) fn seal_section(section: &mut Vec<u8>) {
+    let section_length = section.len() - 3 + CRC32_LENGTH;
+    section[1] = (section[1] & 0xf0) | (section_length >> 8) as u8 & 0x0f;
+    section[2] = section_length as u8;
+    let crc = crc32(section);
+    section.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// CRC-32/MPEG-2. Computing over a whole section including its CRC_32 field yields 0.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Builders of streams for the tests of the parsers
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+
+    const TABLE_ID_EIT_PF_ACTUAL: u8 = 0x4e;
+
+    /// A section of the syntax with section_syntax_indicator, sealed with CRC_32.
+    pub(crate) fn section(
+        table_id: u8,
+        table_id_extension: u16,
+        (section_number, last_section_number): (u8, u8),
+        body: &[u8],
+    ) -> Vec<u8> {
+        let mut section = vec![
+            table_id,
+            0xb0,
+            0x00,
+            (table_id_extension >> 8) as u8,
+            table_id_extension as u8,
+            // version_number 0, current_next_indicator
+            0xc1,
+            section_number,
+            last_section_number,
+        ];
+        section.extend_from_slice(body);
+        seal_section(&mut section);
+        section
+    }
+
+    /// PAT listing (program_number, PID of the PMT)
+    pub(crate) fn pat(programs: &[(u16, u16)]) -> Vec<u8> {
+        let body = programs
+            .iter()
+            .flat_map(|(n, pid)| {
+                [
+                    (n >> 8) as u8,
+                    *n as u8,
+                    0xe0 | (pid >> 8) as u8,
+                    *pid as u8,
+                ]
+            })
+            .collect::<Vec<_>>();
+        section(TABLE_ID_PAT, 0x7fe0, (0, 0), &body)
+    }
+
+    /// PMT of (stream_type, PID, ES_info descriptors)
+    pub(crate) fn pmt(
+        program_number: u16,
+        pcr_pid: u16,
+        program_info: &[u8],
+        streams: &[(u8, u16, &[u8])],
+    ) -> Vec<u8> {
+        let mut body = vec![
+            0xe0 | (pcr_pid >> 8) as u8,
+            pcr_pid as u8,
+            0xf0 | (program_info.len() >> 8) as u8,
+            program_info.len() as u8,
+        ];
+        body.extend_from_slice(program_info);
+        for (stream_type, pid, descriptors) in streams {
+            body.extend_from_slice(&[
+                *stream_type,
+                0xe0 | (pid >> 8) as u8,
+                *pid as u8,
+                0xf0 | (descriptors.len() >> 8) as u8,
+                descriptors.len() as u8,
+            ]);
+            body.extend_from_slice(descriptors);
+        }
+        section(TABLE_ID_PMT, program_number, (0, 0), &body)
+    }
+
+    /// Event of EIT with a short_event_descriptor of `text_len` bytes, like the ones on air
+    pub(crate) fn eit_event(event_id: u16, text_len: usize) -> Vec<u8> {
+        let mut descriptor = vec![0x4d, (5 + text_len) as u8, b'j', b'p', b'n', text_len as u8];
+        descriptor.extend(std::iter::repeat(0x41).take(text_len));
+        descriptor.push(0x00);
+        let mut event = vec![
+            (event_id >> 8) as u8,
+            event_id as u8,
+            // start_time (MJD + BCD), duration (BCD)
+            0xe7,
+            0x47,
+            0x21,
+            0x00,
+            0x00,
+            0x00,
+            0x30,
+            0x00,
+            // running_status: running
+            0x80 | (descriptor.len() >> 8) as u8,
+            descriptor.len() as u8,
+        ];
+        event.extend_from_slice(&descriptor);
+        event
+    }
+
+    /// EIT[p/f] actual of section_number 0 (present) or 1 (following)
+    pub(crate) fn eit_pf(
+        network_id: u16,
+        service_id: u16,
+        section_number: u8,
+        events: &[Vec<u8>],
+    ) -> Vec<u8> {
+        let mut body = vec![
+            // transport_stream_id
+            0x7f,
+            0xe0,
+            (network_id >> 8) as u8,
+            network_id as u8,
+            // segment_last_section_number, last_table_id
+            0x01,
+            TABLE_ID_EIT_PF_ACTUAL,
+        ];
+        body.extend(events.concat());
+        section(
+            TABLE_ID_EIT_PF_ACTUAL,
+            service_id,
+            (section_number, 1),
+            &body,
+        )
+    }
+
+    /// Packets carrying the sections, starting right after the pointer_field. Stuffed with 0xff.
+    pub(crate) fn packetize(pid: u16, cc: &mut u8, sections: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut rest = sections;
+        let mut first = true;
+        while first || !rest.is_empty() {
+            let mut packet = [0xff; TS_PACKET_SIZE];
+            packet[0] = TS_SYNC_BYTE;
+            packet[1] = (if first { 0x40 } else { 0x00 }) | (pid >> 8) as u8;
+            packet[2] = pid as u8;
+            packet[3] = 0x10 | *cc;
+            *cc = (*cc + 1) & 0x0f;
+            let mut at = 4;
+            if first {
+                packet[at] = 0x00;
+                at += 1;
+            }
+            let n = rest.len().min(TS_PACKET_SIZE - at);
+            packet[at..at + n].copy_from_slice(&rest[..n]);
+            rest = &rest[n..];
+            first = false;
+            out.extend_from_slice(&packet);
+        }
+        out
+    }
+
+    /// A packet of the PID without meaning, e.g. video
+    pub(crate) fn filler(pid: u16, cc: &mut u8) -> Vec<u8> {
+        let mut packet = vec![0x00; TS_PACKET_SIZE];
+        packet[..4].copy_from_slice(&[TS_SYNC_BYTE, (pid >> 8) as u8, pid as u8, 0x10 | *cc]);
+        *cc = (*cc + 1) & 0x0f;
+        packet
+    }
+
+    /// PIDs of the packets in order
+    pub(crate) fn pids(stream: &[u8]) -> Vec<u16> {
+        stream.chunks(TS_PACKET_SIZE).map(pid_of).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::*;
+    use super::*;
+
+    #[test]
+    fn crc_of_sealed_section_is_zero() {
+        let pat = pat(&[(0x0400, 0x01f0)]);
+        assert_eq!(crc32(&pat), 0);
+        assert_eq!(
+            pat.len(),
+            3 + ((pat[1] as usize & 0x0f) << 8 | pat[2] as usize)
+        );
+    }
+
+    #[test]
+    fn packets_are_joined_across_pushes() {
+        let mut cc = 0;
+        let stream = [filler(0x100, &mut cc), filler(0x101, &mut cc)].concat();
+        let mut packets = Packets::new();
+        let mut seen = Vec::new();
+        for chunk in stream.chunks(100) {
+            packets.push(chunk, |p, _| seen.push(pid_of(p)));
+        }
+        assert_eq!(seen, [0x100, 0x101]);
+    }
+
+    #[test]
+    fn garbage_is_skipped() {
+        let mut cc = 0;
+        let stream = [
+            filler(0x100, &mut cc),
+            vec![0x00; 10],
+            filler(0x101, &mut cc),
+        ]
+        .concat();
+        let mut seen = Vec::new();
+        Packets::new().push(&stream, |p, resynced| seen.push((pid_of(p), resynced)));
+        assert_eq!(seen, [(0x100, false), (0x101, true)]);
+    }
+
+    #[test]
+    fn payload_follows_adaptation_field() {
+        let mut packet = filler(0x100, &mut 0);
+        packet[3] = 0x30;
+        packet[4] = 7;
+        assert_eq!(payload_of(&packet).unwrap().len(), TS_PACKET_SIZE - 12);
+        // adaptation_field only
+        packet[3] = 0x20;
+        assert!(payload_of(&packet).is_none());
+    }
+}
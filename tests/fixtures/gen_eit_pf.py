@@ -0,0 +1,213 @@
+#!/usr/bin/env python3
+"""Writes the EIT[p/f] fixtures of eit_parser.rs.
+
+The packets are laid out the way BS muxers send them, independently of src/ts.rs:
+several sections in a packet with payload_unit_start_indicator and pointer_field,
+EIT[schedule] and EIT[p/f] other mixed on PID 0x12, stuffing by adaptation_field,
+a duplicate packet, and a stream which starts in the middle of a section.
+
+Run from this directory: python3 gen_eit_pf.py
+"""
+
+NETWORK_ID = 0x0004
+TRANSPORT_STREAM_ID = 0x4010
+SERVICE_ID = 0x0065
+EVENT_ID = 0x3A5C
+
+EIT_PID = 0x0012
+VIDEO_PID = 0x0100
+NULL_PID = 0x1FFF
+
+
+def crc32_mpeg2(data):
+    table = []
+    for i in range(256):
+        crc = i << 24
+        for _ in range(8):
+            crc = ((crc << 1) ^ 0x04C11DB7) if crc & 0x80000000 else (crc << 1)
+        table.append(crc & 0xFFFFFFFF)
+    crc = 0xFFFFFFFF
+    for b in data:
+        crc = ((crc << 8) & 0xFFFFFFFF) ^ table[((crc >> 24) ^ b) & 0xFF]
+    return crc
+
+
+def bcd(n):
+    return ((n // 10) << 4) | (n % 10)
+
+
+def event(event_id, mjd, hms, duration_hms, name, text, extended=b""):
+    short_event = bytes([0x4D, 5 + len(name) + len(text)]) + b"jpn"
+    short_event += bytes([len(name)]) + name + bytes([len(text)]) + text
+    if extended:
+        # extended_event_descriptor with an item of "番組内容"
+        item_description = b"\x1b\x7c\xc8\xd6\xc1\xc8"
+        items = bytes([len(item_description)]) + item_description
+        items += bytes([len(extended)]) + extended
+        short_event += bytes([0x4E, 5 + len(items) + 1, 0x01]) + b"jpn"
+        short_event += bytes([len(items)]) + items + bytes([0])
+    # Video 1080i 16:9, Japanese
+    component = bytes([0x50, 6 + 3, 0xF1, 0xB3, 0x00]) + b"jpn" + b"\x0e\x66\x6d"
+    # News, as ARIB genres
+    content = bytes([0x54, 2, 0x00, 0xFF])
+    descriptors = short_event + component + content
+    return (
+        event_id.to_bytes(2, "big")
+        + mjd.to_bytes(2, "big")
+        + bytes(bcd(v) for v in hms)
+        + bytes(bcd(v) for v in duration_hms)
+        # running_status: running, free_CA_mode 0
+        + bytes([0x80 | (len(descriptors) >> 8), len(descriptors) & 0xFF])
+        + descriptors
+    )
+
+
+def eit(table_id, network_id, service_id, section_number, last_section_number, events, version=7):
+    body = (
+        service_id.to_bytes(2, "big")
+        + bytes([0xC1 | (version << 1), section_number, last_section_number])
+        + TRANSPORT_STREAM_ID.to_bytes(2, "big")
+        + network_id.to_bytes(2, "big")
+        + bytes([last_section_number, table_id])
+        + b"".join(events)
+    )
+    section_length = len(body) + 4
+    section = bytes([table_id, 0xF0 | (section_length >> 8), section_length & 0xFF]) + body
+    return section + crc32_mpeg2(section).to_bytes(4, "big")
+
+
+def packet(pid, cc, payload, pusi=False, stuff_with_af=False):
+    header = bytes([0x47, (0x40 if pusi else 0x00) | (pid >> 8), pid & 0xFF])
+    room = 184 - len(payload)
+    assert room >= 0
+    if stuff_with_af and room > 0:
+        # adaptation_field_length and flags, then stuffing
+        af = bytes([room - 1]) + (bytes([0x00]) + b"\xff" * (room - 2) if room > 1 else b"")
+        return header + bytes([0x30 | cc]) + af + payload
+    return header + bytes([0x10 | cc]) + payload + b"\xff" * room
+
+
+def filler(pid, cc):
+    return bytes([0x47, pid >> 8, pid & 0xFF, 0x10 | cc]) + bytes(184)
+
+
+class Pid:
+    """Sends sections like a muxer: a packet starting a section gets pointer_field."""
+
+    def __init__(self, pid, cc):
+        self.pid = pid
+        self.cc = cc
+
+    def send(self, sections, tail=b"", stuff_with_af=False):
+        packets = []
+        data = tail
+        starts = []
+        for s in sections:
+            starts.append(len(data))
+            data += s
+        pos = 0
+        while pos < len(data):
+            start = next((s for s in starts if pos <= s < pos + 183), None)
+            if start is not None:
+                pointer = start - pos
+                n = min(183, len(data) - pos)
+                payload = bytes([pointer]) + data[pos : pos + n]
+                pusi = True
+            else:
+                n = min(184, len(data) - pos)
+                payload = data[pos : pos + n]
+                pusi = False
+            pos += n
+            last = pos >= len(data)
+            packets.append(
+                packet(self.pid, self.cc, payload, pusi, stuff_with_af and last)
+            )
+            self.cc = (self.cc + 1) & 0x0F
+        return packets
+
+
+def interleave(*streams):
+    out = []
+    for i in range(max(len(s) for s in streams)):
+        for s in streams:
+            if i < len(s):
+                out.append(s[i])
+    return b"".join(out)
+
+
+NAME = b"\x1b\x7c\xe6\xaf\xb1\xa5\xb9"
+TEXT = b"\x1b\x7c" + bytes(range(0x21, 0x21 + 120))
+EXTENDED = b"\x1b\x7c" + bytes(range(0xA1, 0xA1 + 90)) * 2
+
+TODAY = 60236
+
+
+def present_present():
+    eit_pid = Pid(EIT_PID, 0x9)
+    schedule = eit(0x50, NETWORK_ID, SERVICE_ID, 0x00, 0x08, [
+        event(EVENT_ID, TODAY, (21, 0, 0), (0, 54, 0), NAME, TEXT),
+        event(EVENT_ID + 1, TODAY, (21, 54, 0), (0, 6, 0), NAME, TEXT[:40]),
+    ])
+    present = eit(0x4E, NETWORK_ID, SERVICE_ID, 0, 1, [
+        event(EVENT_ID, TODAY, (21, 0, 0), (0, 54, 0), NAME, TEXT, EXTENDED),
+    ])
+    following = eit(0x4E, NETWORK_ID, SERVICE_ID, 1, 1, [
+        event(EVENT_ID + 1, TODAY, (21, 54, 0), (0, 6, 0), NAME, TEXT[:40]),
+    ])
+    eits = eit_pid.send([schedule, present, following], stuff_with_af=True)
+    # The packet in the middle of the present section, which has no section start, is sent twice.
+    assert eits[2][1] & 0x40 == 0
+    eits.insert(3, eits[2])
+    video = [filler(VIDEO_PID, cc & 0x0F) for cc in range(len(eits))]
+    nulls = [filler(NULL_PID, 0)] * 2
+    return interleave(eits, video, nulls)
+
+
+def following_present():
+    eit_pid = Pid(EIT_PID, 0x3)
+    # The capture starts in the middle of a section, whose tail is before pointer_field.
+    other = eit(0x4E, NETWORK_ID, 0x0066, 0, 1, [
+        event(0x1001, TODAY, (21, 0, 0), (1, 0, 0), NAME, TEXT),
+    ])
+    present = eit(0x4E, NETWORK_ID, SERVICE_ID, 0, 1, [
+        event(EVENT_ID - 1, TODAY, (20, 0, 0), (1, 0, 0), NAME, TEXT),
+    ])
+    following = eit(0x4E, NETWORK_ID, SERVICE_ID, 1, 1, [
+        event(EVENT_ID, TODAY, (21, 0, 0), (0, 54, 0), NAME, TEXT),
+    ])
+    eits = eit_pid.send([present, following], tail=other[-60:])
+    video = [filler(VIDEO_PID, cc & 0x0F) for cc in range(len(eits))]
+    return interleave(eits, video)
+
+
+def other_services():
+    eit_pid = Pid(EIT_PID, 0x0)
+    # Another service of the same network
+    other_service = eit(0x4E, NETWORK_ID, 0x0066, 0, 1, [
+        event(EVENT_ID, TODAY, (21, 0, 0), (0, 54, 0), NAME, TEXT),
+    ])
+    # EIT[p/f] other, i.e. of another transport stream, with the same ids
+    pf_other = eit(0x4F, NETWORK_ID, SERVICE_ID, 0, 1, [
+        event(EVENT_ID, TODAY, (21, 0, 0), (0, 54, 0), NAME, TEXT),
+    ])
+    # The service of the same id in another network
+    other_network = eit(0x4E, 0x0006, SERVICE_ID, 0, 1, [
+        event(EVENT_ID, TODAY, (21, 0, 0), (0, 54, 0), NAME, TEXT),
+    ])
+    schedule = eit(0x50, NETWORK_ID, SERVICE_ID, 0x00, 0x08, [
+        event(EVENT_ID, TODAY, (21, 0, 0), (0, 54, 0), NAME, TEXT),
+    ])
+    return b"".join(
+        eit_pid.send([other_service, pf_other, other_network, schedule], stuff_with_af=True)
+    )
+
+
+if __name__ == "__main__":
+    for name, stream in [
+        ("eit_pf_present.ts", present_present()),
+        ("eit_pf_following.ts", following_present()),
+        ("eit_pf_other_services.ts", other_services()),
+    ]:
+        assert len(stream) % 188 == 0
+        with open(name, "wb") as f:
+            f.write(stream)